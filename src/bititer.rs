@@ -28,6 +28,7 @@ pub struct BitIter<I: Iterator<Item = u8>> {
     cached_byte: u8,
     read_bits: usize,
     total_read: usize,
+    bit_len: Option<usize>,
 }
 
 impl<I: Iterator<Item = u8>> From<I> for BitIter<I> {
@@ -37,6 +38,7 @@ impl<I: Iterator<Item = u8>> From<I> for BitIter<I> {
             cached_byte: 0,
             read_bits: 8,
             total_read: 0,
+            bit_len: None,
         }
     }
 }
@@ -45,6 +47,10 @@ impl<I: Iterator<Item = u8>> Iterator for BitIter<I> {
     type Item = bool;
 
     fn next(&mut self) -> Option<bool> {
+        if self.bit_len == Some(self.total_read) {
+            return None;
+        }
+
         if self.read_bits < 8 {
             self.read_bits += 1;
             self.total_read += 1;
@@ -64,6 +70,19 @@ impl<I: Iterator<Item = u8>> BitIter<I> {
         Self::from(iter)
     }
 
+    /// Creates a new bitwise iterator which stops after `bit_len` bits,
+    /// even if the underlying bytewise iterator has more
+    ///
+    /// This allows the padding bits after the end of an encoded program,
+    /// e.g. one whose length was reported by `BitWriter::n_written`, to
+    /// be ignored rather than read as data.
+    pub fn with_bit_len(iter: I, bit_len: usize) -> Self {
+        BitIter {
+            bit_len: Some(bit_len),
+            ..Self::from(iter)
+        }
+    }
+
     /// Reads up to 64 bits as a big-endian number
     ///
     /// `n` must be between 0 and 63 inclusive; the function will panic
@@ -75,11 +94,16 @@ impl<I: Iterator<Item = u8>> BitIter<I> {
         if n == 0 {
             return Some(0);
         }
+        if let Some(bit_len) = self.bit_len {
+            if bit_len - self.total_read < n {
+                return None;
+            }
+        }
 
         let avail_bits = 8 - self.read_bits;
         if avail_bits < n {
             n -= avail_bits;
-            let pre_result = ((self.cached_byte & ((1 << avail_bits) - 1)) as u64) << n;
+            let pre_result = (u64::from(self.cached_byte) & ((1 << avail_bits) - 1)) << n;
             self.cached_byte = self.iter.next()?;
             self.read_bits = 0;
 
@@ -155,6 +179,21 @@ mod tests {
         assert_eq!(iter.n_total_read(), 24);
     }
 
+    #[test]
+    fn with_bit_len() {
+        let mut iter = BitIter::with_bit_len([0x0f, 0xaa].iter().cloned(), 10);
+        assert_eq!(iter.read_bits_be(6), Some(0x03)); // 00 0011
+        assert_eq!(iter.read_bits_be(2), Some(0x03)); // 11
+        assert_eq!(iter.read_bits_be(3), None);
+        assert_eq!(iter.read_bits_be(2), Some(0x02)); // 10
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.n_total_read(), 10);
+
+        let mut iter = BitIter::with_bit_len([0x0f, 0xaa].iter().cloned(), 10);
+        assert_eq!(iter.by_ref().count(), 10);
+        assert_eq!(iter.n_total_read(), 10);
+    }
+
     #[test]
     fn regression_1() {
         let mut iter = BitIter::from([0x34, 0x90].iter().cloned());
@@ -20,19 +20,22 @@
 use bitcoin_hashes::{sha256, Hash, HashEngine};
 use std::{fmt, ops};
 
+use types::{FinalType, FinalTypeInner};
+
 /// Commitment Merkle Root
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Cmr(sha256::Midstate);
 
+/// Wraps raw bytes without hashing them; see `Cmr::from_byte_array`
 impl From<[u8; 32]> for Cmr {
     fn from(data: [u8; 32]) -> Cmr {
-        Cmr(sha256::Midstate::from_inner(data))
+        Cmr::from_byte_array(data)
     }
 }
 
-impl Into<[u8; 32]> for Cmr {
-    fn into(self) -> [u8; 32] {
-        self.0.into_inner()
+impl From<Cmr> for [u8; 32] {
+    fn from(cmr: Cmr) -> [u8; 32] {
+        cmr.0.into_inner()
     }
 }
 
@@ -47,6 +50,10 @@ impl ops::Deref for Cmr {
 impl Cmr {
     /// Construct a new sha2 midstate by hashing a length-postfixed
     /// string. Will panic if `data` exceeds 56 bytes in length
+    ///
+    /// This is used to compute the tags of nodes from their names. To
+    /// construct a `Cmr` from an existing CMR, which must not be hashed
+    /// again, use `Cmr::from_byte_array` (or `From<[u8; 32]>`).
     pub fn new(data: &[u8]) -> Cmr {
         debug_assert!(data.len() < 56);
 
@@ -79,6 +86,25 @@ impl Cmr {
         Cmr(engine.midstate())
     }
 
+    /// Using a CMR as a sha2 midstate, hash 32 0's followed by the type
+    /// Merkle root of `ty`, as `update_1` does with a child's CMR
+    ///
+    /// This is how annotated roots commit to the types of a node; for two
+    /// types, use `update` with their `type_root`s.
+    pub fn update_with_type(self, ty: &FinalType) -> Cmr {
+        self.update_1(type_root(ty))
+    }
+
+    /// Wrap the bytes of an existing CMR, without hashing them
+    pub fn from_byte_array(data: [u8; 32]) -> Cmr {
+        Cmr(sha256::Midstate::from_inner(data))
+    }
+
+    /// The bytes of the CMR
+    pub fn as_byte_array(&self) -> &[u8; 32] {
+        &(self.0).0
+    }
+
     /// Helper function to convert a `Cmr` to a byte array
     pub fn into_inner(self) -> [u8; 32] {
         self.into()
@@ -103,6 +129,36 @@ impl fmt::Display for Cmr {
     }
 }
 
+/// BIP-340 style tagged hash, SHA256(SHA256(tag) || SHA256(tag) || data)
+///
+/// Unlike `Cmr::new`, which is used for the tags of Simplicity nodes and
+/// is the midstate of a single padded block, this is a full hash.
+pub fn tagged_hash(tag: &[u8], data: &[u8]) -> sha256::Hash {
+    tagged_hash_prehashed(&sha256::Hash::hash(tag)[..], data)
+}
+
+/// Tagged hash, as `tagged_hash`, where the tag has already been hashed
+pub(crate) fn tagged_hash_prehashed(tag_hash: &[u8], data: &[u8]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash);
+    engine.input(tag_hash);
+    engine.input(data);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Type Merkle root of a type: the `type_unit` tag for the unit type,
+/// and the `type_sum` or `type_prod` tag updated with the roots of the
+/// two components for sums and products
+pub fn type_root(ty: &FinalType) -> Cmr {
+    match ty.ty {
+        FinalTypeInner::Unit => tag::type_unit(),
+        FinalTypeInner::Sum(ref a, ref b) => tag::type_sum().update(type_root(a), type_root(b)),
+        FinalTypeInner::Product(ref a, ref b) => {
+            tag::type_prod().update(type_root(a), type_root(b))
+        }
+    }
+}
+
 /// CMR tags for the nodes in pure Simplicity
 pub mod tag {
     use super::Cmr;
@@ -157,8 +213,93 @@ pub mod tag {
         Cmr::new(b"Simplicity\x1fCommitment\x1fwitness")
     }
 
+    /// Tagged hash used by `fail`
+    pub fn fail() -> Cmr {
+        Cmr::new(b"Simplicity\x1fCommitment\x1ffail")
+    }
+
     /// Tagged hash used by `disconnect`
     pub fn disconnect() -> Cmr {
         Cmr::new(b"Simplicity\x1fCommitment\x1fdisconnect")
     }
+
+    /// Tagged hash used for the unit type
+    pub fn type_unit() -> Cmr {
+        Cmr::new(b"Simplicity\x1fType\x1funit")
+    }
+
+    /// Tagged hash used for sum types
+    pub fn type_sum() -> Cmr {
+        Cmr::new(b"Simplicity\x1fType\x1fsum")
+    }
+
+    /// Tagged hash used for product types
+    pub fn type_prod() -> Cmr {
+        Cmr::new(b"Simplicity\x1fType\x1fprod")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_vs_from_byte_array() {
+        let bytes = *b"Simplicity\x1fCommitment\x1funit\0\0\0\0\0\0";
+        let raw = Cmr::from_byte_array(bytes);
+        assert_eq!(raw.as_byte_array(), &bytes);
+        assert_eq!(raw.into_inner(), bytes);
+        assert_eq!(Cmr::from(bytes), raw);
+
+        // `new` hashes its input, so gives a different CMR
+        let tagged = Cmr::new(b"Simplicity\x1fCommitment\x1funit");
+        assert_eq!(tagged, tag::unit());
+        assert_ne!(tagged, raw);
+        assert_eq!(Cmr::from_byte_array(tagged.into_inner()), tagged);
+        assert_ne!(Cmr::new(&tagged[..]), tagged);
+    }
+
+    #[test]
+    fn tagged_hash() {
+        // BIP-340 auxiliary randomness hash of 32 zero bytes
+        assert_eq!(
+            super::tagged_hash(b"BIP0340/aux", &[0; 32]).to_string(),
+            "54f169cfc9e2e5727480441f90ba25c488f461c70b5ea5dcaaf7af69270aa514",
+        );
+    }
+
+    #[test]
+    fn update_with_type() {
+        use std::sync::Arc;
+
+        let unit = Arc::new(FinalType {
+            ty: FinalTypeInner::Unit,
+            bit_width: 0,
+        });
+        let bit = Arc::new(FinalType {
+            ty: FinalTypeInner::Sum(unit.clone(), unit.clone()),
+            bit_width: 1,
+        });
+        let two_bits = FinalType {
+            ty: FinalTypeInner::Product(bit.clone(), bit.clone()),
+            bit_width: 2,
+        };
+
+        let unit_root = tag::type_unit();
+        let bit_root = tag::type_sum().update(unit_root, unit_root);
+        let two_bits_root = tag::type_prod().update(bit_root, bit_root);
+        assert_eq!(type_root(&unit), unit_root);
+        assert_eq!(type_root(&bit), bit_root);
+        assert_eq!(type_root(&two_bits), two_bits_root);
+        assert_ne!(bit_root, two_bits_root);
+
+        // Annotating `iden : 2*2 -> 2*2` with its type
+        let mut engine = sha256::HashEngine::from_midstate(tag::iden().0, 0);
+        engine.input(&[0; 32]);
+        engine.input(&two_bits_root[..]);
+        assert_eq!(
+            tag::iden().update_with_type(&two_bits),
+            Cmr(engine.midstate())
+        );
+    }
 }
@@ -23,9 +23,9 @@
 use std::{io, mem};
 
 use bititer::BitIter;
-use cmr;
+use cmr::Cmr;
 use extension::{self, jets};
-use {Error, Node};
+use {program, Error, Node};
 
 /// Trait for writing individual bits to some sink
 pub trait BitWrite {
@@ -52,6 +52,34 @@ pub trait BitWrite {
         }
         Ok(len)
     }
+
+    /// Write a sequence of bytes, each most significant bit first, as
+    /// `write_u8(byte, 8)` would. On success, returns the number of bits
+    /// written.
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for byte in bytes {
+            self.write_u8(*byte, 8)?;
+        }
+        Ok(8 * bytes.len())
+    }
+
+    /// Write a 32-bit number, most significant bit first. On success,
+    /// returns 32.
+    fn write_u32(&mut self, n: u32) -> io::Result<usize> {
+        for i in 0..32 {
+            self.write_bit(n & (1 << (31 - i)) != 0)?;
+        }
+        Ok(32)
+    }
+
+    /// Write a 64-bit number, most significant bit first. On success,
+    /// returns 64.
+    fn write_u64(&mut self, n: u64) -> io::Result<usize> {
+        for i in 0..64 {
+            self.write_bit(n & (1 << (63 - i)) != 0)?;
+        }
+        Ok(64)
+    }
 }
 
 /// Wrapper around `io::Write` to enable writing individual bits to a bytestream
@@ -141,7 +169,7 @@ impl BitWrite for Vec<bool> {
     }
 }
 
-impl<'a, B: BitWrite> BitWrite for &'a mut B {
+impl<B: BitWrite> BitWrite for &mut B {
     fn write_bit(&mut self, b: bool) -> io::Result<()> {
         (**self).write_bit(b)
     }
@@ -155,18 +183,107 @@ impl<'a, B: BitWrite> BitWrite for &'a mut B {
     }
 }
 
+/// Bit writer which encodes a program into bytes, computing the CMRs of
+/// its nodes as they are written
+///
+/// The CMR is a Merkle root over the program's DAG rather than a hash of
+/// its encoding, so it cannot be computed from the bitstream alone.
+/// Instead, `encode_node` computes each node's CMR from those of its
+/// children, as `Program::decode` does. Bits written through `BitWrite`
+/// (e.g. the program length and witness data) do not affect the CMR.
+pub struct CmrWriter {
+    w: BitWriter<Vec<u8>>,
+    cmrs: Vec<Cmr>,
+}
+
+impl CmrWriter {
+    /// Create a new, empty `CmrWriter`
+    pub fn new() -> CmrWriter {
+        CmrWriter {
+            w: BitWriter::new(vec![]),
+            cmrs: vec![],
+        }
+    }
+
+    /// Encode the next node of the program, without witness data, as
+    /// `encode_node_no_witness` would. Returns the number of bits written.
+    pub fn encode_node<Witness, Ext: extension::Node>(
+        &mut self,
+        node: &Node<Witness, Ext>,
+    ) -> io::Result<usize> {
+        let index = self.cmrs.len();
+        let len = encode_node_no_witness(node, index, &mut self.w)?;
+        let cmr = program::node_cmr(node, |i| self.cmrs[i]);
+        self.cmrs.push(cmr);
+        Ok(len)
+    }
+
+    /// The CMR of the most recently encoded node, i.e. of the program
+    /// so far, if any node has been encoded
+    pub fn cmr(&self) -> Option<Cmr> {
+        self.cmrs.last().cloned()
+    }
+
+    /// Flush any remaining bits, returning the encoded bytes along with
+    /// the CMR of the program
+    ///
+    /// # Panics
+    ///
+    /// Panics if no nodes were encoded.
+    pub fn finalize(mut self) -> (Vec<u8>, Cmr) {
+        let cmr = self.cmr().expect("a program has at least one node");
+        self.w.flush_all().expect("writing to a Vec");
+        (self.w.into_inner(), cmr)
+    }
+}
+
+impl Default for CmrWriter {
+    fn default() -> CmrWriter {
+        CmrWriter::new()
+    }
+}
+
+impl BitWrite for CmrWriter {
+    fn write_bit(&mut self, b: bool) -> io::Result<()> {
+        self.w.write_bit(b)
+    }
+
+    fn flush_all(&mut self) -> io::Result<()> {
+        self.w.flush_all()
+    }
+
+    fn n_written(&self) -> usize {
+        self.w.n_written()
+    }
+}
+
 /// Decode a natural number according to section 7.2.1
 /// of the Simplicity whitepaper.
 pub fn decode_node_no_witness<I: Iterator<Item = u8>, Ext: extension::Node>(
     idx: usize,
     iter: &mut BitIter<I>,
+) -> Result<Node<(), Ext>, Error> {
+    decode_node_no_witness_with(idx, iter, false)
+}
+
+/// Decode a node, as `decode_node_no_witness`, but if `opaque_jets` is set,
+/// decode unknown jets as `jets::Node::Opaque` rather than failing
+fn decode_node_no_witness_with<I: Iterator<Item = u8>, Ext: extension::Node>(
+    idx: usize,
+    iter: &mut BitIter<I>,
+    opaque_jets: bool,
 ) -> Result<Node<(), Ext>, Error> {
     match iter.next() {
         None => Err(Error::EndOfStream),
         Some(true) => match iter.next() {
             None => Err(Error::EndOfStream),
             Some(false) => Ok(Node::Ext(extension::Node::decode(iter)?)),
-            Some(true) => Ok(Node::Jet(jets::decode_node(iter)?)),
+            Some(true) => match jets::decode_node_with(iter, opaque_jets)? {
+                jets::Decoded::Jet(jet) => Ok(Node::Jet(jet)),
+                jets::Decoded::Extended(code) => {
+                    Ok(Node::Ext(extension::Node::decode_extended(code)?))
+                }
+            },
         },
         Some(false) => {
             let code = match iter.read_bits_be(2) {
@@ -179,63 +296,34 @@ pub fn decode_node_no_witness<I: Iterator<Item = u8>, Ext: extension::Node>(
             };
             match (code, subcode) {
                 (0, 0) => Ok(Node::Comp(
-                    idx.checked_sub(decode_natural(&mut *iter)?)
-                        .ok_or(Error::BadIndex)?,
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
+                    decode_child(idx, &mut *iter)?,
+                    decode_child(idx, iter)?,
                 )),
                 // FIXME `Case` should check for asserts and reject if both children are hidden
                 (0, 1) => Ok(Node::Case(
-                    idx.checked_sub(decode_natural(&mut *iter)?)
-                        .ok_or(Error::BadIndex)?,
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
+                    decode_child(idx, &mut *iter)?,
+                    decode_child(idx, iter)?,
                 )),
                 (0, 2) => Ok(Node::Pair(
-                    idx.checked_sub(decode_natural(&mut *iter)?)
-                        .ok_or(Error::BadIndex)?,
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
+                    decode_child(idx, &mut *iter)?,
+                    decode_child(idx, iter)?,
                 )),
                 (0, 3) => Ok(Node::Disconnect(
-                    idx.checked_sub(decode_natural(&mut *iter)?)
-                        .ok_or(Error::BadIndex)?,
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
-                )),
-                (1, 0) => Ok(Node::InjL(
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
-                )),
-                (1, 1) => Ok(Node::InjR(
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
-                )),
-                (1, 2) => Ok(Node::Take(
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
-                )),
-                (1, 3) => Ok(Node::Drop(
-                    idx.checked_sub(decode_natural(iter)?)
-                        .ok_or(Error::BadIndex)?,
+                    decode_child(idx, &mut *iter)?,
+                    decode_child(idx, iter)?,
                 )),
+                (1, 0) => Ok(Node::InjL(decode_child(idx, iter)?)),
+                (1, 1) => Ok(Node::InjR(decode_child(idx, iter)?)),
+                (1, 2) => Ok(Node::Take(decode_child(idx, iter)?)),
+                (1, 3) => Ok(Node::Drop(decode_child(idx, iter)?)),
                 (2, 0) => Ok(Node::Iden),
                 (2, 1) => Ok(Node::Unit),
-                (2, 2) => Err(Error::ParseError("01010 (fail node)")),
+                (2, 2) => Ok(Node::Fail(
+                    decode_32bytes(&mut *iter)?,
+                    decode_32bytes(iter)?,
+                )),
                 (2, 3) => Err(Error::ParseError("01011 (stop code)")),
-                (3, 0) => {
-                    let mut h = [0; 32];
-                    for byte in &mut h {
-                        for b in 0..8 {
-                            match iter.next() {
-                                Some(true) => *byte |= 1 << (7 - b),
-                                Some(false) => {}
-                                None => return Err(Error::EndOfStream),
-                            };
-                        }
-                    }
-                    Ok(Node::Hidden(cmr::Cmr::from(h)))
-                }
+                (3, 0) => Ok(Node::Hidden(Cmr::from(decode_32bytes(iter)?))),
                 (3, 1) => Ok(Node::Witness(())),
                 (_, _) => unreachable!("we read only so many bits"),
             }
@@ -279,25 +367,64 @@ pub fn encode_node_no_witness<T, W: BitWrite, Ext: extension::Node>(
         Node::Drop(i) => Ok(writer.write_u8(7, 5)? + encode_natural(index - i, &mut *writer)?),
         Node::Iden => writer.write_u8(8, 5),
         Node::Unit => writer.write_u8(9, 5),
-        Node::Fail(..) => unimplemented!(),
-        Node::Hidden(cmr) => {
-            let mut len = writer.write_u8(6, 4)?;
-            for byte in &cmr[..] {
-                len += writer.write_u8(*byte, 8)?;
-            }
-            Ok(len)
+        Node::Fail(ref x, ref y) => {
+            Ok(writer.write_u8(10, 5)? + writer.write_bytes(x)? + writer.write_bytes(y)?)
         }
+        Node::Hidden(cmr) => Ok(writer.write_u8(6, 4)? + writer.write_bytes(&cmr)?),
         Node::Witness(..) => writer.write_u8(7, 4),
         Node::Ext(ref b) => extension::Node::encode(b, writer),
         Node::Jet(ref j) => j.encode_node(writer),
     }
 }
 
+/// Decode a reference from the node at index `idx` to one of its children.
+///
+/// Children are encoded as the (nonzero) distance back to an already-decoded
+/// node, so a subexpression used several times appears only once in the
+/// program and is shared by every node which refers to it.
+fn decode_child<I: Iterator<Item = u8>>(idx: usize, iter: &mut BitIter<I>) -> Result<usize, Error> {
+    idx.checked_sub(decode_natural(iter)?)
+        .ok_or(Error::BadIndex(idx))
+}
+
+/// Decode 32 bytes, as used by `hidden` and `fail` nodes
+fn decode_32bytes<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<[u8; 32], Error> {
+    let mut ret = [0; 32];
+    for byte in &mut ret {
+        for b in 0..8 {
+            match iter.next() {
+                Some(true) => *byte |= 1 << (7 - b),
+                Some(false) => {}
+                None => return Err(Error::EndOfStream),
+            };
+        }
+    }
+    Ok(ret)
+}
+
 pub fn decode_program_no_witness<I: Iterator<Item = u8>, Ext: extension::Node>(
     iter: &mut BitIter<I>,
+) -> Result<Vec<Node<(), Ext>>, Error> {
+    decode_program_no_witness_with(iter, false, None)
+}
+
+/// Decode a program, as `decode_program_no_witness`, but if `opaque_jets`
+/// is set, decode unknown jets as `jets::Node::Opaque` rather than failing,
+/// and if `max_nodes` is set, fail on programs with more nodes than that
+pub(crate) fn decode_program_no_witness_with<I: Iterator<Item = u8>, Ext: extension::Node>(
+    iter: &mut BitIter<I>,
+    opaque_jets: bool,
+    max_nodes: Option<usize>,
 ) -> Result<Vec<Node<(), Ext>>, Error> {
     let prog_len = decode_natural(&mut *iter)?;
 
+    // The node count comes first, so is checked before allocating anything
+    if let Some(max) = max_nodes {
+        if prog_len > max {
+            return Err(Error::ProgramTooLarge(prog_len, max));
+        }
+    }
+
     // FIXME make this a reasonable limit
     if prog_len > 1_000_000 {
         return Err(Error::TooManyNodes(prog_len));
@@ -305,7 +432,13 @@ pub fn decode_program_no_witness<I: Iterator<Item = u8>, Ext: extension::Node>(
 
     let mut program = Vec::with_capacity(prog_len);
     for i in 0..prog_len {
-        program.push(decode_node_no_witness(i, iter)?);
+        // Report where in the encoding an unrecognized node starts
+        let pos = iter.n_total_read();
+        let node = decode_node_no_witness_with(i, iter, opaque_jets).map_err(|e| match e {
+            Error::ParseError(s) => Error::ParseErrorAt(pos, s),
+            e => e,
+        })?;
+        program.push(node);
     }
 
     Ok(program)
@@ -477,4 +610,73 @@ mod tests {
             assert_eq!(target, decode);
         }
     }
+
+    #[test]
+    fn write_bytes() {
+        let bytes: Vec<u8> = (0..32).map(|i| i * 7 + 3).collect();
+
+        // Start off a byte boundary to check that nothing is aligned
+        let mut w = BitWriter::new(vec![]);
+        w.write_u8(5, 3).expect("encoding to a Vec");
+        assert_eq!(w.write_bytes(&bytes).expect("encoding to a Vec"), 256);
+        assert_eq!(w.write_u32(0xdead_beef).expect("encoding to a Vec"), 32);
+        assert_eq!(
+            w.write_u64(0x0123_4567_89ab_cdef)
+                .expect("encoding to a Vec"),
+            64,
+        );
+        w.flush_all().expect("flushing");
+        assert_eq!(w.n_written(), 3 + 256 + 32 + 64);
+
+        let mut iter = BitIter::new(w.into_inner().into_iter());
+        assert_eq!(iter.read_bits_be(3), Some(5));
+        for byte in &bytes {
+            assert_eq!(iter.read_bits_be(8), Some(u64::from(*byte)));
+        }
+        assert_eq!(iter.read_bits_be(32), Some(0xdead_beef));
+        assert_eq!(iter.read_bits_be(32), Some(0x0123_4567));
+        assert_eq!(iter.read_bits_be(32), Some(0x89ab_cdef));
+
+        // Bits are written most significant first
+        let mut w = Vec::<bool>::new();
+        w.write_u32(1).expect("encoding to a Vec");
+        assert_eq!(w.iter().position(|&bit| bit), Some(31));
+        let mut w = Vec::<bool>::new();
+        w.write_bytes(&[0x80, 0x01]).expect("encoding to a Vec");
+        assert_eq!(
+            w.iter()
+                .enumerate()
+                .filter(|&(_, &bit)| bit)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>(),
+            vec![0, 15],
+        );
+    }
+
+    #[test]
+    fn cmr_writer() {
+        use extension::dummy::Node as DummyNode;
+        use Program;
+
+        let nodes: [Node<(), DummyNode>; 5] = [
+            Node::Iden,
+            Node::Jet(jets::Node::Adder32),
+            Node::Comp(0, 1),
+            Node::Hidden(Cmr::from([7; 32])),
+            Node::Case(3, 2),
+        ];
+        let mut w = CmrWriter::new();
+        assert_eq!(w.cmr(), None);
+        encode_natural(nodes.len(), &mut w).expect("encoding to a Vec");
+        for node in &nodes {
+            w.encode_node(node).expect("encoding to a Vec");
+        }
+        w.write_bit(false).expect("encoding to a Vec");
+        let (bytes, cmr) = w.finalize();
+
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(bytes.clone().into_iter()))
+            .expect("decoding program");
+        assert_eq!(Some(cmr), prog.cmr());
+        assert_eq!(bytes[..], prog.to_bytes()[..]);
+    }
 }
@@ -18,12 +18,17 @@
 //! frame management optimizations which can be used to great benefit.
 //!
 
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::time::Instant;
 use std::{cmp, fmt, ptr};
 
 use bititer::BitIter;
+use byteorder::{BigEndian, ByteOrder};
 use extension;
-use types::FinalTypeInner;
-use {Node, Program, Value};
+use program::ProgramNode;
+use types::{FinalType, FinalTypeInner};
+use {Error, Node, Program, Value};
 
 /// A frame used internally by the Bit Machine to keep track of
 /// where we are reading or writing to
@@ -76,6 +81,29 @@ impl Frame {
         }
     }
 
+    /// Read the bit at offset `n` from the start of the frame, regardless
+    /// of the current cursor position
+    fn read_at_offset(&self, n: isize) -> bool {
+        unsafe {
+            let p = self.data.offset((self.start + n) / 8);
+            *p & (1 << ((self.start + n) % 8)) != 0
+        }
+    }
+
+    /// Write the bit at offset `n` from the start of the frame, regardless
+    /// of the current cursor position
+    fn write_at_offset(&mut self, n: isize, b: bool) {
+        let mask = 1 << ((self.start + n) % 8);
+        unsafe {
+            let p = self.data.offset((self.start + n) / 8);
+            if b {
+                *p |= mask;
+            } else {
+                *p &= !mask;
+            }
+        }
+    }
+
     fn read(&self) -> bool {
         unsafe {
             let p = self.data.offset(self.abs_pos / 8);
@@ -96,6 +124,10 @@ impl Frame {
         self.abs_pos += 1;
     }
 
+    fn remaining(&self) -> usize {
+        cmp::max(self.start + self.len - self.abs_pos, 0) as usize
+    }
+
     fn fwd(&mut self, n: usize) {
         self.abs_pos += n as isize;
     }
@@ -104,6 +136,7 @@ impl Frame {
         self.abs_pos -= n as isize;
     }
 
+    #[allow(clippy::manual_div_ceil)] // `div_ceil` is too new for our MSRV
     fn copy_from(&mut self, other: &Frame, n: usize) {
         if self.abs_pos % 8 == 0 && other.abs_pos % 8 == 0 {
             unsafe {
@@ -126,33 +159,260 @@ impl Frame {
     }
 }
 
+/// Whether a `TraceEvent` marks the start or the end of executing a node
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TraceKind {
+    /// The node is about to be executed
+    Enter,
+    /// The node, including all its children, has finished executing
+    Exit,
+}
+
+/// An event passed to the callback of `BitMachine::exec_with_trace`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TraceEvent {
+    /// Whether the node is being entered or exited
+    pub kind: TraceKind,
+    /// Index of the node in the program
+    pub index: usize,
+    /// Name of the node's combinator, as given by `Node::combinator_name`
+    pub combinator: &'static str,
+    /// Cursor of the current read frame, relative to its start, if there
+    /// is a read frame
+    pub read_pos: Option<usize>,
+    /// Cursor of the current write frame, relative to its start, if there
+    /// is a write frame
+    pub write_pos: Option<usize>,
+}
+
+/// How many nodes `BitMachine::exec_with_deadline` executes between
+/// looking at the clock
+pub const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Time after which execution is abandoned
+#[cfg(feature = "std")]
+type Deadline = Instant;
+
+/// Time after which execution is abandoned; without the `std` feature
+/// there is no clock, so no deadline can be given
+#[cfg(not(feature = "std"))]
+#[derive(Copy, Clone)]
+enum Deadline {}
+
+/// Whether a deadline has passed
+#[cfg(feature = "std")]
+fn deadline_passed(deadline: Deadline) -> bool {
+    Instant::now() >= deadline
+}
+
+/// Whether a deadline has passed
+#[cfg(not(feature = "std"))]
+fn deadline_passed(deadline: Deadline) -> bool {
+    match deadline {}
+}
+
 /// An execution context for a Simplicity program
 pub struct BitMachine {
     data: Vec<u8>,
     next_pos: isize,
     read: Vec<Frame>,
     write: Vec<Frame>,
+    /// Number of frames reserved for the two stacks together, which
+    /// `new_frame` will not exceed
+    frame_count: usize,
+    /// Whether arithmetic jets should fail rather than output a carry bit
+    checked_arithmetic: bool,
+}
+
+/// The only value of a type with no bits, e.g. `1 × 1`, which is
+/// determined by the type alone
+fn zero_width_value(ty: &FinalType) -> Value {
+    match ty.ty {
+        FinalTypeInner::Unit => Value::Unit,
+        FinalTypeInner::Product(ref a, ref b) => {
+            Value::prod(zero_width_value(a), zero_width_value(b))
+        }
+        FinalTypeInner::Sum(..) => unreachable!("sum types have nonzero width"),
+    }
 }
 
 impl BitMachine {
     /// Construct a Bit Machine with enough space to execute
     /// the given program
     pub fn for_program<Ext: extension::Node>(program: &Program<Ext>) -> BitMachine {
-        let prog = program.root_node();
-        let io_width = prog.source_ty.bit_width() + prog.target_ty.bit_width();
+        let (data_len, frame_count) = BitMachine::size_for_program(program);
         BitMachine {
-            data: vec![0; (io_width + prog.extra_cells_bound + 7) / 8],
+            data: vec![0; data_len],
             next_pos: 0,
+            read: Vec::with_capacity(frame_count),
+            write: Vec::with_capacity(frame_count),
+            // The program's frames, plus its input and output frames
+            frame_count: frame_count + 1,
+            checked_arithmetic: false,
+        }
+    }
+
+    /// Number of bytes of cell storage, and the number of frames on each
+    /// stack, needed to execute the given program
+    #[allow(clippy::manual_div_ceil)] // `div_ceil` is too new for our MSRV
+    fn size_for_program<Ext: extension::Node>(program: &Program<Ext>) -> (usize, usize) {
+        let prog = program.root_node();
+        let io_width = prog.source_ty.bit_width() + prog.target_ty.bit_width();
+        (
+            (io_width + prog.extra_cells_bound + 7) / 8,
             // +1's for input and output; these are used only for nontrivial
-            read: Vec::with_capacity(prog.frame_count_bound + 1),
-            write: Vec::with_capacity(prog.frame_count_bound + 1),
+            prog.frame_count_bound + 1,
+        )
+    }
+
+    /// Clear all frames and zero the cells, so that the machine can run
+    /// the program it was sized for again, without reallocating
+    ///
+    /// Checked-arithmetic mode is unaffected.
+    pub fn reset(&mut self) {
+        self.read.clear();
+        self.write.clear();
+        self.next_pos = 0;
+        for byte in &mut self.data {
+            *byte = 0;
+        }
+    }
+
+    /// Reset the machine, growing its storage if needed to execute the
+    /// given program. Storage is never shrunk, so a single machine can be
+    /// reused to validate many programs with few allocations.
+    pub fn reset_for_program<Ext: extension::Node>(&mut self, program: &Program<Ext>) {
+        self.reset();
+        let (data_len, frame_count) = BitMachine::size_for_program(program);
+        if self.data.len() < data_len {
+            self.data.resize(data_len, 0);
+        }
+        self.read.reserve(frame_count);
+        self.write.reserve(frame_count);
+        self.frame_count = cmp::max(self.frame_count, frame_count + 1);
+    }
+
+    /// Construct a Bit Machine for the given program, with the given value
+    /// as its input
+    ///
+    /// Unlike `input`, this fails if the value does not have the program's
+    /// source type, rather than writing bits the program will misread.
+    pub fn with_input<Ext: extension::Node>(
+        program: &Program<Ext>,
+        input: &Value,
+    ) -> Result<BitMachine, Error> {
+        let source_ty = &program.root_node().source_ty;
+        input.check_type(source_ty)?;
+        BitMachine::with_input_bits(program, &input.to_bits(source_ty))
+    }
+
+    /// Construct a Bit Machine for the given program, with its input frame
+    /// filled directly from raw bits, e.g. the output of another program
+    ///
+    /// Fails if the number of bits does not match the width of the
+    /// program's source type.
+    pub fn with_input_bits<Ext: extension::Node>(
+        program: &Program<Ext>,
+        bits: &[bool],
+    ) -> Result<BitMachine, Error> {
+        let input_width = program.root_node().source_ty.bit_width();
+        if bits.len() != input_width {
+            return Err(Error::BadInputWidth(input_width, bits.len()));
+        }
+
+        let mut mac = BitMachine::for_program(program);
+        if input_width > 0 {
+            mac.new_frame(input_width);
+            for bit in bits {
+                mac.write(*bit);
+            }
+            mac.move_frame();
         }
+        Ok(mac)
+    }
+
+    /// Construct a Bit Machine for running `jet` directly, with a zeroed
+    /// input frame of the width of its source type and an empty output
+    /// frame of the width of its target type
+    ///
+    /// Fill the input with the `set_input_*` methods, execute the jet on
+    /// the machine, then inspect the result with `output_bits` or
+    /// `output_hex`.
+    pub fn test_harness(jet: extension::jets::Node) -> BitMachine {
+        BitMachine::with_frames(jet.source_type().bit_width(), jet.target_type().bit_width())
+    }
+
+    /// Construct a Bit Machine with a zeroed input frame and an empty
+    /// output frame of the given widths
+    ///
+    /// Jets run on such a machine must fit these widths, since frame
+    /// accesses are not bounds-checked.
+    #[allow(clippy::manual_div_ceil)] // `div_ceil` is too new for our MSRV
+    pub(crate) fn with_frames(source_width: usize, target_width: usize) -> BitMachine {
+        let mut mac = BitMachine {
+            data: vec![0; (source_width + target_width + 7) / 8],
+            next_pos: 0,
+            read: Vec::with_capacity(1),
+            write: Vec::with_capacity(1),
+            frame_count: 2,
+            checked_arithmetic: false,
+        };
+        mac.new_frame(source_width);
+        mac.move_frame();
+        mac.new_frame(target_width);
+        mac
+    }
+
+    /// Set the bit at offset `pos` of the input frame
+    ///
+    /// Will panic if there is no room for it in the frame, or if the
+    /// machine was not constructed with `test_harness`.
+    pub fn set_input_bit(&mut self, pos: usize, bit: bool) {
+        assert_eq!(self.read.len(), 1, "set_input_* needs a test harness");
+        let frame = &mut self.read[0];
+        assert!((pos as isize) < frame.len, "input bit {} out of range", pos);
+        frame.write_at_offset(pos as isize, bit);
+    }
+
+    /// Set the bits at offset `pos` of the input frame to the bytes of
+    /// `data`, most significant bit first, as jets read them
+    pub fn set_input_bytes(&mut self, pos: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            for j in 0..8 {
+                self.set_input_bit(pos + 8 * i + j, byte & (0x80 >> j) != 0);
+            }
+        }
+    }
+
+    /// Set the 32 bits at offset `pos` of the input frame to a big-endian
+    /// 32-bit word, as read by `read_u32`
+    pub fn set_input_u32(&mut self, pos: usize, data: u32) {
+        let mut bytes = [0; 4];
+        BigEndian::write_u32(&mut bytes, data);
+        self.set_input_bytes(pos, &bytes);
+    }
+
+    /// Set whether arithmetic jets should run in checked mode
+    ///
+    /// By default, jets such as `adder32` output a carry (or borrow) bit
+    /// alongside a wrapped result, as specified by Simplicity. In checked
+    /// mode, execution instead fails with `Error::ArithmeticOverflow` when
+    /// this bit would be set. This does not match consensus semantics, and
+    /// is intended only for wrapper contexts which want a hard error.
+    pub fn set_checked_arithmetic(&mut self, checked: bool) {
+        self.checked_arithmetic = checked;
     }
 
     /// Push a new frame of given size onto the write stack
     fn new_frame(&mut self, len: usize) {
-        assert!(self.next_pos as usize + len < self.data.len() * 8);
-        assert!(self.write.len() + self.read.len() < self.read.capacity());
+        // The frame must fit in the data, and the two stacks together hold
+        // no more frames than were reserved for them
+        assert!(self.next_pos as usize + len <= self.data.len() * 8);
+        assert!(
+            self.write.len() + self.read.len() < self.frame_count,
+            "more than {} frames",
+            self.frame_count
+        );
 
         self.write.push(Frame {
             data: self.data.as_mut_ptr(),
@@ -178,28 +438,54 @@ impl BitMachine {
     }
 
     /// Write a single bit to the current write frame
-    fn write(&mut self, bit: bool) {
+    pub(crate) fn write(&mut self, bit: bool) {
         let idx = self.write.len() - 1;
         self.write[idx].write(bit);
     }
 
     /// Move the cursor of the current write frame forward by
     /// a specified number of bits
-    fn skip(&mut self, n: usize) {
+    pub(crate) fn skip(&mut self, n: usize) {
+        debug_assert!(
+            n <= self.write_frame_remaining(),
+            "skipped past end of frame"
+        );
         let idx = self.write.len() - 1;
         self.write[idx].fwd(n);
     }
 
+    /// Number of cells between the cursor of the current write frame and
+    /// its end, or zero if there is no write frame
+    ///
+    /// A primitive's output may share its frame with other data (e.g. the
+    /// other half of a `pair`), so this bounds, rather than equals, the
+    /// number of bits it has left to write.
+    pub fn write_frame_remaining(&self) -> usize {
+        self.write.last().map_or(0, Frame::remaining)
+    }
+
+    /// Number of cells between the cursor of the current read frame and
+    /// its end, or zero if there is no read frame
+    pub fn read_frame_remaining(&self) -> usize {
+        self.read.last().map_or(0, Frame::remaining)
+    }
+
     /// Copy a specified number of bits from the current read
     /// frame to the current write frame
+    ///
+    /// Copying zero bits is a no-op, even when there is no read frame, as
+    /// for a `disconnect` at the root of a program with unit input.
     fn copy(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
         let widx = self.write.len() - 1;
         let ridx = self.read.len() - 1;
         self.write[widx].copy_from(&self.read[ridx], n);
     }
 
     /// Move the cursor of the current read frame forward a number of bits
-    fn fwd(&mut self, n: usize) {
+    pub(crate) fn fwd(&mut self, n: usize) {
         let idx = self.read.len() - 1;
         self.read[idx].fwd(n);
     }
@@ -210,6 +496,111 @@ impl BitMachine {
         self.read[idx].back(n);
     }
 
+    /// Read a single bit from the current read frame, advancing its cursor
+    pub(crate) fn read_bit(&mut self) -> bool {
+        let idx = self.read.len() - 1;
+        let bit = self.read[idx].read();
+        self.read[idx].fwd(1);
+        bit
+    }
+
+    /// Read a bit from the current read frame, without advancing its
+    /// cursor. Panics if there is no read frame.
+    pub fn peek_bit(&self) -> bool {
+        let idx = self.read.len() - 1;
+        self.read[idx].read()
+    }
+
+    /// Read a big-endian u32 value from the current read frame, without
+    /// advancing its cursor. Panics if there is no read frame.
+    pub fn peek_u32(&self) -> u32 {
+        let frame = &self.read[self.read.len() - 1];
+        let offset = frame.abs_pos - frame.start;
+        let mut ret = 0;
+        for i in 0..32 {
+            ret = 2 * ret + frame.read_at_offset(offset + i) as u32;
+        }
+        ret
+    }
+
+    /// Read a bunch of bytes from the current read frame, advancing its cursor
+    ///
+    /// Fails with `Error::FrameUnderflow`, having read nothing, if there are
+    /// fewer than `8 * n` bits left in the frame (or there is no frame).
+    pub(crate) fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, Error> {
+        let remaining = self.read_frame_remaining();
+        if 8 * n > remaining {
+            return Err(Error::FrameUnderflow(8 * n, remaining));
+        }
+
+        let mut ret = vec![0; n];
+        for byte in &mut ret {
+            for i in 0..8 {
+                if self.read_bit() {
+                    *byte |= 1 << (7 - i);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Read 32 bytes (e.g. a hash or midstate) from the current read frame,
+    /// advancing its cursor; fails as `read_bytes` does
+    pub(crate) fn read_32bytes(&mut self) -> Result<[u8; 32], Error> {
+        let mut ret = [0; 32];
+        ret.copy_from_slice(&self.read_bytes(32)?);
+        Ok(ret)
+    }
+
+    /// Read a u8 value from the current read frame, advancing its cursor
+    pub(crate) fn read_u8(&mut self) -> u8 {
+        let mut ret = 0;
+        for _ in 0..8 {
+            ret = 2 * ret + self.read_bit() as u8;
+        }
+        ret
+    }
+
+    /// Read a big-endian u16 value from the current read frame, advancing
+    /// its cursor
+    pub(crate) fn read_u16(&mut self) -> u16 {
+        let mut ret = 0;
+        for _ in 0..16 {
+            ret = 2 * ret + self.read_bit() as u16;
+        }
+        ret
+    }
+
+    /// Read a big-endian u32 value from the current read frame, advancing
+    /// its cursor
+    pub(crate) fn read_u32(&mut self) -> u32 {
+        let mut ret = 0;
+        for _ in 0..32 {
+            ret = 2 * ret + self.read_bit() as u32;
+        }
+        ret
+    }
+
+    /// Read a big-endian u64 value from the current read frame, advancing
+    /// its cursor
+    pub(crate) fn read_u64(&mut self) -> u64 {
+        let mut ret = 0;
+        for _ in 0..64 {
+            ret = 2 * ret + self.read_bit() as u64;
+        }
+        ret
+    }
+
+    /// Write the carry (or borrow) bit output by an arithmetic jet, or
+    /// fail if it is set and the machine is in checked-arithmetic mode
+    pub(crate) fn write_carry(&mut self, carry: bool) -> Result<(), Error> {
+        if carry && self.checked_arithmetic {
+            return Err(Error::ArithmeticOverflow);
+        }
+        self.write(carry);
+        Ok(())
+    }
+
     /// Write a big-endian u64 value to the current write frame
     pub(crate) fn write_u64(&mut self, data: u64) {
         for idx in 0..64 {
@@ -224,6 +615,20 @@ impl BitMachine {
         }
     }
 
+    /// Write a big-endian u16 value to the current write frame
+    pub(crate) fn write_u16(&mut self, data: u16) {
+        for idx in 0..16 {
+            self.write(data & (1 << (15 - idx)) != 0);
+        }
+    }
+
+    /// Write a u8 value to the current write frame
+    pub(crate) fn write_u8(&mut self, data: u8) {
+        for idx in 0..8 {
+            self.write(data & (1 << (7 - idx)) != 0);
+        }
+    }
+
     /// Write a buch of bytes to the current write frame
     pub(crate) fn write_bytes(&mut self, data: &[u8]) {
         for bit in BitIter::new(data.iter().cloned()) {
@@ -251,10 +656,39 @@ impl BitMachine {
         }
     }
 
+    /// Run a primitive (extension node or jet), which may freely read
+    /// from the current read frame; its cursor is restored afterward
+    ///
+    /// In debug builds, checks that the primitive wrote (or skipped) exactly
+    /// `target_width` bits, i.e. the bit width of its target type.
+    fn exec_primitive<F>(&mut self, target_width: usize, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut BitMachine) -> Result<(), Error>,
+    {
+        let read_pos = self.read.last().map(|frame| frame.abs_pos);
+        let write_pos = self.write.last().map(|frame| frame.abs_pos);
+        f(self)?;
+        if let Some(pos) = read_pos {
+            let idx = self.read.len() - 1;
+            self.read[idx].abs_pos = pos;
+        }
+        if let Some(pos) = write_pos {
+            let idx = self.write.len() - 1;
+            debug_assert_eq!(
+                (self.write[idx].abs_pos - pos) as usize,
+                target_width,
+                "primitive output does not match its target type",
+            );
+        }
+        Ok(())
+    }
+
     /// Add a read frame with some given value in it, as input to the
     /// program
+    ///
+    /// The value is not checked against the program's source type; use
+    /// `BitMachine::with_input` for that.
     pub fn input(&mut self, input: &Value) {
-        // FIXME typecheck this
         self.new_frame(input.len());
         self.write_value(input);
         self.move_frame();
@@ -265,20 +699,97 @@ impl BitMachine {
         &mut self,
         program: &Program<Ext>,
         txenv: &Ext::TxEnv,
-    ) -> Value {
+    ) -> Result<Value, Error> {
+        self.exec_inner(program, txenv, false, None, |_| {})
+    }
+
+    /// Execute a program in the Bit Machine, counting how many times each
+    /// node is executed. Nodes which were never executed are absent from
+    /// the returned map, which is keyed by node index.
+    pub fn exec_profiled<Ext: extension::Node>(
+        &mut self,
+        program: &Program<Ext>,
+        txenv: &Ext::TxEnv,
+    ) -> Result<(Value, HashMap<usize, u64>), Error> {
+        let mut counts = HashMap::new();
+        let output = self.exec_inner(program, txenv, false, None, |event| {
+            *counts.entry(event.index).or_insert(0) += 1;
+        })?;
+        Ok((output, counts))
+    }
+
+    /// Execute a program in the Bit Machine, calling `trace` whenever a
+    /// node is entered and when it (and all its children) have exited
+    ///
+    /// Nodes shared in the DAG are entered and exited once per execution.
+    pub fn exec_with_trace<Ext: extension::Node>(
+        &mut self,
+        program: &Program<Ext>,
+        txenv: &Ext::TxEnv,
+        trace: &mut dyn FnMut(TraceEvent),
+    ) -> Result<Value, Error> {
+        self.exec_inner(program, txenv, true, None, trace)
+    }
+
+    /// Execute a program in the Bit Machine, giving up with
+    /// `Error::Timeout` if it is still running at `deadline`
+    ///
+    /// The clock is only consulted every `DEADLINE_CHECK_INTERVAL` nodes,
+    /// so the deadline may be overshot by the time that takes to execute.
+    #[cfg(feature = "std")]
+    pub fn exec_with_deadline<Ext: extension::Node>(
+        &mut self,
+        program: &Program<Ext>,
+        txenv: &Ext::TxEnv,
+        deadline: Instant,
+    ) -> Result<Value, Error> {
+        self.exec_inner(program, txenv, false, Some(deadline), |_| {})
+    }
+
+    /// The trace event for entering or exiting a node
+    fn trace_event<Ext>(&self, kind: TraceKind, node: &ProgramNode<Ext>) -> TraceEvent {
+        TraceEvent {
+            kind: kind,
+            index: node.index,
+            combinator: node.node.combinator_name(),
+            read_pos: self.read.last().map(|f| (f.abs_pos - f.start) as usize),
+            write_pos: self.write.last().map(|f| (f.abs_pos - f.start) as usize),
+        }
+    }
+
+    /// Execute a program in the Bit Machine, calling `on_node` with an
+    /// `Enter` event for every node as it is executed, and if `trace_exits`
+    /// is set, with an `Exit` event when it is done. If a `deadline` is
+    /// given, execution fails once it has passed.
+    #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` is too new for our MSRV
+    fn exec_inner<Ext: extension::Node, F: FnMut(TraceEvent)>(
+        &mut self,
+        program: &Program<Ext>,
+        txenv: &Ext::TxEnv,
+        trace_exits: bool,
+        deadline: Option<Deadline>,
+        mut on_node: F,
+    ) -> Result<Value, Error> {
         enum CallStack {
             Goto(usize),
             MoveFrame,
             DropFrame,
             CopyFwd(usize),
             Back(usize),
-        };
+            Exit(usize),
+        }
+
+        // Opaque jets are untyped, so the rest of the program may be
+        // mistyped too; fail before running any of it
+        if let Some(index) = program.first_opaque_jet() {
+            return Err(Error::OpaqueJet(index));
+        }
 
         let mut ip = program.root_node();
         let mut call_stack = vec![];
         let mut iters = 0u64;
 
-        let input_width = ip.target_ty.bit_width();
+        let input_width = ip.source_ty.bit_width();
         if input_width > 0 && self.read.is_empty() {
             panic!(
                 "Pleas call `Program::input` to add an input value for this program {}",
@@ -291,6 +802,15 @@ impl BitMachine {
         }
 
         'main_loop: loop {
+            on_node(self.trace_event(TraceKind::Enter, ip));
+            if trace_exits {
+                call_stack.push(CallStack::Exit(ip.index));
+            }
+            if let Some(deadline) = deadline {
+                if iters % DEADLINE_CHECK_INTERVAL == 0 && deadline_passed(deadline) {
+                    return Err(Error::Timeout);
+                }
+            }
             iters += 1;
             if iters % 1_000_000_000 == 0 {
                 println!("({:5} M) exec {}", iters / 1_000_000, ip);
@@ -369,7 +889,7 @@ impl BitMachine {
                     }
                 }
                 Node::Case(s, t) => {
-                    let sw = self.read[self.read.len() - 1].read();
+                    let sw = self.peek_bit();
                     let aw;
                     let bw;
                     if let FinalTypeInner::Product(ref a, _) = ip.source_ty.ty {
@@ -393,13 +913,21 @@ impl BitMachine {
                         call_stack.push(CallStack::Goto(s));
                     }
                 }
-                Node::Witness(ref value) => self.write_value(value),
+                Node::Witness(ref value) => {
+                    // Unlike `write_value`, this includes the padding of any
+                    // sum types, as `input` should once it is typechecked
+                    for bit in value.to_bits(&ip.target_ty) {
+                        self.write(bit);
+                    }
+                }
                 Node::Hidden(ref h) => panic!("Hit hidden node {} at iter {}: {}", ip, iters, h),
-                Node::Ext(ref e) => e.exec(self, txenv),
-                /*
-                 */
-                Node::Jet(ref j) => unimplemented!("jet {}", j),
-                Node::Fail(..) => panic!("encountered fail node while executing"),
+                Node::Ext(ref e) => {
+                    self.exec_primitive(ip.target_ty.bit_width(), |mac| e.exec(mac, txenv))?
+                }
+                Node::Jet(ref j) => {
+                    self.exec_primitive(ip.target_ty.bit_width(), |mac| j.exec(mac, ip.index))?
+                }
+                Node::Fail(..) => return Err(Error::ExecutionFailed(ip.index)),
             }
 
             ip = loop {
@@ -412,6 +940,9 @@ impl BitMachine {
                         self.fwd(n);
                     }
                     Some(CallStack::Back(n)) => self.back(n),
+                    Some(CallStack::Exit(idx)) => {
+                        on_node(self.trace_event(TraceKind::Exit, &program.nodes[idx]))
+                    }
                     None => break 'main_loop,
                 };
             };
@@ -420,10 +951,722 @@ impl BitMachine {
         if output_width > 0 {
             let out_frame = self.write.last_mut().unwrap();
             out_frame.abs_pos -= out_frame.len;
-            Value::from_witness(out_frame, &program.root_node().target_ty)
-                .expect("unwrapping output value")
+            Value::from_frame(out_frame, &program.root_node().target_ty)
         } else {
-            Value::Unit
+            Ok(zero_width_value(&program.root_node().target_ty))
+        }
+    }
+
+    /// The bits of the output frame, in the order they were written
+    ///
+    /// Intended to be called after `exec`, to inspect the raw output
+    /// of a program whose target type is unknown or not trusted.
+    pub fn output_bits(&self) -> Vec<bool> {
+        match self.write.last() {
+            Some(frame) => (0..frame.len).map(|i| frame.read_at_offset(i)).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Hex dump of the output frame; bits are packed MSB-first and the
+    /// final byte, if partial, is padded with zeroes
+    pub fn output_hex(&self) -> String {
+        let mut ret = String::new();
+        for chunk in self.output_bits().chunks(8) {
+            let mut byte = 0u8;
+            for (i, bit) in chunk.iter().enumerate() {
+                if *bit {
+                    byte |= 1 << (7 - i);
+                }
+            }
+            ret.push_str(&format!("{:02x}", byte));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin_hashes::{sha256, Hash, HashEngine};
+    use bititer::BitIter;
+    use cmr::{self, Cmr};
+    use extension::dummy::{Node as DummyNode, TxEnv};
+    use extension::jets::Node as JetNode;
+    #[cfg(feature = "proptests")]
+    use quickcheck::QuickCheck;
+    #[cfg(feature = "proptests")]
+    use test_progs::arbitrary::ArbitraryProgram;
+    use test_progs::decode_prog;
+
+    #[test]
+    #[cfg(feature = "proptests")]
+    fn arbitrary_exec() {
+        // Execution may fail, e.g. on an assertion jet, but must not panic,
+        // and on success fills exactly the program's output frame
+        fn prop(prog: ArbitraryProgram) -> bool {
+            let prog = Program::<DummyNode>::from_nodes(prog.nodes).expect("typechecking program");
+            let mut mac = BitMachine::for_program(&prog);
+            match mac.exec(&prog, &TxEnv) {
+                Ok(output) => {
+                    let target_ty = &prog.root_node().target_ty;
+                    mac.output_bits().len() == target_ty.bit_width()
+                        && output.check_type(target_ty).is_ok()
+                }
+                Err(_) => true,
+            }
+        }
+        QuickCheck::new()
+            .tests(100)
+            .quickcheck(prop as fn(ArbitraryProgram) -> bool);
+    }
+
+    #[test]
+    fn peek() {
+        let prog = vec![0x60]; // single `adder32` node
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(Value::u32(0x8000_0001), Value::u32(7)));
+        let start = mac.read[0].abs_pos;
+
+        assert!(mac.peek_bit());
+        assert!(mac.peek_bit());
+        assert_eq!(mac.read[0].abs_pos, start);
+        assert!(mac.read_bit());
+        assert_eq!(mac.read[0].abs_pos, start + 1);
+        assert!(!mac.peek_bit());
+        assert!(!mac.read_bit());
+        assert_eq!(mac.read[0].abs_pos, start + 2);
+
+        mac.fwd(30);
+        assert_eq!(mac.peek_u32(), 7);
+        assert_eq!(mac.peek_u32(), 7);
+        assert_eq!(mac.read[0].abs_pos, start + 32);
+        assert_eq!(mac.read_u32(), 7);
+        assert_eq!(mac.read[0].abs_pos, start + 64);
+    }
+
+    #[test]
+    fn frame_remaining() {
+        let prog = vec![0x60]; // single `adder32` node
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        let mut mac = BitMachine::for_program(&prog);
+        assert_eq!(mac.read_frame_remaining(), 0);
+        assert_eq!(mac.write_frame_remaining(), 0);
+
+        mac.input(&Value::prod(Value::u32(1), Value::u32(2)));
+        assert_eq!(mac.read_frame_remaining(), 64);
+        mac.read_bit();
+        assert_eq!(mac.read_frame_remaining(), 63);
+        mac.read_u32();
+        assert_eq!(mac.read_frame_remaining(), 31);
+        mac.fwd(31);
+        assert_eq!(mac.read_frame_remaining(), 0);
+
+        mac.new_frame(33);
+        assert_eq!(mac.write_frame_remaining(), 33);
+        mac.write(true);
+        assert_eq!(mac.write_frame_remaining(), 32);
+        mac.write_u16(0xffff);
+        assert_eq!(mac.write_frame_remaining(), 16);
+        mac.skip(16);
+        assert_eq!(mac.write_frame_remaining(), 0);
+        // Reading is unaffected by the write frame
+        assert_eq!(mac.read_frame_remaining(), 0);
+    }
+
+    #[test]
+    fn sha256_output_hex() {
+        // 0 1110 0 (single `sha256hashblock` node, no witness)
+        let prog = vec![0x70];
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+
+        let iv = [
+            0x6a, 0x09, 0xe6, 0x67, 0xbb, 0x67, 0xae, 0x85, 0x3c, 0x6e, 0xf3, 0x72, 0xa5, 0x4f,
+            0xf5, 0x3a, 0x51, 0x0e, 0x52, 0x7f, 0x9b, 0x05, 0x68, 0x8c, 0x1f, 0x83, 0xd9, 0xab,
+            0x5b, 0xe0, 0xcd, 0x19,
+        ];
+        // Padding block for the empty message
+        let mut block = [0; 32];
+        block[0] = 0x80;
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(
+            Value::u256(&iv),
+            Value::prod(Value::u256(&block), Value::u256(&[0; 32])),
+        ));
+        mac.exec(&prog, &TxEnv).expect("executing program");
+
+        assert_eq!(mac.output_bits().len(), 256);
+        assert_eq!(
+            mac.output_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+    }
+
+    #[test]
+    fn sha256_as_bytes32() {
+        // 0 1110 0 (single `sha256hashblock` node, no witness)
+        let prog = vec![0x70];
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+
+        let iv = sha256::HashEngine::default().midstate().into_inner();
+        let mut block = [0; 32];
+        block[0] = 0x80;
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(
+            Value::u256(&iv),
+            Value::prod(Value::u256(&block), Value::u256(&[0; 32])),
+        ));
+        let output = mac.exec(&prog, &TxEnv).expect("executing program");
+        assert_eq!(
+            output.as_bytes32(),
+            Some(sha256::Hash::hash(&[]).into_inner())
+        );
+        assert_eq!(output.as_u64(), None);
+    }
+
+    #[test]
+    fn multiplier32_max() {
+        // 0 11001 00 (single `multiplier32` node, no witness)
+        let prog = vec![0x64];
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(
+            Value::u32(0xffff_ffff),
+            Value::u32(0xffff_ffff),
+        ));
+        mac.exec(&prog, &TxEnv).expect("executing program");
+
+        assert_eq!(mac.output_bits().len(), 64);
+        assert_eq!(mac.output_hex(), "fffffffe00000001");
+    }
+
+    #[test]
+    fn adder32_checked() {
+        // 0 110000 0 (single `adder32` node, no witness)
+        let prog = vec![0x60];
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        let input = Value::prod(Value::u32(0xffff_ffff), Value::u32(2));
+
+        // Default mode wraps and sets the carry bit
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&input);
+        assert_eq!(
+            mac.exec(&prog, &TxEnv),
+            Ok(Value::prod(Value::u1(1), Value::u32(1))),
+        );
+
+        // Checked mode fails
+        let mut mac = BitMachine::for_program(&prog);
+        mac.set_checked_arithmetic(true);
+        mac.input(&input);
+        assert_eq!(mac.exec(&prog, &TxEnv), Err(Error::ArithmeticOverflow));
+
+        // ...but only on overflow
+        let mut mac = BitMachine::for_program(&prog);
+        mac.set_checked_arithmetic(true);
+        mac.input(&Value::prod(Value::u32(40), Value::u32(2)));
+        assert_eq!(
+            mac.exec(&prog, &TxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(42))),
+        );
+    }
+
+    #[test]
+    fn narrow_arithmetic() {
+        let run = |jet: JetNode, input: Value| {
+            let prog: Program<DummyNode> = decode_prog(&[Node::Jet(jet)]);
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&input);
+            mac.exec(&prog, &TxEnv).expect("executing program")
+        };
+
+        assert_eq!(
+            run(JetNode::Adder8, Value::prod(Value::u8(0xff), Value::u8(2))),
+            Value::prod(Value::u1(1), Value::u8(1)),
+        );
+        assert_eq!(
+            run(JetNode::Adder8, Value::prod(Value::u8(0xfe), Value::u8(1))),
+            Value::prod(Value::u1(0), Value::u8(0xff)),
+        );
+        assert_eq!(
+            run(
+                JetNode::Adder16,
+                Value::prod(Value::u16(0xffff), Value::u16(1))
+            ),
+            Value::prod(Value::u1(1), Value::u16(0)),
+        );
+        assert_eq!(
+            run(
+                JetNode::Subtractor8,
+                Value::prod(Value::u8(0), Value::u8(1))
+            ),
+            Value::prod(Value::u1(1), Value::u8(0xff)),
+        );
+        assert_eq!(
+            run(
+                JetNode::Subtractor16,
+                Value::prod(Value::u16(0), Value::u16(0xffff))
+            ),
+            Value::prod(Value::u1(1), Value::u16(1)),
+        );
+        assert_eq!(
+            run(
+                JetNode::Subtractor16,
+                Value::prod(Value::u16(0xffff), Value::u16(1))
+            ),
+            Value::prod(Value::u1(0), Value::u16(0xfffe)),
+        );
+    }
+
+    #[test]
+    fn sha256_pair() {
+        // 0 11110 0 0 0 (single `sha256pair` node, no witness)
+        let prog = vec![0x78, 0x00];
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+
+        let left = sha256::Hash::hash(b"left");
+        let right = sha256::Hash::hash(b"right");
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(
+            Value::u256(&left.into_inner()),
+            Value::u256(&right.into_inner()),
+        ));
+        mac.exec(&prog, &TxEnv).expect("executing program");
+
+        let mut engine = sha256::Hash::engine();
+        engine.input(&left[..]);
+        engine.input(&right[..]);
+        assert_eq!(
+            mac.output_hex(),
+            sha256::Hash::from_engine(engine).to_string(),
+        );
+    }
+
+    #[test]
+    fn sha256_tagged() {
+        let prog: Program<DummyNode> = decode_prog(&[Node::Jet(JetNode::Sha256Tagged)]);
+
+        // BIP-340 auxiliary randomness hash of 32 zero bytes
+        let tag = sha256::Hash::hash(b"BIP0340/aux");
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(
+            Value::u256(&tag.into_inner()),
+            Value::u256(&[0; 32]),
+        ));
+        mac.exec(&prog, &TxEnv).expect("executing program");
+        assert_eq!(
+            mac.output_hex(),
+            "54f169cfc9e2e5727480441f90ba25c488f461c70b5ea5dcaaf7af69270aa514",
+        );
+    }
+
+    #[test]
+    fn assertion_index() {
+        // Three assertions on the same pair of words
+        let prog: Program<DummyNode> = decode_prog(&[
+            Node::Jet(JetNode::Eq32Verify),
+            Node::Jet(JetNode::Lt32Verify),
+            Node::Jet(JetNode::Le32Verify),
+            Node::Pair(0, 1),
+            Node::Pair(3, 2),
+        ]);
+        let run = |a: u32, b: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::prod(Value::u32(a), Value::u32(b)));
+            mac.exec(&prog, &TxEnv)
+        };
+
+        // Only `lt32verify` fails
+        assert_eq!(run(3, 3), Err(Error::AssertionFailed(1, "lt32verify")));
+        // `eq32verify` fails first
+        assert_eq!(run(2, 3), Err(Error::AssertionFailed(0, "eq32verify")));
+    }
+
+    #[test]
+    fn fail() {
+        // `fail` in the left branch of a `case`
+        let entropy = ([0xab; 32], [0xcd; 32]);
+        let prog: Program<DummyNode> = decode_prog(&[
+            Node::Fail(entropy.0, entropy.1),
+            Node::Unit,
+            Node::Case(0, 1),
+        ]);
+        assert_eq!(prog.nodes[0].node, Node::Fail(entropy.0, entropy.1));
+        assert_eq!(
+            prog.nodes[0].cmr,
+            cmr::tag::fail().update(Cmr::from(entropy.0), Cmr::from(entropy.1)),
+        );
+
+        let run = |bit: u8| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::prod(Value::u1(bit), Value::Unit));
+            mac.exec(&prog, &TxEnv)
+        };
+        assert_eq!(run(1), Ok(Value::Unit));
+        assert_eq!(run(0), Err(Error::ExecutionFailed(0)));
+    }
+
+    #[test]
+    fn exec_profiled() {
+        // Negate the input bit twice, via a shared `case` node
+        let prog: Program<DummyNode> = decode_prog(&[
+            Node::Iden,
+            Node::Unit,
+            Node::Pair(0, 1),
+            Node::Unit,
+            Node::InjR(3),
+            Node::InjL(3),
+            Node::Case(4, 5),
+            Node::Comp(2, 6),
+            Node::Pair(7, 7),
+        ]);
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u1(0));
+        let (output, counts) = mac.exec_profiled(&prog, &TxEnv).expect("executing program");
+
+        assert_eq!(output, Value::prod(Value::u1(1), Value::u1(1)));
+        assert_eq!(counts[&8], 1);
+        assert_eq!(counts[&6], 2);
+        assert_eq!(counts[&4], 2);
+        assert_eq!(counts[&3], 2);
+        assert_eq!(counts.get(&5), None);
+    }
+
+    #[test]
+    fn exec_with_trace() {
+        // pair(injr(unit), injl(unit)), with the `unit` node shared
+        let prog: Program<DummyNode> =
+            decode_prog(&[Node::Unit, Node::InjR(0), Node::InjL(0), Node::Pair(1, 2)]);
+
+        let mut events = vec![];
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::Unit);
+        let output = mac
+            .exec_with_trace(&prog, &TxEnv, &mut |event| events.push(event))
+            .expect("executing program");
+        assert_eq!(output, Value::prod(Value::u1(1), Value::u1(0)));
+
+        let event = |kind, index, combinator, write_pos| TraceEvent {
+            kind: kind,
+            index: index,
+            combinator: combinator,
+            read_pos: Some(0),
+            write_pos: Some(write_pos),
+        };
+        assert_eq!(
+            events,
+            vec![
+                event(TraceKind::Enter, 3, "pair", 0),
+                event(TraceKind::Enter, 1, "injr", 0),
+                event(TraceKind::Enter, 0, "unit", 1),
+                event(TraceKind::Exit, 0, "unit", 1),
+                event(TraceKind::Exit, 1, "injr", 1),
+                event(TraceKind::Enter, 2, "injl", 1),
+                event(TraceKind::Enter, 0, "unit", 2),
+                event(TraceKind::Exit, 0, "unit", 2),
+                event(TraceKind::Exit, 2, "injl", 2),
+                event(TraceKind::Exit, 3, "pair", 2),
+            ],
+        );
+    }
+
+    #[test]
+    fn reset_for_program() {
+        // single `unit`, `adder32` and `multiplier32` nodes, no witness
+        let decode = |bytes: Vec<u8>| {
+            Program::<DummyNode>::decode(&mut BitIter::from(bytes.into_iter()))
+                .expect("decoding program")
+        };
+        let unit = decode(vec![0x24]);
+        let add = decode(vec![0x60]);
+        let mul = decode(vec![0x64]);
+        let input = Value::prod(Value::u32(0xffff_ffff), Value::u32(3));
+
+        // Start with a machine too small for the other programs
+        let mut mac = BitMachine::for_program(&unit);
+        mac.input(&Value::Unit);
+        assert_eq!(mac.exec(&unit, &TxEnv), Ok(Value::Unit));
+
+        mac.reset_for_program(&add);
+        mac.input(&input);
+        let sum = Value::prod(Value::u1(1), Value::u32(2));
+        assert_eq!(mac.exec(&add, &TxEnv), Ok(sum.clone()));
+
+        mac.reset_for_program(&mul);
+        mac.input(&input);
+        assert_eq!(mac.exec(&mul, &TxEnv), Ok(Value::u64(0x2_ffff_fffd)));
+
+        // Nothing is left over from previous runs
+        mac.reset_for_program(&add);
+        mac.input(&input);
+        assert_eq!(mac.exec(&add, &TxEnv), Ok(sum));
+        mac.reset();
+        mac.input(&Value::prod(Value::u32(1), Value::u32(2)));
+        assert_eq!(
+            mac.exec(&add, &TxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(3))),
+        );
+    }
+
+    #[test]
+    fn reset_for_deeper_program() {
+        // `scribe` a 64-bit word, copy it through five nested `comp`s of
+        // `iden`, and `pair` the copy with itself
+        let word = Value::u64(0x0123_4567_89ab_cdef);
+        let mut nodes = word.scribe::<Value, DummyNode>();
+        let scribe = nodes.len() - 1;
+        nodes.push(Node::Iden);
+        for _ in 0..5 {
+            let child = nodes.len() - 1;
+            nodes.push(Node::Comp(child, child));
+        }
+        let copy = nodes.len() - 1;
+        nodes.push(Node::Pair(copy, copy));
+        let pair = nodes.len() - 1;
+        nodes.push(Node::Comp(scribe, pair));
+        let deep = Program::from_nodes(nodes).expect("typechecking program");
+
+        // Start with a machine sized for a program needing no frames
+        let unit =
+            Program::<DummyNode>::from_nodes(vec![Node::Unit]).expect("typechecking program");
+        let mut mac = BitMachine::for_program(&unit);
+        mac.input(&Value::Unit);
+        assert_eq!(mac.exec(&unit, &TxEnv), Ok(Value::Unit));
+
+        mac.reset_for_program(&deep);
+        mac.input(&Value::Unit);
+        assert_eq!(mac.exec(&deep, &TxEnv), Ok(Value::prod(word.clone(), word)),);
+    }
+
+    #[test]
+    fn with_input_bits() {
+        // 0 11001 00 (single `multiplier32` node, no witness)
+        let mul = vec![0x64];
+        let mul = Program::<DummyNode>::decode(&mut BitIter::from(mul.into_iter()))
+            .expect("decoding program");
+        // 0 110000 0 (single `adder32` node, no witness)
+        let add = vec![0x60];
+        let add = Program::<DummyNode>::decode(&mut BitIter::from(add.into_iter()))
+            .expect("decoding program");
+
+        let mut mac = BitMachine::for_program(&mul);
+        mac.input(&Value::prod(Value::u32(0xffff_ffff), Value::u32(2)));
+        mac.exec(&mul, &TxEnv).expect("executing program");
+        let bits = mac.output_bits();
+
+        // Add the high and low words of the 64-bit product
+        let mut mac = BitMachine::with_input_bits(&add, &bits).expect("input bits");
+        assert_eq!(
+            mac.exec(&add, &TxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(0xffff_ffff))),
+        );
+
+        assert_eq!(
+            BitMachine::with_input_bits(&add, &bits[1..]).err(),
+            Some(Error::BadInputWidth(64, 63)),
+        );
+    }
+
+    #[test]
+    fn with_input() {
+        // 0 110000 0 (single `adder32` node, no witness)
+        let add = vec![0x60];
+        let add = Program::<DummyNode>::decode(&mut BitIter::from(add.into_iter()))
+            .expect("decoding program");
+
+        let input = Value::prod(Value::u32(1), Value::u32(2));
+        let mut mac = BitMachine::with_input(&add, &input).expect("input");
+        assert_eq!(
+            mac.exec(&add, &TxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(3))),
+        );
+
+        assert_eq!(
+            BitMachine::with_input(&add, &Value::unit()).err(),
+            Some(Error::InputTypeMismatch("product", "unit")),
+        );
+        // Right width, wrong shape: a 64-bit word where a 32-bit one belongs
+        let input = Value::prod(Value::u64(1), Value::unit());
+        assert_eq!(input.len(), 64);
+        assert_eq!(
+            BitMachine::with_input(&add, &input).err(),
+            Some(Error::InputTypeMismatch("sum", "product")),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn exec_with_deadline() {
+        use std::time::{Duration, Instant};
+
+        // Each `comp` runs its child twice, so this executes about 2^16 nodes
+        let mut nodes = vec![Node::Unit];
+        for i in 0..16 {
+            nodes.push(Node::Comp(i, i));
         }
+        let prog: Program<DummyNode> = decode_prog(&nodes);
+
+        let mut mac = BitMachine::for_program(&prog);
+        assert_eq!(
+            mac.exec_with_deadline(&prog, &TxEnv, Instant::now()),
+            Err(Error::Timeout),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let mut mac = BitMachine::for_program(&prog);
+        assert_eq!(
+            mac.exec_with_deadline(&prog, &TxEnv, deadline),
+            Ok(Value::unit()),
+        );
+    }
+
+    /// Parse a string of `0`s and `1`s, ignoring spaces
+    fn bits(s: &str) -> Vec<bool> {
+        s.chars().filter(|c| *c != ' ').map(|c| c == '1').collect()
+    }
+
+    /// Run the combinator built by `build` on `input`, returning the bits
+    /// of the output frame
+    ///
+    /// So that the combinator's input type is fixed, it is composed after
+    /// a `scribe` of the input; `build` is given the index of its first
+    /// node, and must return nodes whose last node is the combinator.
+    fn run_combinator<F>(input: &Value, build: F) -> Vec<bool>
+    where
+        F: Fn(usize) -> Vec<Node<Value, DummyNode>>,
+    {
+        let mut nodes = input.scribe();
+        let input_root = nodes.len() - 1;
+        nodes.extend(build(nodes.len()));
+        let comb_root = nodes.len() - 1;
+        nodes.push(Node::Comp(input_root, comb_root));
+
+        let prog = Program::from_nodes(nodes).expect("typechecking program");
+        let mut mac = BitMachine::for_program(&prog);
+        mac.exec(&prog, &TxEnv).expect("executing program");
+        mac.output_bits()
+    }
+
+    #[test]
+    fn combinator_frames() {
+        let byte = Value::u8(0xa5);
+        let nibbles = Value::prod(Value::u4(0x3), Value::u4(0xc));
+
+        // `unit` writes nothing; `iden` copies its whole input
+        assert_eq!(run_combinator(&byte, |_| vec![Node::Unit]), bits(""));
+        assert_eq!(
+            run_combinator(&byte, |_| vec![Node::Iden]),
+            bits("1010 0101")
+        );
+
+        // `take` and `drop` run their child on either half of the input
+        assert_eq!(
+            run_combinator(&nibbles, |n| vec![Node::Iden, Node::Take(n)]),
+            bits("0011")
+        );
+        assert_eq!(
+            run_combinator(&nibbles, |n| vec![Node::Iden, Node::Drop(n)]),
+            bits("1100")
+        );
+
+        // `pair` writes the outputs of its children one after the other
+        assert_eq!(
+            run_combinator(&nibbles, |n| vec![
+                Node::Iden,
+                Node::Drop(n),
+                Node::Take(n),
+                Node::Pair(n + 1, n + 2),
+            ]),
+            bits("1100 0011")
+        );
+
+        // `comp` runs its second child on the output of its first
+        assert_eq!(
+            run_combinator(&nibbles, |n| vec![
+                Node::Iden,
+                Node::Drop(n),
+                Node::Pair(n, n),
+                Node::Comp(n + 1, n + 2),
+            ]),
+            bits("1100 1100")
+        );
+
+        // `injl` and `injr` write a tag bit then their child's output
+        let crumb = Value::u2(0b10);
+        assert_eq!(
+            run_combinator(&crumb, |n| vec![Node::Iden, Node::InjL(n)]),
+            bits("0 10")
+        );
+        assert_eq!(
+            run_combinator(&crumb, |n| vec![Node::Iden, Node::InjR(n)]),
+            bits("1 10")
+        );
+    }
+
+    #[test]
+    fn case_frames() {
+        // `case` on (2^64 + 2^32) × 1, whose left branch outputs its input
+        // and whose right branch outputs its input byte-swapped, twice
+        let case = |n| {
+            vec![
+                Node::Iden,
+                Node::Take(n),
+                Node::Jet(JetNode::ByteSwap32),
+                Node::Pair(n + 2, n + 2),
+                Node::Take(n + 3),
+                Node::Case(n + 1, n + 4),
+            ]
+        };
+
+        // The left value takes up the whole sum, so has no padding
+        let left = Value::prod(
+            Value::sum_l(Value::u64(0x0102_0304_0506_0708)),
+            Value::unit(),
+        );
+        assert_eq!(
+            run_combinator(&left, case),
+            Value::u64(0x0102_0304_0506_0708).to_witness_bits()
+        );
+        // The right value is padded by 32 bits, which `case` skips
+        let right = Value::prod(Value::sum_r(Value::u32(0x0102_0304)), Value::unit());
+        assert_eq!(
+            run_combinator(&right, case),
+            Value::u64(0x0403_0201_0403_0201).to_witness_bits()
+        );
+
+        // The input frame holds the tag, then the padding, then the value
+        let with_input = |n| {
+            let mut nodes = case(n);
+            nodes.push(Node::Iden);
+            nodes.push(Node::Pair(n + 6, n + 5));
+            nodes
+        };
+        let mut expected = bits("1");
+        expected.extend(vec![false; 32]);
+        expected.extend(Value::u32(0x0102_0304).to_witness_bits());
+        expected.extend(Value::u64(0x0403_0201_0403_0201).to_witness_bits());
+        assert_eq!(run_combinator(&right, with_input), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "more than 2 frames")]
+    fn reserved_frames() {
+        // A harness reserves only its input and output frames, however
+        // much capacity its stacks were actually given
+        let mut mac = BitMachine::with_frames(0, 0);
+        mac.new_frame(0);
     }
 }
@@ -31,12 +31,18 @@ use {encode, exec, extension};
 /// Transaction environment for Bitcoin Simplicity programs
 pub struct TxEnv {
     tx: bitcoin::Transaction,
+    /// Index of the input currently being validated
+    ix: u32,
 }
 
 impl TxEnv {
-    /// Constructor from a transaction
-    pub fn from_tx(tx: bitcoin::Transaction) -> TxEnv {
-        TxEnv { tx: tx }
+    /// Constructor from a transaction and the index of the input currently
+    /// being validated
+    pub fn from_tx(tx: bitcoin::Transaction, ix: u32) -> Result<TxEnv, Error> {
+        if ix as usize >= tx.input.len() {
+            return Err(Error::BadInputIndex(ix, tx.input.len()));
+        }
+        Ok(TxEnv { tx: tx, ix: ix })
     }
 }
 
@@ -63,9 +69,10 @@ pub enum Node {
     ScriptCMR,
 }
 
-impl fmt::Display for Node {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(match *self {
+impl Node {
+    /// Name of the primitive, as used by its `Display` implementation
+    pub fn name(&self) -> &'static str {
+        match *self {
             Node::Version => "version",
             Node::LockTime => "locktime",
             Node::InputsHash => "inputshash",
@@ -84,7 +91,13 @@ impl fmt::Display for Node {
             Node::OutputValue => "outputvalue",
             Node::OutputScriptHash => "outputscripthash",
             Node::ScriptCMR => "scriptcmr",
-        })
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
 
@@ -216,7 +229,7 @@ impl extension::Node for Node {
         }
     }
 
-    fn exec(&self, mac: &mut exec::BitMachine, txenv: &Self::TxEnv) {
+    fn exec(&self, mac: &mut exec::BitMachine, txenv: &Self::TxEnv) -> Result<(), Error> {
         // FIXME finish this
         match *self {
             Node::InputsHash => {
@@ -243,7 +256,7 @@ impl extension::Node for Node {
                 mac.write_u64(99_998_000);
             }
             Node::CurrentIndex => {
-                mac.write_u32(0);
+                mac.write_u32(txenv.ix);
             }
             Node::LockTime => {
                 mac.write_u32(txenv.tx.lock_time);
@@ -251,7 +264,60 @@ impl extension::Node for Node {
             Node::Version => {
                 mac.write_u32(txenv.tx.version);
             }
-            ref b => unimplemented!("bitcoin {}", b),
+            ref b => return Err(Error::UnimplementedPrimitive(b.name())),
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use exec::BitMachine;
+    use extension::Node as ExtNode;
+    use test_progs::single_node_prog;
+    use Value;
+
+    #[test]
+    fn version_locktime_index() {
+        let txin = bitcoin::TxIn {
+            previous_output: Default::default(),
+            script_sig: Default::default(),
+            sequence: 0xffff_ffff,
+            witness: vec![],
+        };
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 600_000,
+            input: vec![txin.clone(), txin],
+            output: vec![],
+        };
+        assert_eq!(
+            TxEnv::from_tx(tx.clone(), 2).err(),
+            Some(Error::BadInputIndex(2, 2)),
+        );
+        let txenv = TxEnv::from_tx(tx, 1).unwrap();
+
+        for &(node, expected) in &[
+            (Node::Version, 2),
+            (Node::LockTime, 600_000),
+            (Node::CurrentIndex, 1),
+        ] {
+            assert_eq!(node.source_type().0, b"1");
+            let prog = single_node_prog(node);
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::Unit);
+            assert_eq!(mac.exec(&prog, &txenv), Ok(Value::u32(expected)));
+        }
+
+        // Primitives this library cannot execute yet fail rather than panic
+        let prog = single_node_prog(Node::NumInputs);
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::Unit);
+        assert_eq!(
+            mac.exec(&prog, &txenv),
+            Err(Error::UnimplementedPrimitive("numinputs")),
+        );
     }
 }
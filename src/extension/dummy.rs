@@ -54,7 +54,7 @@ impl extension::Node for Node {
         match *self {}
     }
 
-    fn exec(&self, _: &mut exec::BitMachine, _: &Self::TxEnv) {
+    fn exec(&self, _: &mut exec::BitMachine, _: &Self::TxEnv) -> Result<(), Error> {
         match *self {}
     }
 }
@@ -18,23 +18,757 @@
 //! blockchain
 //!
 
-use std::{fmt, io};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use byteorder::{BigEndian, ByteOrder};
+use elements::bitcoin::consensus::encode::VarInt;
+use elements::confidential;
+use elements::encode::Encodable;
+use elements::AssetId;
+use std::{cmp, fmt, io};
 
 use super::TypeName;
 use bititer::BitIter;
 use cmr::Cmr;
-use Error;
+use extension::jets::Node as JetNode;
 use {encode, exec, extension};
+use {Error, Program, Value};
+
+/// The kind of asset issuance attached to a transaction input
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IssuanceKind {
+    /// Issuance of a new asset, and possibly its reissuance token
+    New,
+    /// Reissuance of an existing asset, authorized by its reissuance token
+    Reissuance,
+}
+
+impl IssuanceKind {
+    /// Classify the issuance attached to a transaction input, if any.
+    /// Reissuances are distinguished by a nonzero asset blinding nonce
+    pub fn from_txin(txin: &elements::TxIn) -> Option<IssuanceKind> {
+        if !txin.has_issuance() {
+            None
+        } else if txin.asset_issuance.asset_blinding_nonce == [0; 32] {
+            Some(IssuanceKind::New)
+        } else {
+            Some(IssuanceKind::Reissuance)
+        }
+    }
+}
+
+/// Entropy and asset ids derived from an input's asset issuance
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct IssuanceIds {
+    /// Entropy of the issued asset
+    pub entropy: sha256::Midstate,
+    /// Id of the issued asset
+    pub asset: AssetId,
+    /// Id of the reissuance token. Only known for new issuances, since a
+    /// reissuance does not reveal whether the token was issued confidentially
+    pub token: Option<AssetId>,
+}
+
+impl IssuanceIds {
+    /// Derive the issuance entropy and asset ids for a transaction input,
+    /// if it has an issuance attached
+    #[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+    pub fn from_txin(txin: &elements::TxIn) -> Option<IssuanceIds> {
+        let issuance = &txin.asset_issuance;
+        match IssuanceKind::from_txin(txin)? {
+            IssuanceKind::New => {
+                // New issuance; `asset_entropy` is the contract hash
+                let contract_hash = sha256::Hash::from_inner(issuance.asset_entropy);
+                let entropy = AssetId::generate_asset_entropy(txin.previous_output, contract_hash);
+                let confidential = match issuance.amount {
+                    confidential::Value::Confidential(..) => true,
+                    _ => false,
+                };
+                Some(IssuanceIds {
+                    entropy: entropy,
+                    asset: AssetId::from_entropy(entropy),
+                    token: Some(AssetId::reissuance_token_from_entropy(
+                        entropy,
+                        confidential,
+                    )),
+                })
+            }
+            IssuanceKind::Reissuance => {
+                // Reissuance; `asset_entropy` is the original entropy
+                let entropy = sha256::Midstate::from_inner(issuance.asset_entropy);
+                Some(IssuanceIds {
+                    entropy: entropy,
+                    asset: AssetId::from_entropy(entropy),
+                    token: None,
+                })
+            }
+        }
+    }
+}
+
+/// Data about an output being spent by a transaction, which is not
+/// available from the spending transaction itself
+///
+/// Fields may be added to this struct as primitives need more data about
+/// spent outputs, so prefer building it with one of its constructors over
+/// a struct literal.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ElementsUtxo {
+    /// The scriptpubkey of the output
+    pub script_pubkey: elements::bitcoin::Script,
+    /// The asset of the output
+    pub asset: confidential::Asset,
+    /// The value of the output
+    pub value: confidential::Value,
+    /// For peg-in inputs, the genesis hash of the parent chain that the
+    /// coins were pegged in from
+    pub pegin: Option<elements::bitcoin::BlockHash>,
+    /// For peg-in inputs, the height of the parent chain block which
+    /// confirmed the peg-in, if known
+    pub pegin_height: Option<u32>,
+}
+
+impl ElementsUtxo {
+    /// Construct a utxo which is not a peg-in
+    pub fn new(
+        script_pubkey: elements::bitcoin::Script,
+        asset: confidential::Asset,
+        value: confidential::Value,
+    ) -> ElementsUtxo {
+        ElementsUtxo {
+            script_pubkey: script_pubkey,
+            asset: asset,
+            value: value,
+            pegin: None,
+            pegin_height: None,
+        }
+    }
+
+    /// Construct a peg-in utxo, given the genesis hash of the parent chain
+    /// and, if known, the height of the block which confirmed the peg-in
+    pub fn new_pegin(
+        script_pubkey: elements::bitcoin::Script,
+        asset: confidential::Asset,
+        value: confidential::Value,
+        genesis: elements::bitcoin::BlockHash,
+        height: Option<u32>,
+    ) -> ElementsUtxo {
+        ElementsUtxo {
+            pegin: Some(genesis),
+            pegin_height: height,
+            ..ElementsUtxo::new(script_pubkey, asset, value)
+        }
+    }
+
+    /// Construct a utxo from the transaction output being spent, which is
+    /// not a peg-in
+    pub fn from_txout(txout: &elements::TxOut) -> ElementsUtxo {
+        ElementsUtxo::new(txout.script_pubkey.clone(), txout.asset, txout.value)
+    }
+}
+
+/// Data which is committed to by the hashes (e.g. `outputshash`) that
+/// Simplicity computes over parts of a transaction
+pub trait SimplicityHash {
+    /// Add the committed data to a SHA256 engine
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine);
+}
+
+impl SimplicityHash for confidential::Asset {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        self.consensus_encode(eng).expect("engines don't error");
+    }
+}
+
+impl SimplicityHash for confidential::Value {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        self.consensus_encode(eng).expect("engines don't error");
+    }
+}
+
+impl SimplicityHash for confidential::Nonce {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        self.consensus_encode(eng).expect("engines don't error");
+    }
+}
+
+impl SimplicityHash for elements::TxOut {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        self.asset.simplicity_hash(eng);
+        self.value.simplicity_hash(eng);
+        self.nonce.simplicity_hash(eng);
+        eng.input(&sha256::Hash::hash(&self.script_pubkey[..]));
+    }
+}
+
+impl SimplicityHash for elements::TxIn {
+    /// Commits to the outpoint, sequence number and (if any) issuance of
+    /// the input, but not its scriptsig or witness
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        self.previous_output
+            .consensus_encode(&mut *eng)
+            .expect("engines don't error");
+        self.sequence
+            .consensus_encode(&mut *eng)
+            .expect("engines don't error");
+        if self.has_issuance() {
+            eng.input(&[1]);
+            self.asset_issuance
+                .consensus_encode(&mut *eng)
+                .expect("engines don't error");
+        } else {
+            eng.input(&[0]);
+        }
+    }
+}
+
+impl SimplicityHash for [elements::TxIn] {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        for txin in self {
+            txin.simplicity_hash(eng);
+        }
+    }
+}
+
+impl SimplicityHash for [elements::TxOut] {
+    fn simplicity_hash(&self, eng: &mut sha256::HashEngine) {
+        for txout in self {
+            txout.simplicity_hash(eng);
+        }
+    }
+}
+
+/// Compute the SHA256 hash of some data committed to by Simplicity
+fn simplicity_hash<T: SimplicityHash + ?Sized>(data: &T) -> sha256::Hash {
+    let mut eng = sha256::Hash::engine();
+    data.simplicity_hash(&mut eng);
+    sha256::Hash::from_engine(eng)
+}
+
+/// Compute the hash of a transaction's inputs, as output by `inputshash`
+///
+/// This is the SHA256 of the inputs' data concatenated, with no count
+/// prefix, as in the reference implementation. So an empty list hashes to
+/// the SHA256 of the empty string. Transactions without inputs are invalid
+/// by consensus, and `TxEnv` cannot be constructed for one.
+pub fn compute_inputs_hash(inputs: &[elements::TxIn]) -> sha256::Hash {
+    simplicity_hash(inputs)
+}
+
+/// Compute the hash of a transaction's outputs, as output by `outputshash`
+///
+/// As for `compute_inputs_hash`, an empty list hashes to the SHA256 of the
+/// empty string. Transactions without outputs are invalid by consensus,
+/// but this is not checked here.
+pub fn compute_outputs_hash(outputs: &[elements::TxOut]) -> sha256::Hash {
+    simplicity_hash(outputs)
+}
+
+/// CMR of the subexpression of the `sighash_all` program which computes the
+/// transaction digest; the signed message commits to it alongside the digest
+///
+/// This is the CMR of node 457 of the program as encoded in
+/// `test_progs::sighash_all::SIGHASH_ALL`, the `comp` whose output is the
+/// digest. That program is only built for tests, which check this value
+/// against it.
+const SIGHASH_ALL_DIGEST_CMR: [u8; 32] = [
+    0x52, 0x72, 0x19, 0xd1, 0x89, 0x70, 0xd6, 0x41, 0xc9, 0x2b, 0x5f, 0x01, 0xf3, 0x8e, 0xc2, 0xe2,
+    0xdb, 0xc1, 0x0a, 0xe2, 0x0f, 0xff, 0xb0, 0x3d, 0x43, 0x26, 0xfb, 0xaf, 0xdb, 0x86, 0xc9, 0x46,
+];
+
+/// SHA256 engine which has already hashed one block, whose midstate is
+/// the tag `Cmr::new(tag)`
+fn tagged_engine(tag: &[u8]) -> sha256::HashEngine {
+    let midstate = sha256::Midstate::from_inner(Cmr::new(tag).into_inner());
+    sha256::HashEngine::from_midstate(midstate, 64)
+}
+
+/// Compute the message which the `sighash_all` program checks a signature
+/// against, so that signers can produce the witness for it
+///
+/// The program first computes a digest of the transaction, tagged with
+/// `Simplicity\x1fSigHash`: the inputs and outputs hashes, the version,
+/// the lock time, the current input's index (all 32-bit big-endian), then
+/// the consensus encodings of the asset and amount of the output spent by
+/// the current input. The message is the hash, tagged with
+/// `Simplicity\x1fSignature`, of the CMR of the code computing this digest
+/// followed by the digest itself.
+///
+/// As the program does, this fails with `Error::NullConfidentialField` if
+/// the asset or amount is null.
+pub fn sighash_all_msg(txenv: &TxEnv) -> Result<sha256::Hash, Error> {
+    let utxo = &txenv.utxos[txenv.ix as usize];
+    if utxo.asset == confidential::Asset::Null || utxo.value == confidential::Value::Null {
+        return Err(Error::NullConfidentialField);
+    }
+
+    let mut engine = tagged_engine(b"Simplicity\x1fSigHash");
+    engine.input(&txenv.inputs_hash[..]);
+    engine.input(&txenv.outputs_hash[..]);
+    let mut header = [0; 12];
+    BigEndian::write_u32(&mut header[0..4], txenv.tx.version);
+    BigEndian::write_u32(&mut header[4..8], txenv.tx.lock_time);
+    BigEndian::write_u32(&mut header[8..12], txenv.ix);
+    engine.input(&header);
+    utxo.asset
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    utxo.value
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    let digest = sha256::Hash::from_engine(engine);
+
+    let mut engine = tagged_engine(b"Simplicity\x1fSignature");
+    engine.input(&SIGHASH_ALL_DIGEST_CMR);
+    engine.input(&digest[..]);
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+/// Add a node to a program under construction, returning its index
+fn push_node(nodes: &mut Vec<::Node<Value, Node>>, node: ::Node<Value, Node>) -> usize {
+    nodes.push(node);
+    nodes.len() - 1
+}
+
+/// Add an expression `l × l → 1 × 1` to a program under construction,
+/// which fails unless its two 64-bit words are equal
+fn push_eq64_verify(nodes: &mut Vec<::Node<Value, Node>>) -> usize {
+    let mut halves = [0; 2];
+    for (n, half) in halves.iter_mut().enumerate() {
+        // Pick the high (n = 0) or low 32 bits of each word
+        let select = |nodes: &mut Vec<_>, outer: fn(usize) -> ::Node<Value, Node>| {
+            let iden = push_node(nodes, ::Node::Iden);
+            let word = if n == 0 {
+                push_node(nodes, ::Node::Take(iden))
+            } else {
+                push_node(nodes, ::Node::Drop(iden))
+            };
+            push_node(nodes, outer(word))
+        };
+        let left = select(nodes, ::Node::Take);
+        let right = select(nodes, ::Node::Drop);
+        let words = push_node(nodes, ::Node::Pair(left, right));
+        let verify = push_node(nodes, ::Node::Jet(JetNode::Eq32Verify));
+        *half = push_node(nodes, ::Node::Comp(words, verify));
+    }
+    push_node(nodes, ::Node::Pair(halves[0], halves[1]))
+}
+
+/// Program which checks that a transaction conserves the value of the
+/// given explicit asset, and pays exactly `fee` of it in fees
+///
+/// That is, it fails unless the explicit amounts of the asset spent by
+/// the inputs equal those of the outputs, including fee outputs, and the
+/// fee outputs alone sum to `fee`. Together these mean that the inputs
+/// equal the non-fee outputs plus `fee`. Confidential amounts are not
+/// counted.
+pub fn conservation_program(asset: &[u8; 32], fee: u64) -> Program<Node> {
+    let mut nodes = vec![];
+    let asset = Value::u256(asset).scribe_onto(&mut nodes);
+    let total = |nodes: &mut Vec<_>, primitive| {
+        let primitive = push_node(nodes, ::Node::Ext(primitive));
+        push_node(nodes, ::Node::Comp(asset, primitive))
+    };
+    let inputs = total(&mut nodes, Node::TotalInputAmount);
+    let outputs = total(&mut nodes, Node::TotalOutputAmount);
+    let paid = total(&mut nodes, Node::Fee);
+    let fee = Value::u64(fee).scribe_onto(&mut nodes);
+
+    let balance = push_node(&mut nodes, ::Node::Pair(inputs, outputs));
+    let eq = push_eq64_verify(&mut nodes);
+    let balance = push_node(&mut nodes, ::Node::Comp(balance, eq));
+    let fee = push_node(&mut nodes, ::Node::Pair(paid, fee));
+    let eq = push_eq64_verify(&mut nodes);
+    let fee = push_node(&mut nodes, ::Node::Comp(fee, eq));
+    let both = push_node(&mut nodes, ::Node::Pair(balance, fee));
+    let unit = push_node(&mut nodes, ::Node::Unit);
+    push_node(&mut nodes, ::Node::Comp(both, unit));
+
+    Program::from_nodes(nodes).expect("conservation program is well-typed")
+}
+
+/// Width of the confidential form of an asset, value or nonce: a parity
+/// bit and a 256-bit x coordinate
+const CONFIDENTIAL_WIDTH: usize = 1 + 256;
+
+/// The parity of the point committed to by a confidential asset, value
+/// or nonce, from its prefix byte, which must be `even_prefix` (for
+/// even points) or `even_prefix + 1` (for odd points)
+fn confidential_parity(prefix: u8, even_prefix: u8) -> Result<bool, Error> {
+    if prefix == even_prefix {
+        Ok(false)
+    } else if prefix == even_prefix + 1 {
+        Ok(true)
+    } else {
+        Err(Error::BadConfidentialPrefix(prefix))
+    }
+}
+
+/// Write one arm of a confidential field to the Bit Machine: a bit which
+/// is set for the explicit form, then `prefix_bits` followed by `payload`,
+/// left-padded to `CONFIDENTIAL_WIDTH`
+///
+/// Every arm of every field thus writes exactly `1 + CONFIDENTIAL_WIDTH`
+/// bits. Fails, without writing anything, if `payload` is not
+/// `payload_len` bytes long or the arm does not fit in that width.
+fn encode_confidential_field(
+    mac: &mut exec::BitMachine,
+    prefix_bits: &[bool],
+    is_explicit: bool,
+    payload: &[u8],
+    payload_len: usize,
+) -> Result<(), Error> {
+    let width = prefix_bits.len() + 8 * payload.len();
+    if payload.len() != payload_len || width > CONFIDENTIAL_WIDTH {
+        return Err(Error::BadConfidentialLength(payload_len, payload.len()));
+    }
+    mac.write(is_explicit);
+    mac.skip(CONFIDENTIAL_WIDTH - width);
+    for bit in prefix_bits {
+        mac.write(*bit);
+    }
+    mac.write_bytes(payload);
+    Ok(())
+}
+
+/// Confidential data which primitives can write to the Bit Machine
+trait SimplicityEncodable {
+    /// Write the data to the current write frame, as a sum of its
+    /// confidential form (a parity bit and x coordinate) and its explicit
+    /// form. Fails on null data, which has no such encoding.
+    fn simplicity_encode(&self, mac: &mut exec::BitMachine) -> Result<(), Error>;
+}
+
+impl SimplicityEncodable for confidential::Asset {
+    fn simplicity_encode(&self, mac: &mut exec::BitMachine) -> Result<(), Error> {
+        match *self {
+            confidential::Asset::Null => Err(Error::NullConfidentialField),
+            confidential::Asset::Explicit(id) => {
+                encode_confidential_field(mac, &[], true, &id.into_inner(), 32)
+            }
+            confidential::Asset::Confidential(prefix, comm) => {
+                let parity = confidential_parity(prefix, 0x0a)?;
+                encode_confidential_field(mac, &[parity], false, &comm, 32)
+            }
+        }
+    }
+}
+
+impl SimplicityEncodable for confidential::Value {
+    fn simplicity_encode(&self, mac: &mut exec::BitMachine) -> Result<(), Error> {
+        match *self {
+            confidential::Value::Null => Err(Error::NullConfidentialField),
+            confidential::Value::Explicit(n) => {
+                let mut amount = [0; 8];
+                BigEndian::write_u64(&mut amount, n);
+                encode_confidential_field(mac, &[], true, &amount, 8)
+            }
+            confidential::Value::Confidential(prefix, comm) => {
+                let parity = confidential_parity(prefix, 0x08)?;
+                encode_confidential_field(mac, &[parity], false, &comm, 32)
+            }
+        }
+    }
+}
+
+impl SimplicityEncodable for confidential::Nonce {
+    fn simplicity_encode(&self, mac: &mut exec::BitMachine) -> Result<(), Error> {
+        match *self {
+            confidential::Nonce::Null => Err(Error::NullConfidentialField),
+            confidential::Nonce::Explicit(data) => {
+                encode_confidential_field(mac, &[], true, &data.into_inner(), 32)
+            }
+            confidential::Nonce::Confidential(prefix, comm) => {
+                let parity = confidential_parity(prefix, 0x02)?;
+                encode_confidential_field(mac, &[parity], false, &comm, 32)
+            }
+        }
+    }
+}
+
+/// Write an optional piece of confidential data to the Bit Machine, as
+/// a sum of nothing and the data
+fn simplicity_encode_opt<T: SimplicityEncodable>(
+    mac: &mut exec::BitMachine,
+    data: Option<&T>,
+) -> Result<(), Error> {
+    match data {
+        Some(data) => {
+            mac.write(true);
+            data.simplicity_encode(mac)
+        }
+        None => {
+            mac.write(false);
+            mac.skip(1 + CONFIDENTIAL_WIDTH);
+            Ok(())
+        }
+    }
+}
+
+/// Number of inputs or outputs of a transaction, as output by `numinputs`
+/// and `numoutputs`
+///
+/// Transactions cannot have 2^32 inputs or outputs on-chain, but one
+/// constructed in memory could; rather than truncating, this is an error.
+fn tx_count(len: usize) -> Result<u32, Error> {
+    if len as u64 > 0xffff_ffff {
+        Err(Error::CountOverflow(len))
+    } else {
+        Ok(len as u32)
+    }
+}
+
+/// Whether a transaction locktime is interpreted as a block height, rather
+/// than a unix timestamp
+fn locktime_is_height(lock_time: u32) -> bool {
+    lock_time < 500_000_000
+}
+
+/// A BIP-68 relative locktime
+enum RelativeLockTime {
+    /// Number of blocks
+    Height(u32),
+    /// Number of 512-second intervals
+    Time(u32),
+}
+
+/// Decode the relative locktime of an input from its sequence number, as
+/// specified by BIP-68. Returns `None` if relative locktimes are disabled
+/// for the input, or do not apply to the transaction's version.
+fn relative_locktime(version: u32, sequence: u32) -> Option<RelativeLockTime> {
+    if version < 2 || sequence & (1 << 31) != 0 {
+        None
+    } else if sequence & (1 << 22) != 0 {
+        Some(RelativeLockTime::Time(sequence & 0xffff))
+    } else {
+        Some(RelativeLockTime::Height(sequence & 0xffff))
+    }
+}
+
+/// Whether an output is a fee output, i.e. has an empty scriptpubkey
+/// and explicit asset and value
+#[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+fn is_fee_output(txout: &elements::TxOut) -> bool {
+    let explicit_asset = match txout.asset {
+        confidential::Asset::Explicit(..) => true,
+        _ => false,
+    };
+    let explicit_value = match txout.value {
+        confidential::Value::Explicit(..) => true,
+        _ => false,
+    };
+    txout.is_fee() && explicit_asset && explicit_value
+}
+
+/// Sum of the explicit amounts of the given asset among some assets and
+/// amounts, ignoring any which are confidential. Fails if the sum does not
+/// fit in 64 bits, which cannot happen for transactions respecting the
+/// money supply
+fn sum_explicit_amounts<'a, I>(amounts: I, asset: &[u8; 32]) -> Result<u64, Error>
+where
+    I: Iterator<Item = (&'a confidential::Asset, &'a confidential::Value)>,
+{
+    amounts
+        .filter_map(|amount| match amount {
+            (&confidential::Asset::Explicit(id), &confidential::Value::Explicit(n))
+                if id[..] == asset[..] =>
+            {
+                Some(n)
+            }
+            _ => None,
+        })
+        .try_fold(0u64, |sum, n| {
+            sum.checked_add(n).ok_or(Error::AmountOverflow)
+        })
+}
+
+/// SHA256 of the consensus serialization of a witness stack: its length,
+/// then each item with a length prefix
+fn witness_stack_hash(stack: &[Vec<u8>]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    VarInt(stack.len() as u64)
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    for item in stack {
+        item.consensus_encode(&mut engine)
+            .expect("engines don't error");
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// Hash of a scriptpubkey, as output by the script-hash primitives
+///
+/// By convention, a Simplicity output is a segwit v1 output whose 32-byte
+/// witness program is the CMR of the program. Every `OP_1 <32 bytes>`
+/// script hashes to its witness program, so a Simplicity output hashes to
+/// its CMR. Nothing in the script marks it as a Simplicity output, so a
+/// taproot output, which has the same form, hashes to its output key, and
+/// programs cannot tell the two apart. Any other script hashes to the
+/// SHA256 of its serialization.
+fn script_hash(script: &elements::bitcoin::Script) -> [u8; 32] {
+    let bytes = &script[..];
+    let mut ret = [0; 32];
+    if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+        ret.copy_from_slice(&bytes[2..]);
+    } else {
+        ret.copy_from_slice(&sha256::Hash::hash(bytes)[..]);
+    }
+    ret
+}
+
+/// The annex of a witness stack, if any: as in BIP 341, the last item of a
+/// stack with at least two items, if that item starts with 0x50
+fn annex(stack: &[Vec<u8>]) -> Option<&[u8]> {
+    match stack.last() {
+        Some(last) if stack.len() >= 2 && last.first() == Some(&0x50) => Some(&last[..]),
+        _ => None,
+    }
+}
+
+/// Hash of an input's annex, if it has one, as output by `currentannexhash`
+fn annex_hash(txin: &elements::TxIn) -> Option<sha256::Hash> {
+    annex(&txin.witness.script_witness).map(|annex| {
+        let mut engine = sha256::Hash::engine();
+        annex
+            .to_vec()
+            .consensus_encode(&mut engine)
+            .expect("engines don't error");
+        sha256::Hash::from_engine(engine)
+    })
+}
 
 /// Transaction environment for Bitcoin Simplicity programs
 pub struct TxEnv {
-    _tx: elements::Transaction,
+    tx: elements::Transaction,
+    /// The outputs being spent by each input
+    utxos: Vec<ElementsUtxo>,
+    /// Issuance entropy and asset ids for each input, computed once
+    /// at construction since they are expensive to derive
+    issuance_ids: Vec<Option<IssuanceIds>>,
+    /// Hash of all the transaction inputs
+    inputs_hash: sha256::Hash,
+    /// Hash of all the transaction outputs
+    outputs_hash: sha256::Hash,
+    /// Hash of the annex of the current input, if it has one
+    annex_hash: Option<sha256::Hash>,
+    /// Index of the input currently being validated
+    ix: u32,
+    /// CMR of the program being executed, if the caller supplied one
+    script_cmr: Option<Cmr>,
 }
 
 impl TxEnv {
-    /// Constructor from a transaction
-    pub fn from_tx(tx: elements::Transaction) -> TxEnv {
-        TxEnv { _tx: tx }
+    /// Constructor from a transaction, the index of the input currently
+    /// being validated, and the outputs it spends, which must be given in
+    /// the same order as the inputs
+    pub fn from_tx(
+        tx: elements::Transaction,
+        ix: u32,
+        utxos: Vec<ElementsUtxo>,
+    ) -> Result<TxEnv, Error> {
+        if ix as usize >= tx.input.len() {
+            return Err(Error::BadInputIndex(ix, tx.input.len()));
+        }
+        if utxos.len() != tx.input.len() {
+            return Err(Error::UtxoCountMismatch(tx.input.len(), utxos.len()));
+        }
+        let issuance_ids = tx.input.iter().map(IssuanceIds::from_txin).collect();
+        let inputs_hash = compute_inputs_hash(&tx.input);
+        let outputs_hash = compute_outputs_hash(&tx.output);
+        let annex_hash = annex_hash(&tx.input[ix as usize]);
+        Ok(TxEnv {
+            tx: tx,
+            utxos: utxos,
+            issuance_ids: issuance_ids,
+            inputs_hash: inputs_hash,
+            outputs_hash: outputs_hash,
+            annex_hash: annex_hash,
+            ix: ix,
+            script_cmr: None,
+        })
+    }
+
+    /// A copy of the environment for validating a different input of the
+    /// same transaction, which reuses the cached transaction-wide data
+    /// rather than recomputing it. The script CMR is not copied, since the
+    /// new input spends a different utxo
+    pub fn with_index(&self, ix: u32) -> Result<TxEnv, Error> {
+        let txin = self
+            .tx
+            .input
+            .get(ix as usize)
+            .ok_or(Error::BadInputIndex(ix, self.tx.input.len()))?;
+        Ok(TxEnv {
+            tx: self.tx.clone(),
+            utxos: self.utxos.clone(),
+            issuance_ids: self.issuance_ids.clone(),
+            inputs_hash: self.inputs_hash,
+            outputs_hash: self.outputs_hash,
+            annex_hash: annex_hash(txin),
+            ix: ix,
+            script_cmr: None,
+        })
+    }
+
+    /// Set the CMR of the program being executed, without checking it
+    /// against the current utxo. This is needed for peg-ins and for
+    /// legacy outputs, whose scriptpubkeys do not commit to the CMR
+    pub fn with_script_cmr(mut self, script_cmr: Cmr) -> TxEnv {
+        self.script_cmr = Some(script_cmr);
+        self
+    }
+
+    /// Set the CMR of the program being executed, checking that the
+    /// scriptpubkey of the current utxo is the Simplicity output for it,
+    /// i.e. the segwit v1 output with the CMR as its witness program
+    pub fn with_checked_script_cmr(self, script_cmr: Cmr) -> Result<TxEnv, Error> {
+        let utxo = &self.utxos[self.ix as usize];
+        if script_hash(&utxo.script_pubkey) != script_cmr.into_inner() {
+            return Err(Error::ScriptCmrMismatch(self.ix));
+        }
+        Ok(self.with_script_cmr(script_cmr))
+    }
+
+    /// The transaction being validated
+    pub fn tx(&self) -> &elements::Transaction {
+        &self.tx
+    }
+
+    /// The outputs spent by each input of the transaction
+    pub fn utxos(&self) -> &[ElementsUtxo] {
+        &self.utxos
+    }
+
+    /// Index of the input currently being validated
+    pub fn index(&self) -> u32 {
+        self.ix
+    }
+
+    /// CMR of the program being executed, if one was set
+    pub fn script_cmr(&self) -> Option<Cmr> {
+        self.script_cmr
+    }
+
+    /// Cached hash of the transaction inputs, as output by `inputshash`
+    pub fn inputs_hash(&self) -> sha256::Hash {
+        self.inputs_hash
+    }
+
+    /// Cached hash of the transaction outputs, as output by `outputshash`
+    pub fn outputs_hash(&self) -> sha256::Hash {
+        self.outputs_hash
+    }
+
+    /// Cached hash of the current input's annex, as output by
+    /// `currentannexhash`. Like BIP 341's `sha_annex`, this is the SHA256
+    /// of the annex prefixed with its length
+    pub fn annex_hash(&self) -> Option<sha256::Hash> {
+        self.annex_hash
+    }
+
+    /// Cached issuance entropy and asset ids for the `n`th input. Returns
+    /// `None` if the input does not exist or has no issuance
+    pub fn issuance_ids(&self, n: usize) -> Option<&IssuanceIds> {
+        self.issuance_ids.get(n).and_then(Option::as_ref)
     }
 }
 
@@ -47,6 +781,10 @@ pub enum Node {
     InputPrevOutpoint,
     InputAsset,
     InputAmount,
+    /// Hash of the scriptpubkey of the output spent by the given input
+    /// (see also `Node::InputSpentScriptHash`). For a segwit v1 script,
+    /// such as a Simplicity output committing to a CMR, this is its 32-byte
+    /// witness program, i.e. the CMR; for any other script, its SHA256.
     InputScriptHash,
     InputSequence,
     InputIssuanceBlinding,
@@ -57,6 +795,8 @@ pub enum Node {
     OutputAsset,
     OutputAmount,
     OutputNonce,
+    /// Hash of the scriptpubkey of the given output, as for
+    /// `Node::InputScriptHash`
     OutputScriptHash,
     OutputNullDatum,
     ScriptCmr,
@@ -65,6 +805,8 @@ pub enum Node {
     CurrentPrevOutpoint,
     CurrentAsset,
     CurrentAmount,
+    /// Hash of the scriptpubkey of the output spent by the current input,
+    /// as for `Node::InputScriptHash`
     CurrentScriptHash,
     CurrentSequence,
     CurrentIssuanceBlinding,
@@ -77,11 +819,106 @@ pub enum Node {
     NumInputs,
     NumOutputs,
     Fee,
+    InputPeginGenesis,
+    OutputIsFee,
+    OutputsHashRange,
+    LockTimeIsHeight,
+    LockTimeHeight,
+    LockTimeTime,
+    CurrentRelativeLockTime,
+    ExactlyOneInput,
+    ExactlyOneOutput,
+    HasInputAtLeast,
+    HasOutputAtLeast,
+    InputWitnessHash,
+    CurrentIssuanceAssetId,
+    CurrentIssuanceTokenId,
+    CurrentAnnexHash,
+    InputIssuanceKind,
+    NumInputsCapped,
+    NumOutputsCapped,
+    AllOutputsAsset,
+    TotalInputAmount,
+    TotalOutputAmount,
+    InputPeginHeight,
 }
 
-impl fmt::Display for Node {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(match *self {
+impl Node {
+    /// Alias of `Node::InputScriptHash`, named for what it outputs: the
+    /// hash of the scriptpubkey of the output *spent by* an input, rather
+    /// than of anything in the spending input itself
+    ///
+    /// This is the same primitive, with the same encoding and CMR.
+    #[allow(non_upper_case_globals)]
+    pub const InputSpentScriptHash: Node = Node::InputScriptHash;
+
+    /// All elements primitives, in the order they are declared
+    pub fn all() -> &'static [Node] {
+        &[
+            Node::Version,
+            Node::LockTime,
+            Node::InputIsPegin,
+            Node::InputPrevOutpoint,
+            Node::InputAsset,
+            Node::InputAmount,
+            Node::InputScriptHash,
+            Node::InputSequence,
+            Node::InputIssuanceBlinding,
+            Node::InputIssuanceContract,
+            Node::InputIssuanceEntropy,
+            Node::InputIssuanceAssetAmount,
+            Node::InputIssuanceTokenAmount,
+            Node::OutputAsset,
+            Node::OutputAmount,
+            Node::OutputNonce,
+            Node::OutputScriptHash,
+            Node::OutputNullDatum,
+            Node::ScriptCmr,
+            Node::CurrentIndex,
+            Node::CurrentIsPegin,
+            Node::CurrentPrevOutpoint,
+            Node::CurrentAsset,
+            Node::CurrentAmount,
+            Node::CurrentScriptHash,
+            Node::CurrentSequence,
+            Node::CurrentIssuanceBlinding,
+            Node::CurrentIssuanceContract,
+            Node::CurrentIssuanceEntropy,
+            Node::CurrentIssuanceAssetAmount,
+            Node::CurrentIssuanceTokenAmount,
+            Node::InputsHash,
+            Node::OutputsHash,
+            Node::NumInputs,
+            Node::NumOutputs,
+            Node::Fee,
+            Node::InputPeginGenesis,
+            Node::OutputIsFee,
+            Node::OutputsHashRange,
+            Node::LockTimeIsHeight,
+            Node::LockTimeHeight,
+            Node::LockTimeTime,
+            Node::CurrentRelativeLockTime,
+            Node::ExactlyOneInput,
+            Node::ExactlyOneOutput,
+            Node::HasInputAtLeast,
+            Node::HasOutputAtLeast,
+            Node::InputWitnessHash,
+            Node::CurrentIssuanceAssetId,
+            Node::CurrentIssuanceTokenId,
+            Node::CurrentAnnexHash,
+            Node::InputIssuanceKind,
+            Node::NumInputsCapped,
+            Node::NumOutputsCapped,
+            Node::AllOutputsAsset,
+            Node::TotalInputAmount,
+            Node::TotalOutputAmount,
+            Node::InputPeginHeight,
+        ]
+    }
+
+    /// Name of the primitive, as used by its `Display` implementation
+    pub fn name(&self) -> &'static str {
+        match *self {
             Node::Version => "version",
             Node::LockTime => "locktime",
             Node::InputIsPegin => "inputispegin",
@@ -118,7 +955,50 @@ impl fmt::Display for Node {
             Node::NumInputs => "numinputs",
             Node::NumOutputs => "numoutputs",
             Node::Fee => "fee",
-        })
+            Node::InputPeginGenesis => "inputpegingenesis",
+            Node::OutputIsFee => "outputisfee",
+            Node::OutputsHashRange => "outputshashrange",
+            Node::LockTimeIsHeight => "locktimeisheight",
+            Node::LockTimeHeight => "locktimeheight",
+            Node::LockTimeTime => "locktimetime",
+            Node::CurrentRelativeLockTime => "currentrelativelocktime",
+            Node::ExactlyOneInput => "exactlyoneinput",
+            Node::ExactlyOneOutput => "exactlyoneoutput",
+            Node::HasInputAtLeast => "hasinputatleast",
+            Node::HasOutputAtLeast => "hasoutputatleast",
+            Node::InputWitnessHash => "inputwitnesshash",
+            Node::CurrentIssuanceAssetId => "currentissuanceassetid",
+            Node::CurrentIssuanceTokenId => "currentissuancetokenid",
+            Node::CurrentAnnexHash => "currentannexhash",
+            Node::InputIssuanceKind => "inputissuancekind",
+            Node::NumInputsCapped => "numinputscapped",
+            Node::NumOutputsCapped => "numoutputscapped",
+            Node::AllOutputsAsset => "alloutputsasset",
+            Node::TotalInputAmount => "totalinputamount",
+            Node::TotalOutputAmount => "totaloutputamount",
+            Node::InputPeginHeight => "inputpeginheight",
+        }
+    }
+
+    /// Look up a primitive by its name
+    pub fn from_name(name: &str) -> Option<Node> {
+        Node::all().iter().cloned().find(|node| node.name() == name)
+    }
+
+    /// The name, source type, target type and CMR of the primitive
+    pub fn signature(&self) -> (&'static str, TypeName, TypeName, Cmr) {
+        (
+            self.name(),
+            extension::Node::source_type(self),
+            extension::Node::target_type(self),
+            extension::Node::cmr(self),
+        )
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
 
@@ -183,6 +1063,34 @@ impl extension::Node for Node {
         }
     }
 
+    fn decode_extended(code: usize) -> Result<Node, Error> {
+        match code {
+            1 => Ok(Node::InputPeginGenesis),
+            2 => Ok(Node::OutputIsFee),
+            3 => Ok(Node::OutputsHashRange),
+            4 => Ok(Node::LockTimeIsHeight),
+            5 => Ok(Node::LockTimeHeight),
+            6 => Ok(Node::LockTimeTime),
+            7 => Ok(Node::CurrentRelativeLockTime),
+            8 => Ok(Node::ExactlyOneInput),
+            9 => Ok(Node::ExactlyOneOutput),
+            10 => Ok(Node::HasInputAtLeast),
+            11 => Ok(Node::HasOutputAtLeast),
+            12 => Ok(Node::InputWitnessHash),
+            13 => Ok(Node::CurrentIssuanceAssetId),
+            14 => Ok(Node::CurrentIssuanceTokenId),
+            15 => Ok(Node::CurrentAnnexHash),
+            16 => Ok(Node::InputIssuanceKind),
+            17 => Ok(Node::NumInputsCapped),
+            18 => Ok(Node::NumOutputsCapped),
+            19 => Ok(Node::AllOutputsAsset),
+            20 => Ok(Node::TotalInputAmount),
+            21 => Ok(Node::TotalOutputAmount),
+            22 => Ok(Node::InputPeginHeight),
+            _ => Err(Error::ParseError("unknown extended elements primitive")),
+        }
+    }
+
     fn source_type(&self) -> TypeName {
         match *self {
             Node::Version => TypeName(b"1"),
@@ -201,7 +1109,16 @@ impl extension::Node for Node {
             | Node::OutputAsset
             | Node::OutputAmount
             | Node::OutputNonce
-            | Node::OutputScriptHash => TypeName(b"i"),
+            | Node::OutputScriptHash
+            | Node::InputPeginGenesis
+            | Node::OutputIsFee
+            | Node::HasInputAtLeast
+            | Node::HasOutputAtLeast
+            | Node::InputWitnessHash
+            | Node::InputIssuanceKind
+            | Node::NumInputsCapped
+            | Node::NumOutputsCapped
+            | Node::InputPeginHeight => TypeName(b"i"),
             Node::OutputNullDatum => TypeName(b"*ii"),
             Node::ScriptCmr
             | Node::CurrentIndex
@@ -219,8 +1136,21 @@ impl extension::Node for Node {
             | Node::InputsHash
             | Node::OutputsHash
             | Node::NumInputs
-            | Node::NumOutputs => TypeName(b"1"),
+            | Node::NumOutputs
+            | Node::LockTimeIsHeight
+            | Node::LockTimeHeight
+            | Node::LockTimeTime
+            | Node::CurrentRelativeLockTime
+            | Node::ExactlyOneInput
+            | Node::ExactlyOneOutput
+            | Node::CurrentIssuanceAssetId
+            | Node::CurrentIssuanceTokenId
+            | Node::CurrentAnnexHash => TypeName(b"1"),
             Node::Fee => TypeName(b"h"),
+            Node::OutputsHashRange => TypeName(b"l"),
+            Node::AllOutputsAsset => TypeName(b"h"),
+            Node::TotalInputAmount => TypeName(b"h"),
+            Node::TotalOutputAmount => TypeName(b"h"),
         }
     }
 
@@ -263,6 +1193,28 @@ impl extension::Node for Node {
             Node::NumInputs => TypeName(b"i"),
             Node::NumOutputs => TypeName(b"i"),
             Node::Fee => TypeName(b"l"),
+            Node::InputPeginGenesis => TypeName(b"+1h"),
+            Node::OutputIsFee => TypeName(b"+12"),
+            Node::OutputsHashRange => TypeName(b"+1h"),
+            Node::LockTimeIsHeight => TypeName(b"2"),
+            Node::LockTimeHeight => TypeName(b"+ii"),
+            Node::LockTimeTime => TypeName(b"+ii"),
+            Node::CurrentRelativeLockTime => TypeName(b"+1+ii"),
+            Node::ExactlyOneInput => TypeName(b"2"),
+            Node::ExactlyOneOutput => TypeName(b"2"),
+            Node::HasInputAtLeast => TypeName(b"2"),
+            Node::HasOutputAtLeast => TypeName(b"2"),
+            Node::InputWitnessHash => TypeName(b"+1h"),
+            Node::CurrentIssuanceAssetId => TypeName(b"+1h"),
+            Node::CurrentIssuanceTokenId => TypeName(b"+1h"),
+            Node::CurrentAnnexHash => TypeName(b"+1h"),
+            Node::InputIssuanceKind => TypeName(b"+1+12"),
+            Node::NumInputsCapped => TypeName(b"i"),
+            Node::NumOutputsCapped => TypeName(b"i"),
+            Node::AllOutputsAsset => TypeName(b"2"),
+            Node::TotalInputAmount => TypeName(b"l"),
+            Node::TotalOutputAmount => TypeName(b"l"),
+            Node::InputPeginHeight => TypeName(b"+1i"),
         }
     }
 
@@ -344,6 +1296,68 @@ impl extension::Node for Node {
             Node::NumInputs => Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fnumInputs"),
             Node::NumOutputs => Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fnumOutputs"),
             Node::Fee => Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1ffee"),
+            Node::InputPeginGenesis => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1finputPeginGenesis")
+            }
+            Node::OutputIsFee => Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1foutputIsFee"),
+            Node::OutputsHashRange => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1foutputsHashRange")
+            }
+            Node::LockTimeIsHeight => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1flockTimeIsHeight")
+            }
+            Node::LockTimeHeight => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1flockTimeHeight")
+            }
+            Node::LockTimeTime => Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1flockTimeTime"),
+            Node::CurrentRelativeLockTime => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fcurrentRelLockTime")
+            }
+            Node::ExactlyOneInput => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fexactlyOneInput")
+            }
+            Node::ExactlyOneOutput => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fexactlyOneOutput")
+            }
+            Node::HasInputAtLeast => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fhasInputAtLeast")
+            }
+            Node::HasOutputAtLeast => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fhasOutputAtLeast")
+            }
+            Node::InputWitnessHash => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1finputWitnessHash")
+            }
+            Node::CurrentIssuanceAssetId => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fcurrentIssuanceAssetId")
+            }
+            Node::CurrentIssuanceTokenId => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fcurrentIssuanceTokenId")
+            }
+            Node::CurrentAnnexHash => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fcurrentAnnexHash")
+            }
+            Node::InputIssuanceKind => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1finputIssuanceKind")
+            }
+            Node::NumInputsCapped => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fnumInputsCapped")
+            }
+            Node::NumOutputsCapped => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fnumOutputsCapped")
+            }
+            Node::AllOutputsAsset => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1fallOutputsAsset")
+            }
+            Node::TotalInputAmount => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1ftotalInputAmount")
+            }
+            Node::TotalOutputAmount => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1ftotalOutputAmount")
+            }
+            Node::InputPeginHeight => {
+                Cmr::new(b"Simplicity\x1fPrimitive\x1fElements\x1finputPeginHeight")
+            }
         }
     }
 
@@ -354,18 +1368,18 @@ impl extension::Node for Node {
             Node::InputIsPegin => w.write_u8(64 + 1, 7),
             Node::InputPrevOutpoint => w.write_u8(64 + 2, 7),
             Node::InputAsset => w.write_u8(64 + 3, 7),
-            Node::InputAmount => w.write_u8(128 + 2, 8),
-            Node::InputScriptHash => w.write_u8(128 + 3, 8),
+            Node::InputAmount => w.write_u8(128 + 8, 8),
+            Node::InputScriptHash => w.write_u8(128 + 9, 8),
             Node::InputSequence => w.write_u8(64 + 5, 7),
             Node::InputIssuanceBlinding => w.write_u8(64 + 6, 7),
             Node::InputIssuanceContract => w.write_u8(64 + 7, 7),
-            Node::InputIssuanceEntropy => w.write_u8(128 + 4, 8),
-            Node::InputIssuanceAssetAmount => w.write_u8(128 + 5, 8),
+            Node::InputIssuanceEntropy => w.write_u8(128 + 16, 8),
+            Node::InputIssuanceAssetAmount => w.write_u8(128 + 17, 8),
             Node::InputIssuanceTokenAmount => w.write_u8(64 + 9, 7),
             Node::OutputAsset => w.write_u8(64 + 10, 7),
             Node::OutputAmount => w.write_u8(64 + 11, 7),
-            Node::OutputNonce => w.write_u8(128 + 6, 8),
-            Node::OutputScriptHash => w.write_u8(128 + 7, 8),
+            Node::OutputNonce => w.write_u8(128 + 24, 8),
+            Node::OutputScriptHash => w.write_u8(128 + 25, 8),
             Node::OutputNullDatum => w.write_u8(64 + 13, 7),
             Node::ScriptCmr => w.write_u8(64 + 14, 7),
             Node::CurrentIndex => w.write_u8(64 + 15, 7),
@@ -385,11 +1399,1788 @@ impl extension::Node for Node {
             Node::NumInputs => w.write_u8(64 + 29, 7),
             Node::NumOutputs => w.write_u8(64 + 30, 7),
             Node::Fee => w.write_u8(64 + 31, 7),
+            Node::InputPeginGenesis => Ok(w.write_u8(31, 5)? + encode::encode_natural(1, &mut *w)?),
+            Node::OutputIsFee => Ok(w.write_u8(31, 5)? + encode::encode_natural(2, &mut *w)?),
+            Node::OutputsHashRange => Ok(w.write_u8(31, 5)? + encode::encode_natural(3, &mut *w)?),
+            Node::LockTimeIsHeight => Ok(w.write_u8(31, 5)? + encode::encode_natural(4, &mut *w)?),
+            Node::LockTimeHeight => Ok(w.write_u8(31, 5)? + encode::encode_natural(5, &mut *w)?),
+            Node::LockTimeTime => Ok(w.write_u8(31, 5)? + encode::encode_natural(6, &mut *w)?),
+            Node::CurrentRelativeLockTime => {
+                Ok(w.write_u8(31, 5)? + encode::encode_natural(7, &mut *w)?)
+            }
+            Node::ExactlyOneInput => Ok(w.write_u8(31, 5)? + encode::encode_natural(8, &mut *w)?),
+            Node::ExactlyOneOutput => Ok(w.write_u8(31, 5)? + encode::encode_natural(9, &mut *w)?),
+            Node::HasInputAtLeast => Ok(w.write_u8(31, 5)? + encode::encode_natural(10, &mut *w)?),
+            Node::HasOutputAtLeast => Ok(w.write_u8(31, 5)? + encode::encode_natural(11, &mut *w)?),
+            Node::InputWitnessHash => Ok(w.write_u8(31, 5)? + encode::encode_natural(12, &mut *w)?),
+            Node::CurrentIssuanceAssetId => {
+                Ok(w.write_u8(31, 5)? + encode::encode_natural(13, &mut *w)?)
+            }
+            Node::CurrentIssuanceTokenId => {
+                Ok(w.write_u8(31, 5)? + encode::encode_natural(14, &mut *w)?)
+            }
+            Node::CurrentAnnexHash => Ok(w.write_u8(31, 5)? + encode::encode_natural(15, &mut *w)?),
+            Node::InputIssuanceKind => {
+                Ok(w.write_u8(31, 5)? + encode::encode_natural(16, &mut *w)?)
+            }
+            Node::NumInputsCapped => Ok(w.write_u8(31, 5)? + encode::encode_natural(17, &mut *w)?),
+            Node::NumOutputsCapped => Ok(w.write_u8(31, 5)? + encode::encode_natural(18, &mut *w)?),
+            Node::AllOutputsAsset => Ok(w.write_u8(31, 5)? + encode::encode_natural(19, &mut *w)?),
+            Node::TotalInputAmount => Ok(w.write_u8(31, 5)? + encode::encode_natural(20, &mut *w)?),
+            Node::TotalOutputAmount => {
+                Ok(w.write_u8(31, 5)? + encode::encode_natural(21, &mut *w)?)
+            }
+            Node::InputPeginHeight => Ok(w.write_u8(31, 5)? + encode::encode_natural(22, &mut *w)?),
         }
     }
 
-    fn exec(&self, _mac: &mut exec::BitMachine, _txenv: &Self::TxEnv) {
-        // FIXME finish this
-        unimplemented!()
+    fn exec(&self, mac: &mut exec::BitMachine, txenv: &Self::TxEnv) -> Result<(), Error> {
+        match *self {
+            Node::Version => mac.write_u32(txenv.tx.version),
+            Node::LockTime => mac.write_u32(txenv.tx.lock_time),
+            Node::LockTimeIsHeight => mac.write(locktime_is_height(txenv.tx.lock_time)),
+            Node::LockTimeHeight => {
+                mac.write(locktime_is_height(txenv.tx.lock_time));
+                mac.write_u32(txenv.tx.lock_time);
+            }
+            Node::LockTimeTime => {
+                mac.write(!locktime_is_height(txenv.tx.lock_time));
+                mac.write_u32(txenv.tx.lock_time);
+            }
+            Node::CurrentIndex => mac.write_u32(txenv.ix),
+            Node::CurrentAsset => txenv.utxos[txenv.ix as usize]
+                .asset
+                .simplicity_encode(mac)?,
+            Node::CurrentAmount => txenv.utxos[txenv.ix as usize]
+                .value
+                .simplicity_encode(mac)?,
+            Node::CurrentSequence => mac.write_u32(txenv.tx.input[txenv.ix as usize].sequence),
+            Node::InputSequence => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.input.get(idx) {
+                    Some(txin) => {
+                        mac.write(true);
+                        mac.write_u32(txin.sequence);
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(32);
+                    }
+                }
+            }
+            Node::CurrentRelativeLockTime => {
+                let sequence = txenv.tx.input[txenv.ix as usize].sequence;
+                match relative_locktime(txenv.tx.version, sequence) {
+                    None => {
+                        mac.write(false);
+                        mac.skip(33);
+                    }
+                    Some(RelativeLockTime::Height(n)) => {
+                        mac.write(true);
+                        mac.write(false);
+                        mac.write_u32(n);
+                    }
+                    Some(RelativeLockTime::Time(n)) => {
+                        mac.write(true);
+                        mac.write(true);
+                        mac.write_u32(n);
+                    }
+                }
+            }
+            Node::InputPrevOutpoint => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.input.get(idx) {
+                    Some(txin) => {
+                        // The txid is written in its serialization (not its
+                        // display) byte order, like every other 256-bit hash
+                        mac.write(true);
+                        mac.write_bytes(&txin.previous_output.txid[..]);
+                        mac.write_u32(txin.previous_output.vout);
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256 + 32);
+                    }
+                }
+            }
+            Node::ExactlyOneInput => mac.write(txenv.tx.input.len() == 1),
+            Node::ExactlyOneOutput => mac.write(txenv.tx.output.len() == 1),
+            Node::HasInputAtLeast => {
+                let n = mac.read_u32() as usize;
+                mac.write(txenv.tx.input.len() >= n);
+            }
+            Node::HasOutputAtLeast => {
+                let n = mac.read_u32() as usize;
+                mac.write(txenv.tx.output.len() >= n);
+            }
+            Node::InputScriptHash => {
+                let idx = mac.read_u32() as usize;
+                match txenv.utxos.get(idx) {
+                    Some(utxo) => {
+                        mac.write(true);
+                        mac.write_bytes(&script_hash(&utxo.script_pubkey));
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256);
+                    }
+                }
+            }
+            Node::CurrentScriptHash => {
+                let utxo = &txenv.utxos[txenv.ix as usize];
+                mac.write_bytes(&script_hash(&utxo.script_pubkey));
+            }
+            Node::ScriptCmr => match txenv.script_cmr {
+                Some(cmr) => mac.write_bytes(cmr.as_byte_array()),
+                None => return Err(Error::NoScriptCmr),
+            },
+            Node::OutputScriptHash => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.output.get(idx) {
+                    Some(txout) => {
+                        mac.write(true);
+                        mac.write_bytes(&script_hash(&txout.script_pubkey));
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256);
+                    }
+                }
+            }
+            Node::InputWitnessHash => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.input.get(idx) {
+                    Some(txin) => {
+                        mac.write(true);
+                        mac.write_bytes(&witness_stack_hash(&txin.witness.script_witness));
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256);
+                    }
+                }
+            }
+            Node::CurrentIssuanceAssetId | Node::CurrentIssuanceTokenId => {
+                let ids = txenv.issuance_ids(txenv.ix as usize);
+                let id = match *self {
+                    Node::CurrentIssuanceAssetId => ids.map(|ids| ids.asset),
+                    Node::CurrentIssuanceTokenId => ids.and_then(|ids| ids.token),
+                    _ => unreachable!(),
+                };
+                match id {
+                    Some(id) => {
+                        mac.write(true);
+                        mac.write_bytes(&id.into_inner()[..]);
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256);
+                    }
+                }
+            }
+            Node::CurrentAnnexHash => match txenv.annex_hash {
+                Some(hash) => {
+                    mac.write(true);
+                    mac.write_bytes(&hash[..]);
+                }
+                None => {
+                    mac.write(false);
+                    mac.skip(256);
+                }
+            },
+            Node::InputIssuanceKind => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.input.get(idx) {
+                    Some(txin) => {
+                        mac.write(true);
+                        match IssuanceKind::from_txin(txin) {
+                            None => {
+                                mac.write(false);
+                                mac.skip(1);
+                            }
+                            Some(kind) => {
+                                mac.write(true);
+                                mac.write(kind == IssuanceKind::Reissuance);
+                            }
+                        }
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(2);
+                    }
+                }
+            }
+            Node::NumInputsCapped => {
+                let cap = mac.read_u32();
+                mac.write_u32(cmp::min(txenv.tx.input.len(), cap as usize) as u32);
+            }
+            Node::NumOutputsCapped => {
+                let cap = mac.read_u32();
+                mac.write_u32(cmp::min(txenv.tx.output.len(), cap as usize) as u32);
+            }
+            Node::AllOutputsAsset => {
+                // Confidential assets cannot be shown to match, so count
+                // as mismatches
+                let asset = mac.read_32bytes()?;
+                let all_match = txenv
+                    .tx
+                    .output
+                    .iter()
+                    .filter(|txout| !is_fee_output(txout))
+                    .all(|txout| match txout.asset {
+                        confidential::Asset::Explicit(id) => id.into_inner() == asset,
+                        _ => false,
+                    });
+                mac.write(all_match);
+            }
+            Node::TotalInputAmount => {
+                let asset = mac.read_32bytes()?;
+                let amounts = txenv.utxos.iter().map(|utxo| (&utxo.asset, &utxo.value));
+                mac.write_u64(sum_explicit_amounts(amounts, &asset)?);
+            }
+            Node::TotalOutputAmount => {
+                let asset = mac.read_32bytes()?;
+                let amounts = txenv
+                    .tx
+                    .output
+                    .iter()
+                    .map(|txout| (&txout.asset, &txout.value));
+                mac.write_u64(sum_explicit_amounts(amounts, &asset)?);
+            }
+            Node::InputPeginHeight => {
+                let idx = mac.read_u32() as usize;
+                match txenv.utxos.get(idx).and_then(|utxo| utxo.pegin_height) {
+                    Some(height) => {
+                        mac.write(true);
+                        mac.write_u32(height);
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(32);
+                    }
+                }
+            }
+            Node::InputAsset => {
+                let idx = mac.read_u32() as usize;
+                let asset = txenv.utxos.get(idx).map(|utxo| &utxo.asset);
+                simplicity_encode_opt(mac, asset)?;
+            }
+            Node::InputAmount => {
+                let idx = mac.read_u32() as usize;
+                let value = txenv.utxos.get(idx).map(|utxo| &utxo.value);
+                simplicity_encode_opt(mac, value)?;
+            }
+            Node::OutputAsset => {
+                let idx = mac.read_u32() as usize;
+                let asset = txenv.tx.output.get(idx).map(|txout| &txout.asset);
+                simplicity_encode_opt(mac, asset)?;
+            }
+            Node::OutputNonce => {
+                let idx = mac.read_u32() as usize;
+                let nonce = txenv.tx.output.get(idx).map(|txout| &txout.nonce);
+                simplicity_encode_opt(mac, nonce)?;
+            }
+            Node::OutputAmount => {
+                let idx = mac.read_u32() as usize;
+                let value = txenv.tx.output.get(idx).map(|txout| &txout.value);
+                simplicity_encode_opt(mac, value)?;
+            }
+            Node::InputPeginGenesis => {
+                let idx = mac.read_u32() as usize;
+                match txenv.utxos.get(idx).and_then(|utxo| utxo.pegin) {
+                    Some(genesis) => {
+                        mac.write(true);
+                        mac.write_bytes(&genesis[..]);
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(256);
+                    }
+                }
+            }
+            Node::OutputIsFee => {
+                let idx = mac.read_u32() as usize;
+                match txenv.tx.output.get(idx) {
+                    Some(txout) => {
+                        mac.write(true);
+                        mac.write(is_fee_output(txout));
+                    }
+                    None => {
+                        mac.write(false);
+                        mac.skip(1);
+                    }
+                }
+            }
+            Node::InputsHash => mac.write_bytes(&txenv.inputs_hash[..]),
+            Node::OutputsHash => mac.write_bytes(&txenv.outputs_hash[..]),
+            Node::OutputsHashRange => {
+                let start = mac.read_u32() as usize;
+                let end = mac.read_u32() as usize;
+                if start <= end && end <= txenv.tx.output.len() {
+                    mac.write(true);
+                    mac.write_bytes(&simplicity_hash(&txenv.tx.output[start..end])[..]);
+                } else {
+                    mac.write(false);
+                    mac.skip(256);
+                }
+            }
+            Node::Fee => {
+                let asset = mac.read_32bytes()?;
+                let fees = txenv
+                    .tx
+                    .output
+                    .iter()
+                    .filter(|txout| is_fee_output(txout))
+                    .map(|txout| (&txout.asset, &txout.value));
+                mac.write_u64(sum_explicit_amounts(fees, &asset)?);
+            }
+            Node::NumInputs => mac.write_u32(tx_count(txenv.tx.input.len())?),
+            Node::NumOutputs => mac.write_u32(tx_count(txenv.tx.output.len())?),
+            ref e => return Err(Error::UnimplementedPrimitive(e.name())),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin_hashes::sha256d;
+    use bititer::BitIter;
+    use elements::{AssetIssuance, OutPoint, Transaction, TxIn, TxInWitness, TxOut};
+    use encode::BitWrite;
+    use exec::BitMachine;
+    use test_progs::{sighash_all_program, single_node_prog};
+    use {Program, Value};
+
+    fn utxo(pegin: Option<elements::bitcoin::BlockHash>) -> ElementsUtxo {
+        ElementsUtxo {
+            script_pubkey: Default::default(),
+            asset: confidential::Asset::Null,
+            value: confidential::Value::Null,
+            pegin: pegin,
+            pegin_height: None,
+        }
+    }
+
+    fn issuance_input(vout: u32, nonce: [u8; 32], amount: confidential::Value) -> TxIn {
+        TxIn {
+            previous_output: OutPoint {
+                txid: elements::bitcoin::Txid::from_inner([vout as u8; 32]),
+                vout: vout,
+            },
+            is_pegin: false,
+            has_issuance: true,
+            script_sig: Default::default(),
+            sequence: 0xffff_ffff,
+            asset_issuance: AssetIssuance {
+                asset_blinding_nonce: nonce,
+                asset_entropy: [0xab; 32],
+                amount: amount,
+                inflation_keys: confidential::Value::Null,
+            },
+            witness: TxInWitness::default(),
+        }
+    }
+
+    fn plain_input() -> TxIn {
+        let mut ret = issuance_input(0, [0; 32], confidential::Value::Null);
+        ret.has_issuance = false;
+        ret
+    }
+
+    fn transaction(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: input,
+            output: output,
+        }
+    }
+
+    fn txout(asset: confidential::Asset, value: confidential::Value, script: &[u8]) -> TxOut {
+        TxOut {
+            asset: asset,
+            value: value,
+            nonce: confidential::Nonce::Null,
+            script_pubkey: script.to_vec().into(),
+            witness: Default::default(),
+        }
+    }
+
+    #[test]
+    fn all_primitives() {
+        assert_eq!(Node::all().len(), 58);
+        let mut encodings: Vec<Vec<bool>> = vec![];
+        for node in Node::all() {
+            assert_eq!(Node::from_name(node.name()), Some(*node));
+            assert_eq!(node.signature().0, &node.to_string()[..]);
+
+            let mut w = encode::BitWriter::new(vec![]);
+            extension::Node::encode(node, &mut w).expect("encoding to a Vec");
+            w.flush_all().expect("flushing");
+            let mut iter = BitIter::from(w.into_inner().into_iter());
+            assert_eq!(
+                encode::decode_node_no_witness(0, &mut iter),
+                Ok(::Node::Ext(*node)),
+            );
+
+            let mut bits = vec![];
+            extension::Node::encode(node, &mut bits).expect("encoding to a Vec");
+            for (other, other_bits) in Node::all().iter().zip(&encodings) {
+                assert!(
+                    !bits.starts_with(other_bits) && !other_bits.starts_with(&bits),
+                    "encodings of {} and {} overlap",
+                    node,
+                    other,
+                );
+            }
+            encodings.push(bits);
+        }
+        assert_eq!(Node::from_name("inputvalue"), None);
+    }
+
+    #[test]
+    fn output_widths() {
+        // Every implemented primitive, which must write exactly as many bits
+        // as its target type; this is `debug_assert`ed by the Bit Machine
+        let implemented = [
+            Node::Version,
+            Node::LockTime,
+            Node::LockTimeIsHeight,
+            Node::LockTimeHeight,
+            Node::LockTimeTime,
+            Node::CurrentIndex,
+            Node::CurrentSequence,
+            Node::InputSequence,
+            Node::InputPrevOutpoint,
+            Node::CurrentRelativeLockTime,
+            Node::InputAsset,
+            Node::InputAmount,
+            Node::OutputAsset,
+            Node::OutputAmount,
+            Node::InputPeginGenesis,
+            Node::OutputIsFee,
+            Node::OutputsHash,
+            Node::OutputsHashRange,
+            Node::Fee,
+            Node::ExactlyOneInput,
+            Node::ExactlyOneOutput,
+            Node::HasInputAtLeast,
+            Node::HasOutputAtLeast,
+            Node::InputScriptHash,
+            Node::CurrentScriptHash,
+            Node::OutputScriptHash,
+            Node::InputsHash,
+            Node::InputWitnessHash,
+            Node::CurrentIssuanceAssetId,
+            Node::CurrentIssuanceTokenId,
+            Node::CurrentAnnexHash,
+            Node::InputIssuanceKind,
+            Node::NumInputsCapped,
+            Node::NumOutputsCapped,
+            Node::NumInputs,
+            Node::NumOutputs,
+            Node::AllOutputsAsset,
+            Node::TotalInputAmount,
+            Node::TotalOutputAmount,
+            Node::InputPeginHeight,
+            Node::ScriptCmr,
+        ];
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 500,
+            input: vec![plain_input()],
+            output: vec![txout(
+                confidential::Asset::Confidential(10, [2; 32]),
+                confidential::Value::Confidential(8, [3; 32]),
+                &[0x6a],
+            )],
+        };
+        let mut spent = utxo(Some(elements::bitcoin::BlockHash::from_inner([1; 32])));
+        spent.asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([4; 32]));
+        spent.value = confidential::Value::Explicit(1000);
+        let txenv = TxEnv::from_tx(tx, 0, vec![spent])
+            .unwrap()
+            .with_script_cmr(Cmr::from_byte_array([5; 32]));
+
+        for node in &implemented {
+            let prog = single_node_prog(*node);
+
+            // All-zero inputs give in-range indices, all-one inputs do not
+            let width = prog.root_node().source_ty.bit_width();
+            for &bit in &[false, true] {
+                let mut mac =
+                    BitMachine::with_input_bits(&prog, &vec![bit; width]).expect("input width");
+                mac.exec(&prog, &txenv).expect("executing program");
+                assert_eq!(
+                    mac.output_bits().len(),
+                    prog.root_node().target_ty.bit_width(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cached_issuance_ids() {
+        let mut plain = issuance_input(0, [0; 32], confidential::Value::Null);
+        plain.has_issuance = false;
+        let tx = transaction(
+            vec![
+                plain,
+                issuance_input(1, [0; 32], confidential::Value::Explicit(1000)),
+                issuance_input(2, [0; 32], confidential::Value::Confidential(8, [1; 32])),
+                issuance_input(3, [1; 32], confidential::Value::Explicit(1000)),
+            ],
+            vec![],
+        );
+        let txenv = TxEnv::from_tx(tx.clone(), 0, vec![utxo(None); 4]).unwrap();
+
+        assert_eq!(txenv.issuance_ids(0), None);
+        assert_eq!(txenv.issuance_ids(4), None);
+
+        // New issuances
+        for &(n, confidential) in &[(1, false), (2, true)] {
+            let entropy = AssetId::generate_asset_entropy(
+                tx.input[n].previous_output,
+                sha256::Hash::from_inner([0xab; 32]),
+            );
+            let ids = txenv.issuance_ids(n).expect("cached issuance");
+            assert_eq!(ids.entropy, entropy);
+            assert_eq!(ids.asset, AssetId::from_entropy(entropy));
+            assert_eq!(
+                ids.token,
+                Some(AssetId::reissuance_token_from_entropy(
+                    entropy,
+                    confidential
+                )),
+            );
+        }
+
+        // Reissuance
+        let entropy = sha256::Midstate::from_inner([0xab; 32]);
+        let ids = txenv.issuance_ids(3).expect("cached issuance");
+        assert_eq!(ids.entropy, entropy);
+        assert_eq!(ids.asset, AssetId::from_entropy(entropy));
+        assert_eq!(ids.token, None);
+    }
+
+    #[test]
+    fn current_issuance_ids() {
+        let tx = transaction(
+            vec![
+                plain_input(),
+                issuance_input(1, [0; 32], confidential::Value::Explicit(1000)),
+                issuance_input(2, [1; 32], confidential::Value::Explicit(1000)),
+            ],
+            vec![],
+        );
+        let asset_prog = single_node_prog(Node::CurrentIssuanceAssetId);
+        let token_prog = single_node_prog(Node::CurrentIssuanceTokenId);
+        let run = |prog: &Program<Node>, ix: u32| {
+            let txenv = TxEnv::from_tx(tx.clone(), ix, vec![utxo(None); 3]).unwrap();
+            let mut mac = BitMachine::for_program(prog);
+            mac.input(&Value::Unit);
+            let output = mac.exec(prog, &txenv).expect("executing program");
+            output.to_bits(&prog.root_node().target_ty)
+        };
+        let some = |id: AssetId| {
+            let mut ret = vec![true];
+            ret.extend(BitIter::from(id.into_inner().into_inner().iter().cloned()));
+            ret
+        };
+
+        // No issuance
+        assert_eq!(run(&asset_prog, 0), vec![false; 257]);
+        assert_eq!(run(&token_prog, 0), vec![false; 257]);
+
+        // New issuance, with explicit amounts
+        let entropy = AssetId::generate_asset_entropy(
+            tx.input[1].previous_output,
+            sha256::Hash::from_inner([0xab; 32]),
+        );
+        assert_eq!(run(&asset_prog, 1), some(AssetId::from_entropy(entropy)));
+        assert_eq!(
+            run(&token_prog, 1),
+            some(AssetId::reissuance_token_from_entropy(entropy, false)),
+        );
+
+        // Reissuance, which does not determine the token id
+        let entropy = sha256::Midstate::from_inner([0xab; 32]);
+        assert_eq!(run(&asset_prog, 2), some(AssetId::from_entropy(entropy)));
+        assert_eq!(run(&token_prog, 2), vec![false; 257]);
+    }
+
+    #[test]
+    fn input_pegin_genesis() {
+        // 0 11111 0 0 (single `inputpegingenesis` node, no witness)
+        let prog = vec![0x7c];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Ext(Node::InputPeginGenesis));
+        let mut enc = Vec::<bool>::new();
+        extension::Node::encode(&Node::InputPeginGenesis, &mut enc).expect("encoding to a Vec");
+        assert_eq!(enc, vec![true, true, true, true, true, false],);
+
+        let genesis = elements::bitcoin::BlockHash::from_inner([0x0f; 32]);
+        let mut pegin = issuance_input(0, [0; 32], confidential::Value::Null);
+        pegin.is_pegin = true;
+        pegin.has_issuance = false;
+        let mut plain = issuance_input(1, [0; 32], confidential::Value::Null);
+        plain.has_issuance = false;
+        let tx = transaction(vec![pegin, plain], vec![]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(Some(genesis)), utxo(None)]).unwrap();
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(0));
+        mac.exec(&prog, &txenv).expect("executing program");
+        let bits = mac.output_bits();
+        assert_eq!(bits.len(), 257);
+        assert!(bits[0]);
+        assert_eq!(
+            bits[1..],
+            BitIter::from(genesis[..].iter().cloned()).collect::<Vec<_>>()[..],
+        );
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(1));
+        mac.exec(&prog, &txenv).expect("executing program");
+        assert!(!mac.output_bits()[0]);
+    }
+
+    #[test]
+    fn output_is_fee() {
+        // 0 11111 100 0 000000 (single `outputisfee` node, no witness)
+        let prog = vec![0x7e, 0x00];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Ext(Node::OutputIsFee));
+
+        let asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([1; 32]));
+        let other_asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([2; 32]));
+        let tx = transaction(
+            vec![plain_input()],
+            vec![
+                txout(asset, confidential::Value::Explicit(1000), &[0x51]),
+                txout(asset, confidential::Value::Explicit(20), &[]),
+                txout(asset, confidential::Value::Confidential(8, [3; 32]), &[]),
+                txout(other_asset, confidential::Value::Explicit(30), &[]),
+                txout(asset, confidential::Value::Explicit(5), &[]),
+            ],
+        );
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        for &(idx, ref expected) in &[
+            (0, Value::sum_r(Value::u1(0))),
+            (1, Value::sum_r(Value::u1(1))),
+            (2, Value::sum_r(Value::u1(0))),
+            (3, Value::sum_r(Value::u1(1))),
+            (4, Value::sum_r(Value::u1(1))),
+            (5, Value::sum_l(Value::Unit)),
+        ] {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            assert_eq!(mac.exec(&prog, &txenv), Ok(expected.clone()));
+        }
+
+        // 0 10 11111 0 0000000 (single `fee` node, no witness)
+        let prog = vec![0x5f, 0x00];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Ext(Node::Fee));
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u256(&[0x01; 32]));
+        assert_eq!(mac.exec(&prog, &txenv), Ok(Value::u64(25)));
+    }
+
+    #[test]
+    fn output_nonce() {
+        let mut explicit = txout(confidential::Asset::Null, confidential::Value::Null, &[]);
+        explicit.nonce = confidential::Nonce::Explicit(sha256d::Hash::from_inner([0xff; 32]));
+        let mut conf = explicit.clone();
+        conf.nonce = confidential::Nonce::Confidential(3, [0xff; 32]);
+        let null = txout(confidential::Asset::Null, confidential::Value::Null, &[]);
+        let tx = transaction(vec![plain_input()], vec![explicit, conf, null]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        let prog = single_node_prog(Node::OutputNonce);
+        let run = |idx: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            mac.exec(&prog, &txenv)
+                .map(|output| output.to_bits(&prog.root_node().target_ty))
+        };
+
+        // Exactly 256 bits of data follow the tags (and padding)
+        let explicit = run(0).expect("executing program");
+        assert_eq!(explicit[..3], [true, true, false]);
+        assert!(explicit[3..].iter().all(|bit| *bit));
+        assert_eq!(explicit.len(), 3 + 256);
+        let conf = run(1).expect("executing program");
+        assert_eq!(conf[..2], [true, false]);
+        assert!(conf[2..].iter().all(|bit| *bit));
+        assert_eq!(run(2), Err(Error::NullConfidentialField));
+    }
+
+    #[test]
+    fn confidential_asset_parity() {
+        let tx = transaction(
+            vec![plain_input()],
+            [0x0a, 0x0b, 0x08]
+                .iter()
+                .map(|&prefix| {
+                    txout(
+                        confidential::Asset::Confidential(prefix, [0; 32]),
+                        confidential::Value::Null,
+                        &[],
+                    )
+                })
+                .collect(),
+        );
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        let prog = single_node_prog(Node::OutputAsset);
+        let run = |idx: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            mac.exec(&prog, &txenv)
+                .map(|output| output.to_bits(&prog.root_node().target_ty))
+        };
+
+        // Output present, confidential, parity, then 256 bits of commitment
+        let even = run(0).expect("executing program");
+        assert_eq!(even.len(), 1 + 258);
+        assert_eq!(even[..3], [true, false, false]);
+        let odd = run(1).expect("executing program");
+        assert_eq!(odd[..3], [true, false, true]);
+        // 0x08 is a value prefix, not an asset prefix
+        assert_eq!(run(2), Err(Error::BadConfidentialPrefix(0x08)));
+    }
+
+    #[test]
+    fn explicit_amount_padding() {
+        let tx = transaction(vec![plain_input()], vec![]);
+        let mut spent = utxo(None);
+        spent.value = confidential::Value::Explicit(42);
+        let txenv = TxEnv::from_tx(tx, 0, vec![spent]).unwrap();
+
+        let prog = single_node_prog(Node::InputAmount);
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(0));
+        let output = mac.exec(&prog, &txenv).expect("executing program");
+        let bits = output.to_bits(&prog.root_node().target_ty);
+
+        // Input present, then a 258-bit field: explicit, padding, amount
+        assert_eq!(bits.len(), 1 + 258);
+        assert_eq!(bits[..2], [true, true]);
+        assert!(bits[2..2 + 193].iter().all(|bit| !bit));
+        let amount = bits[2 + 193..]
+            .iter()
+            .fold(0u64, |acc, &bit| 2 * acc + bit as u64);
+        assert_eq!(amount, 42);
+    }
+
+    #[test]
+    fn txenv_accessors() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 10,
+            input: vec![plain_input(), plain_input()],
+            output: vec![txout(
+                confidential::Asset::Null,
+                confidential::Value::Null,
+                &[],
+            )],
+        };
+        let utxos = vec![utxo(None), utxo(Some(Default::default()))];
+        let txenv = TxEnv::from_tx(tx.clone(), 1, utxos.clone()).unwrap();
+
+        assert_eq!(*txenv.tx(), tx);
+        assert_eq!(txenv.utxos(), &utxos[..]);
+        assert_eq!(txenv.index(), 1);
+        assert_eq!(txenv.inputs_hash(), compute_inputs_hash(&tx.input));
+        assert_eq!(txenv.outputs_hash(), compute_outputs_hash(&tx.output));
+    }
+
+    #[test]
+    fn standalone_hashes() {
+        let tx = transaction(
+            vec![
+                plain_input(),
+                issuance_input(1, [0; 32], confidential::Value::Explicit(1000)),
+            ],
+            vec![
+                txout(
+                    confidential::Asset::Null,
+                    confidential::Value::Null,
+                    &[0x51],
+                ),
+                txout(confidential::Asset::Null, confidential::Value::Null, &[]),
+            ],
+        );
+        let inputs_hash = compute_inputs_hash(&tx.input);
+        let outputs_hash = compute_outputs_hash(&tx.output);
+        assert_ne!(inputs_hash, compute_inputs_hash(&tx.input[..1]));
+        assert_ne!(outputs_hash, compute_outputs_hash(&tx.output[..1]));
+
+        // No data is hashed for an empty list
+        let empty_hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(compute_inputs_hash(&[]).to_string(), empty_hash);
+        assert_eq!(compute_outputs_hash(&[]).to_string(), empty_hash);
+
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None); 2]).unwrap();
+        assert_eq!(txenv.inputs_hash, inputs_hash);
+        assert_eq!(txenv.outputs_hash, outputs_hash);
+        for &(node, hash) in &[
+            (Node::InputsHash, inputs_hash),
+            (Node::OutputsHash, outputs_hash),
+        ] {
+            let prog = single_node_prog(node);
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::Unit);
+            mac.exec(&prog, &txenv).expect("executing program");
+            assert_eq!(mac.output_hex(), hash.to_string());
+        }
+    }
+
+    #[test]
+    fn outputs_hash_range() {
+        // 0 11111 101 0 000000 (single `outputshashrange` node, no witness)
+        let prog = vec![0x7e, 0x80];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Ext(Node::OutputsHashRange));
+
+        let asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([1; 32]));
+        let tx = transaction(
+            vec![plain_input()],
+            vec![
+                txout(asset, confidential::Value::Explicit(1000), &[0x51]),
+                txout(
+                    asset,
+                    confidential::Value::Confidential(8, [3; 32]),
+                    &[0x52],
+                ),
+                txout(asset, confidential::Value::Explicit(20), &[]),
+            ],
+        );
+        let txenv = TxEnv::from_tx(tx.clone(), 0, vec![utxo(None)]).unwrap();
+
+        let hash_range = |start: u32, end: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::prod(Value::u32(start), Value::u32(end)));
+            mac.exec(&prog, &txenv).expect("executing program");
+            mac.output_bits()
+        };
+        let some_hash = |hash: sha256::Hash| {
+            let mut bits = vec![true];
+            bits.extend(BitIter::from(hash.into_inner().iter().cloned()));
+            bits
+        };
+
+        let mut eng = sha256::Hash::engine();
+        tx.output[1].simplicity_hash(&mut eng);
+        tx.output[2].simplicity_hash(&mut eng);
+        assert_eq!(hash_range(1, 3), some_hash(sha256::Hash::from_engine(eng)));
+        assert_eq!(hash_range(0, 3), some_hash(txenv.outputs_hash));
+        assert_eq!(hash_range(2, 2), some_hash(sha256::Hash::hash(&[])));
+        assert!(!hash_range(2, 1)[0]);
+        assert!(!hash_range(1, 4)[0]);
+    }
+
+    #[test]
+    fn input_prev_outpoint() {
+        // 0 10 00010 0 (single `inputprevoutpoint` node, no witness)
+        let prog = vec![0x42, 0x00];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Ext(Node::InputPrevOutpoint));
+        assert_eq!(prog.root_node().target_ty.bit_width(), 1 + 256 + 32);
+
+        let tx = transaction(
+            vec![issuance_input(7, [0; 32], confidential::Value::Null)],
+            vec![],
+        );
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        // Both branches advance the write cursor by the full target width,
+        // which is `debug_assert`ed by the Bit Machine
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(0));
+        let output = mac.exec(&prog, &txenv).expect("executing program");
+        let mut expected = vec![true];
+        expected.extend(BitIter::from([7; 32].iter().cloned()));
+        expected.extend(BitIter::from([0, 0, 0, 7].iter().cloned()));
+        assert_eq!(output.to_bits(&prog.root_node().target_ty), expected);
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(1));
+        let output = mac.exec(&prog, &txenv).expect("executing program");
+        let bits = output.to_bits(&prog.root_node().target_ty);
+        assert_eq!(bits.len(), expected.len());
+        assert!(bits.iter().all(|bit| !bit));
+    }
+
+    #[test]
+    fn input_witness_hash() {
+        let mut input = plain_input();
+        input.witness.script_witness = vec![vec![0xaa; 3], vec![], vec![0x51]];
+        let tx = transaction(vec![input, plain_input()], vec![]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None), utxo(None)]).unwrap();
+
+        let prog = single_node_prog(Node::InputWitnessHash);
+        let run = |idx: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            let output = mac.exec(&prog, &txenv).expect("executing program");
+            output.to_bits(&prog.root_node().target_ty)
+        };
+
+        // 3 items: 3 bytes, 0 bytes, 1 byte
+        let serialized = [0x03, 0x03, 0xaa, 0xaa, 0xaa, 0x00, 0x01, 0x51];
+        let mut expected = vec![true];
+        expected.extend(BitIter::from(
+            sha256::Hash::hash(&serialized).into_inner().iter().cloned(),
+        ));
+        assert_eq!(run(0), expected);
+
+        // An empty stack serializes to a single zero byte
+        let mut expected = vec![true];
+        expected.extend(BitIter::from(
+            sha256::Hash::hash(&[0x00]).into_inner().iter().cloned(),
+        ));
+        assert_eq!(run(1), expected);
+        assert_eq!(run(2), vec![false; 257]);
+    }
+
+    #[test]
+    fn with_index() {
+        let mut with_annex = plain_input();
+        with_annex.witness.script_witness = vec![vec![0xaa], vec![0x50]];
+        let tx = transaction(
+            vec![plain_input(), with_annex],
+            vec![txout(
+                confidential::Asset::Null,
+                confidential::Value::Explicit(1),
+                &[0x51],
+            )],
+        );
+        assert_eq!(
+            TxEnv::from_tx(tx.clone(), 0, vec![utxo(None)]).err(),
+            Some(Error::UtxoCountMismatch(2, 1)),
+        );
+        assert_eq!(
+            TxEnv::from_tx(tx.clone(), 2, vec![utxo(None); 2]).err(),
+            Some(Error::BadInputIndex(2, 2)),
+        );
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None); 2]).unwrap();
+        let other = txenv.with_index(1).expect("valid index");
+        assert_eq!(other.inputs_hash(), txenv.inputs_hash());
+        assert_eq!(other.outputs_hash(), txenv.outputs_hash());
+        assert_eq!(txenv.annex_hash(), None);
+        assert!(other.annex_hash().is_some());
+        assert_eq!(txenv.with_index(2).err(), Some(Error::BadInputIndex(2, 2)),);
+
+        let prog = single_node_prog(Node::CurrentIndex);
+        let run = |txenv: &TxEnv| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::Unit);
+            mac.exec(&prog, txenv).expect("executing program")
+        };
+        assert_eq!(run(&txenv), Value::u32(0));
+        assert_eq!(run(&other), Value::u32(1));
+    }
+
+    #[test]
+    fn current_annex_hash() {
+        let mut with_annex = plain_input();
+        with_annex.witness.script_witness = vec![vec![0xaa], vec![0x50, 0x01]];
+        // A single item is never an annex
+        let mut lone_item = plain_input();
+        lone_item.witness.script_witness = vec![vec![0x50, 0x01]];
+        let tx = transaction(vec![with_annex, lone_item, plain_input()], vec![]);
+
+        let prog = single_node_prog(Node::CurrentAnnexHash);
+        let run = |ix: u32| {
+            let txenv = TxEnv::from_tx(tx.clone(), ix, vec![utxo(None); 3]).unwrap();
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::Unit);
+            let output = mac.exec(&prog, &txenv).expect("executing program");
+            output.to_bits(&prog.root_node().target_ty)
+        };
+
+        let mut expected = vec![true];
+        expected.extend(BitIter::from(
+            sha256::Hash::hash(&[0x02, 0x50, 0x01])
+                .into_inner()
+                .iter()
+                .cloned(),
+        ));
+        assert_eq!(run(0), expected);
+        assert_eq!(run(1), vec![false; 257]);
+        assert_eq!(run(2), vec![false; 257]);
+    }
+
+    #[test]
+    fn input_issuance_kind() {
+        let tx = transaction(
+            vec![
+                plain_input(),
+                issuance_input(1, [0; 32], confidential::Value::Explicit(1000)),
+                issuance_input(2, [1; 32], confidential::Value::Explicit(1000)),
+            ],
+            vec![],
+        );
+        let txenv = TxEnv::from_tx(tx.clone(), 0, vec![utxo(None); 3]).unwrap();
+        assert_eq!(IssuanceKind::from_txin(&tx.input[0]), None);
+        assert_eq!(
+            IssuanceKind::from_txin(&tx.input[1]),
+            Some(IssuanceKind::New)
+        );
+        assert_eq!(
+            IssuanceKind::from_txin(&tx.input[2]),
+            Some(IssuanceKind::Reissuance)
+        );
+
+        let prog = single_node_prog(Node::InputIssuanceKind);
+        for &(idx, ref expected) in &[
+            (0, Value::sum_r(Value::sum_l(Value::Unit))),
+            (1, Value::sum_r(Value::sum_r(Value::u1(0)))),
+            (2, Value::sum_r(Value::sum_r(Value::u1(1)))),
+            (3, Value::sum_l(Value::Unit)),
+        ] {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            assert_eq!(mac.exec(&prog, &txenv), Ok(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn confidential_field_widths() {
+        let hash = sha256d::Hash::from_inner([0x5a; 32]);
+        let explicit = |payload: &[u8]| {
+            let mut bits = vec![true, true];
+            bits.extend(vec![false; 257 - 8 * payload.len()]);
+            bits.extend(BitIter::from(payload.iter().cloned()));
+            bits
+        };
+        let confidential = |parity: bool| {
+            let mut bits = vec![true, false, parity];
+            bits.extend(BitIter::from([0x77; 32].iter().cloned()));
+            bits
+        };
+
+        let mut outputs = vec![];
+        let mut expected = vec![];
+        for &(asset, value, nonce, ref expected_bits) in &[
+            (
+                confidential::Asset::Explicit(hash),
+                confidential::Value::Explicit(0x0102_0304_0506_0708),
+                confidential::Nonce::Explicit(hash),
+                [
+                    explicit(&[0x5a; 32]),
+                    explicit(&[1, 2, 3, 4, 5, 6, 7, 8]),
+                    explicit(&[0x5a; 32]),
+                ],
+            ),
+            (
+                confidential::Asset::Confidential(0x0a, [0x77; 32]),
+                confidential::Value::Confidential(0x08, [0x77; 32]),
+                confidential::Nonce::Confidential(0x02, [0x77; 32]),
+                [
+                    confidential(false),
+                    confidential(false),
+                    confidential(false),
+                ],
+            ),
+            (
+                confidential::Asset::Confidential(0x0b, [0x77; 32]),
+                confidential::Value::Confidential(0x09, [0x77; 32]),
+                confidential::Nonce::Confidential(0x03, [0x77; 32]),
+                [confidential(true), confidential(true), confidential(true)],
+            ),
+        ] {
+            let mut output = txout(asset, value, &[]);
+            output.nonce = nonce;
+            outputs.push(output);
+            expected.push(expected_bits.clone());
+        }
+        let tx = transaction(vec![plain_input()], outputs);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        for (field, node) in [Node::OutputAsset, Node::OutputAmount, Node::OutputNonce]
+            .iter()
+            .enumerate()
+        {
+            let prog = single_node_prog(*node);
+            for (idx, expected_bits) in expected.iter().enumerate() {
+                // Debug builds also check that the primitive wrote exactly
+                // this many bits
+                let mut mac = BitMachine::for_program(&prog);
+                mac.input(&Value::u32(idx as u32));
+                mac.exec(&prog, &txenv).expect("executing program");
+                let bits = mac.output_bits();
+                assert_eq!(bits.len(), 1 + 258);
+                assert_eq!(bits, expected_bits[field]);
+            }
+        }
+    }
+
+    #[test]
+    fn confidential_field_lengths() {
+        let mut mac = BitMachine::with_frames(0, 1 + CONFIDENTIAL_WIDTH);
+        let remaining = mac.write_frame_remaining();
+
+        // A truncated explicit nonce is rejected rather than padded
+        let nonce = [0x5a; 32];
+        assert_eq!(
+            encode_confidential_field(&mut mac, &[], true, &nonce[..31], 32),
+            Err(Error::BadConfidentialLength(32, 31)),
+        );
+        // As is an arm too wide for the field
+        assert_eq!(
+            encode_confidential_field(&mut mac, &[true, false], false, &nonce, 32),
+            Err(Error::BadConfidentialLength(32, 32)),
+        );
+        assert_eq!(mac.write_frame_remaining(), remaining);
+
+        assert_eq!(
+            encode_confidential_field(&mut mac, &[], true, &nonce, 32),
+            Ok(())
+        );
+        assert_eq!(mac.write_frame_remaining(), 0);
+    }
+
+    #[test]
+    fn all_outputs_asset() {
+        let asset = [0x11; 32];
+        let explicit =
+            |bytes: [u8; 32]| confidential::Asset::Explicit(sha256d::Hash::from_inner(bytes));
+        let amount = confidential::Value::Explicit(1000);
+        let matching = txout(explicit(asset), amount, &[0x51]);
+        // Fee outputs are ignored, whatever their asset
+        let fee = txout(explicit([0x22; 32]), amount, &[]);
+
+        let prog = single_node_prog(Node::AllOutputsAsset);
+        let run = |outputs: Vec<TxOut>| {
+            let tx = transaction(vec![plain_input()], outputs);
+            let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u256(&[0x11; 32]));
+            mac.exec(&prog, &txenv).expect("executing program")
+        };
+
+        assert_eq!(
+            run(vec![matching.clone(), fee.clone(), matching.clone()]),
+            Value::u1(1)
+        );
+        assert_eq!(run(vec![fee.clone()]), Value::u1(1));
+        assert_eq!(
+            run(vec![
+                matching.clone(),
+                txout(explicit([0x33; 32]), amount, &[0x51]),
+            ]),
+            Value::u1(0)
+        );
+        assert_eq!(
+            run(vec![
+                matching,
+                txout(
+                    confidential::Asset::Confidential(0x0a, asset),
+                    amount,
+                    &[0x51]
+                ),
+                fee,
+            ]),
+            Value::u1(0)
+        );
+    }
+
+    #[test]
+    fn input_pegin_height() {
+        let genesis = elements::bitcoin::BlockHash::from_inner([0x0f; 32]);
+        let mut pegin = plain_input();
+        pegin.is_pegin = true;
+        let tx = transaction(vec![pegin.clone(), pegin, plain_input()], vec![]);
+        let known = ElementsUtxo::new_pegin(
+            Default::default(),
+            confidential::Asset::Null,
+            confidential::Value::Null,
+            genesis,
+            Some(700_000),
+        );
+        let utxos = vec![known, utxo(Some(genesis)), utxo(None)];
+        let txenv = TxEnv::from_tx(tx, 0, utxos).unwrap();
+
+        let prog = single_node_prog(Node::InputPeginHeight);
+        let run = |idx: u32| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u32(idx));
+            mac.exec(&prog, &txenv).expect("executing program");
+            mac.output_bits()
+        };
+
+        let mut expected = vec![true];
+        expected.extend(BitIter::from(vec![0x00, 0x0a, 0xae, 0x60].into_iter()));
+        assert_eq!(run(0), expected);
+        // Peg-in without a known height, non-peg-in, and past the end
+        assert_eq!(run(1), vec![false; 33]);
+        assert_eq!(run(2), vec![false; 33]);
+        assert_eq!(run(3), vec![false; 33]);
+    }
+
+    #[test]
+    fn conservation_program() {
+        let asset = [0x11; 32];
+        let explicit = confidential::Asset::Explicit(sha256d::Hash::from_inner(asset));
+        let other = confidential::Asset::Explicit(sha256d::Hash::from_inner([0x22; 32]));
+        let amount = confidential::Value::Explicit;
+
+        let spent = [
+            txout(explicit, amount(600), &[0x51]),
+            txout(explicit, amount(400), &[0x51]),
+        ];
+        let utxos: Vec<_> = spent.iter().map(ElementsUtxo::from_txout).collect();
+        let run = |outputs: Vec<TxOut>, fee: u64| {
+            let tx = transaction(vec![plain_input(), plain_input()], outputs);
+            let txenv = TxEnv::from_tx(tx, 0, utxos.clone()).unwrap();
+            let prog = super::conservation_program(&asset, fee);
+            let mut mac = BitMachine::for_program(&prog);
+            mac.exec(&prog, &txenv).map(|_| ())
+        };
+
+        // Balanced: 600 + 400 in, 900 out and 100 in fees. Other assets
+        // are ignored
+        let balanced = vec![
+            txout(explicit, amount(900), &[0x51]),
+            txout(other, amount(5), &[0x51]),
+            txout(explicit, amount(100), &[]),
+        ];
+        assert_eq!(run(balanced.clone(), 100), Ok(()));
+        match run(balanced, 50) {
+            Err(Error::AssertionFailed(_, "eq32verify")) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Unbalanced: 50 units go missing
+        let unbalanced = vec![
+            txout(explicit, amount(850), &[0x51]),
+            txout(explicit, amount(100), &[]),
+        ];
+        match run(unbalanced, 100) {
+            Err(Error::AssertionFailed(_, "eq32verify")) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn total_amounts() {
+        let explicit =
+            |bytes: [u8; 32]| confidential::Asset::Explicit(sha256d::Hash::from_inner(bytes));
+        let amount = confidential::Value::Explicit;
+        let (asset_a, asset_b) = ([0x11; 32], [0x22; 32]);
+
+        let spent = [
+            txout(explicit(asset_a), amount(1000), &[0x51]),
+            txout(explicit(asset_b), amount(300), &[0x51]),
+            txout(explicit(asset_a), amount(500), &[0x51]),
+        ];
+        let mut tx = transaction(
+            vec![plain_input(), plain_input(), plain_input()],
+            vec![
+                txout(explicit(asset_a), amount(1400), &[0x51]),
+                txout(explicit(asset_b), amount(300), &[0x51]),
+                // Fee outputs count towards the total
+                txout(explicit(asset_a), amount(100), &[]),
+            ],
+        );
+        let utxos: Vec<_> = spent.iter().map(ElementsUtxo::from_txout).collect();
+
+        let run = |node: Node, tx: &Transaction, asset: [u8; 32]| {
+            let prog = single_node_prog(node);
+            let txenv = TxEnv::from_tx(tx.clone(), 0, utxos.clone()).unwrap();
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::u256(&asset));
+            mac.exec(&prog, &txenv)
+        };
+
+        assert_eq!(
+            run(Node::TotalInputAmount, &tx, asset_a),
+            Ok(Value::u64(1500))
+        );
+        assert_eq!(
+            run(Node::TotalOutputAmount, &tx, asset_a),
+            Ok(Value::u64(1500))
+        );
+        assert_eq!(
+            run(Node::TotalInputAmount, &tx, asset_b),
+            Ok(Value::u64(300))
+        );
+        assert_eq!(
+            run(Node::TotalOutputAmount, &tx, asset_b),
+            Ok(Value::u64(300))
+        );
+        assert_eq!(
+            run(Node::TotalInputAmount, &tx, [0x33; 32]),
+            Ok(Value::u64(0))
+        );
+
+        // Confidential amounts are left out
+        tx.output[0].value = confidential::Value::Confidential(0x08, [0x44; 32]);
+        assert_eq!(
+            run(Node::TotalOutputAmount, &tx, asset_a),
+            Ok(Value::u64(100))
+        );
+
+        // Sums which do not fit in 64 bits are an error, not wrapped
+        tx.output[0].value = amount(0xffff_ffff_ffff_ffd0);
+        assert_eq!(
+            run(Node::TotalOutputAmount, &tx, asset_a),
+            Err(Error::AmountOverflow)
+        );
+        tx.output[2].value = amount(0xffff_ffff_ffff_ffd0);
+        assert_eq!(
+            run(Node::Fee, &tx, asset_a),
+            Ok(Value::u64(0xffff_ffff_ffff_ffd0))
+        );
+    }
+
+    #[test]
+    fn utxo_from_txout() {
+        let asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([1; 32]));
+        let spent = txout(asset, confidential::Value::Explicit(1000), &[0x51]);
+        let utxo = ElementsUtxo::from_txout(&spent);
+        assert_eq!(
+            utxo,
+            ElementsUtxo::new(
+                vec![0x51].into(),
+                asset,
+                confidential::Value::Explicit(1000)
+            ),
+        );
+        assert_eq!(utxo.pegin, None);
+
+        let tx = transaction(vec![plain_input()], vec![]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo]).unwrap();
+
+        let prog = single_node_prog(Node::InputAmount);
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::u32(0));
+        let output = mac.exec(&prog, &txenv).expect("executing program");
+        let bits = output.to_bits(&prog.root_node().target_ty);
+        assert!(bits[0] && bits[1]);
+        let amount = bits[bits.len() - 64..]
+            .iter()
+            .fold(0u64, |acc, &bit| 2 * acc + bit as u64);
+        assert_eq!(amount, 1000);
+    }
+
+    #[test]
+    fn script_hashes() {
+        let cmr = [0xcc; 32];
+        let mut simplicity_script = vec![0x51, 0x20];
+        simplicity_script.extend(&cmr[..]);
+        let legacy_script = vec![0x76, 0xa9, 0x14];
+        let legacy_hash = sha256::Hash::hash(&legacy_script);
+
+        let tx = transaction(
+            vec![plain_input(), plain_input()],
+            vec![
+                txout(
+                    confidential::Asset::Null,
+                    confidential::Value::Null,
+                    &legacy_script,
+                ),
+                txout(
+                    confidential::Asset::Null,
+                    confidential::Value::Null,
+                    &simplicity_script,
+                ),
+            ],
+        );
+        let mut utxos = vec![utxo(None), utxo(None)];
+        utxos[0].script_pubkey = simplicity_script.clone().into();
+        utxos[1].script_pubkey = legacy_script.clone().into();
+        let txenv = TxEnv::from_tx(tx, 0, utxos).unwrap();
+
+        let some = |hash: &[u8]| {
+            let mut bits = vec![true];
+            bits.extend(BitIter::from(hash.iter().cloned()));
+            bits
+        };
+        let bits = |node: Node, idx: u32| {
+            let prog = single_node_prog(node);
+            let mut mac = BitMachine::for_program(&prog);
+            if node == Node::CurrentScriptHash {
+                mac.input(&Value::Unit);
+            } else {
+                mac.input(&Value::u32(idx));
+            }
+            let output = mac.exec(&prog, &txenv).expect("executing program");
+            output.to_bits(&prog.root_node().target_ty)
+        };
+
+        assert_eq!(bits(Node::CurrentScriptHash, 0), some(&cmr)[1..].to_vec());
+        assert_eq!(bits(Node::InputScriptHash, 0), some(&cmr));
+        assert_eq!(bits(Node::InputScriptHash, 1), some(&legacy_hash[..]));
+        assert_eq!(
+            bits(Node::InputSpentScriptHash, 1),
+            bits(Node::InputScriptHash, 1)
+        );
+        assert_eq!(
+            extension::Node::cmr(&Node::InputSpentScriptHash),
+            extension::Node::cmr(&Node::InputScriptHash),
+        );
+        assert_eq!(bits(Node::OutputScriptHash, 0), some(&legacy_hash[..]));
+        assert_eq!(bits(Node::OutputScriptHash, 1), some(&cmr));
+        assert!(!bits(Node::OutputScriptHash, 2)[0]);
+    }
+
+    #[test]
+    fn checked_script_cmr() {
+        let cmr = Cmr::from_byte_array([0xcc; 32]);
+        let mut simplicity_script = vec![0x51, 0x20];
+        simplicity_script.extend(&cmr.as_byte_array()[..]);
+        let tx = transaction(vec![plain_input(), plain_input()], vec![]);
+        let mut utxos = vec![utxo(None), utxo(None)];
+        utxos[0].script_pubkey = simplicity_script.into();
+        utxos[1].script_pubkey = vec![0x76, 0xa9, 0x14].into();
+        let txenv = TxEnv::from_tx(tx, 0, utxos).unwrap();
+
+        let other = txenv.with_index(1).unwrap();
+        let checked = txenv.with_checked_script_cmr(cmr).expect("matching cmr");
+        assert_eq!(checked.script_cmr(), Some(cmr));
+        assert_eq!(checked.with_index(1).unwrap().script_cmr(), None);
+
+        match other.with_index(1).unwrap().with_checked_script_cmr(cmr) {
+            Err(Error::ScriptCmrMismatch(1)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("legacy script matched cmr"),
+        }
+        assert_eq!(other.with_script_cmr(cmr).script_cmr(), Some(cmr));
+    }
+
+    #[test]
+    fn script_cmr() {
+        let tx = transaction(vec![plain_input()], vec![]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+        let cmr = Cmr::from_byte_array([0xcc; 32]);
+
+        let prog = single_node_prog(Node::ScriptCmr);
+        let run = |txenv: &TxEnv| {
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&Value::Unit);
+            mac.exec(&prog, txenv)
+                .map(|output| output.to_bits(&prog.root_node().target_ty))
+        };
+        assert_eq!(run(&txenv), Err(Error::NoScriptCmr));
+        assert_eq!(
+            run(&txenv.with_script_cmr(cmr)),
+            Ok(BitIter::from(cmr.as_byte_array().iter().cloned()).collect()),
+        );
+    }
+
+    #[test]
+    fn unimplemented_primitives() {
+        let tx = transaction(vec![plain_input()], vec![]);
+        let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+
+        for node in &[
+            Node::InputIsPegin,
+            Node::CurrentPrevOutpoint,
+            Node::OutputNullDatum,
+            Node::CurrentIssuanceBlinding,
+            Node::InputIssuanceEntropy,
+        ] {
+            let prog = single_node_prog(*node);
+            let width = prog.root_node().source_ty.bit_width();
+            let mut mac =
+                BitMachine::with_input_bits(&prog, &vec![false; width]).expect("input width");
+            assert_eq!(
+                mac.exec(&prog, &txenv).map(|_| ()),
+                Err(Error::UnimplementedPrimitive(node.name())),
+            );
+        }
+    }
+
+    #[test]
+    fn input_output_counts() {
+        let run = |node: Node, input: Value, txenv: &TxEnv| {
+            let prog = single_node_prog(node);
+            let mut mac = BitMachine::for_program(&prog);
+            mac.input(&input);
+            mac.exec(&prog, txenv).expect("executing program")
+        };
+        let yes = Value::u1(1);
+        let no = Value::u1(0);
+
+        // A transaction always has an input, the one being validated, so
+        // inputs are counted from one and outputs from zero
+        for &count in &[0, 1, 2] {
+            let n_inputs = count + 1;
+            let tx = transaction(
+                vec![plain_input(); n_inputs],
+                vec![txout(confidential::Asset::Null, confidential::Value::Null, &[]); count],
+            );
+            let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None); n_inputs]).unwrap();
+            let exactly_one = |count| if count == 1 { &yes } else { &no };
+
+            assert_eq!(
+                run(Node::ExactlyOneInput, Value::Unit, &txenv),
+                *exactly_one(n_inputs)
+            );
+            assert_eq!(
+                run(Node::ExactlyOneOutput, Value::Unit, &txenv),
+                *exactly_one(count)
+            );
+            for &n in &[0, 1, 2, 3, 4] {
+                let at_least = |count| if count >= n { &yes } else { &no };
+                let input = Value::u32(n as u32);
+                assert_eq!(
+                    run(Node::HasInputAtLeast, input.clone(), &txenv),
+                    *at_least(n_inputs)
+                );
+                assert_eq!(
+                    run(Node::HasOutputAtLeast, input.clone(), &txenv),
+                    *at_least(count)
+                );
+
+                // `n` acts as a cap, which may be above or below the count
+                assert_eq!(
+                    run(Node::NumInputsCapped, input.clone(), &txenv),
+                    Value::u32(cmp::min(n_inputs, n) as u32)
+                );
+                assert_eq!(
+                    run(Node::NumOutputsCapped, input, &txenv),
+                    Value::u32(cmp::min(count, n) as u32)
+                );
+            }
+
+            assert_eq!(
+                run(Node::NumInputs, Value::Unit, &txenv),
+                Value::u32(n_inputs as u32)
+            );
+            assert_eq!(
+                run(Node::NumOutputs, Value::Unit, &txenv),
+                Value::u32(count as u32)
+            );
+        }
+    }
+
+    #[test]
+    fn tx_count_overflow() {
+        assert_eq!(tx_count(0), Ok(0));
+        assert_eq!(tx_count(0xffff_ffff), Ok(0xffff_ffff));
+        // Only reachable where `usize` is wider than 32 bits
+        if let Some(len) = 0xffff_ffff_usize.checked_add(1) {
+            assert_eq!(tx_count(len), Err(Error::CountOverflow(len)));
+        }
+    }
+
+    #[test]
+    fn input_asset_amount() {
+        // 0 1000011 0 0000000 (single `inputasset` node, no witness)
+        let asset_prog = vec![0x43, 0x00];
+        let asset_prog = Program::<Node>::decode(&mut BitIter::from(asset_prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(asset_prog.root_node().node, ::Node::Ext(Node::InputAsset));
+        // 0 10001000 0 000000 (single `inputamount` node, no witness)
+        let amount_prog = vec![0x44, 0x00];
+        let amount_prog = Program::<Node>::decode(&mut BitIter::from(amount_prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(amount_prog.root_node().node, ::Node::Ext(Node::InputAmount));
+
+        let mut plain = issuance_input(0, [0; 32], confidential::Value::Null);
+        plain.has_issuance = false;
+        let tx = transaction(vec![plain.clone(), plain], vec![]);
+        let asset = sha256d::Hash::from_inner([1; 32]);
+        let good = ElementsUtxo {
+            asset: confidential::Asset::Explicit(asset),
+            value: confidential::Value::Confidential(9, [2; 32]),
+            ..utxo(None)
+        };
+        let txenv = TxEnv::from_tx(tx, 0, vec![good, utxo(None)]).unwrap();
+
+        let run = |prog: &Program<Node>, idx: u32| {
+            let mut mac = BitMachine::for_program(prog);
+            mac.input(&Value::u32(idx));
+            mac.exec(prog, &txenv).map(|_| mac.output_bits())
+        };
+
+        // Explicit asset: padded right branch
+        let bits = run(&asset_prog, 0).expect("executing program");
+        assert_eq!(bits[..3], [true, true, false]);
+        assert_eq!(
+            bits[3..],
+            BitIter::from(asset[..].iter().cloned()).collect::<Vec<_>>()[..],
+        );
+        // Confidential amount: left branch with odd parity
+        let bits = run(&amount_prog, 0).expect("executing program");
+        assert_eq!(bits[..3], [true, false, true]);
+        assert_eq!(
+            bits[3..],
+            BitIter::from([2; 32].iter().cloned()).collect::<Vec<_>>()[..],
+        );
+        // Out of range
+        assert!(!run(&asset_prog, 2).expect("executing program")[0]);
+        // Null data
+        assert_eq!(run(&asset_prog, 1), Err(Error::NullConfidentialField));
+        assert_eq!(run(&amount_prog, 1), Err(Error::NullConfidentialField));
+    }
+
+    #[test]
+    fn locktime_discriminators() {
+        // 0 11111 110000 0 000 (single `locktimeisheight` node, no witness)
+        let is_height = vec![0x7f, 0x00];
+        let is_height = Program::<Node>::decode(&mut BitIter::from(is_height.into_iter()))
+            .expect("decoding program");
+        assert_eq!(
+            is_height.root_node().node,
+            ::Node::Ext(Node::LockTimeIsHeight)
+        );
+        // 0 11111 110001 0 000 (single `locktimeheight` node, no witness)
+        let height = vec![0x7f, 0x10];
+        let height = Program::<Node>::decode(&mut BitIter::from(height.into_iter()))
+            .expect("decoding program");
+        assert_eq!(height.root_node().node, ::Node::Ext(Node::LockTimeHeight));
+        // 0 11111 110010 0 000 (single `locktimetime` node, no witness)
+        let time = vec![0x7f, 0x20];
+        let time = Program::<Node>::decode(&mut BitIter::from(time.into_iter()))
+            .expect("decoding program");
+        assert_eq!(time.root_node().node, ::Node::Ext(Node::LockTimeTime));
+
+        let run = |prog: &Program<Node>, lock_time: u32| {
+            let tx = Transaction {
+                version: 2,
+                lock_time: lock_time,
+                input: vec![plain_input()],
+                output: vec![],
+            };
+            let txenv = TxEnv::from_tx(tx, 0, vec![utxo(None)]).unwrap();
+            let mut mac = BitMachine::for_program(prog);
+            mac.exec(prog, &txenv).expect("executing program")
+        };
+
+        let max_height = 499_999_999;
+        let min_time = 500_000_000;
+        assert_eq!(run(&is_height, max_height), Value::u1(1));
+        assert_eq!(run(&is_height, min_time), Value::u1(0));
+        assert_eq!(
+            run(&height, max_height),
+            Value::sum_r(Value::u32(max_height))
+        );
+        assert_eq!(run(&height, min_time), Value::sum_l(Value::u32(min_time)));
+        assert_eq!(run(&time, max_height), Value::sum_l(Value::u32(max_height)));
+        assert_eq!(run(&time, min_time), Value::sum_r(Value::u32(min_time)));
+    }
+
+    #[test]
+    fn current_relative_locktime() {
+        // 0 11111 110011 0 000 (single `currentrelativelocktime` node, no witness)
+        let prog = vec![0x7f, 0x30];
+        let prog = Program::<Node>::decode(&mut BitIter::from(prog.into_iter()))
+            .expect("decoding program");
+        assert_eq!(
+            prog.root_node().node,
+            ::Node::Ext(Node::CurrentRelativeLockTime)
+        );
+
+        let run = |version: u32, sequence: u32| {
+            let mut txin = plain_input();
+            txin.sequence = sequence;
+            let tx = Transaction {
+                version: version,
+                lock_time: 0,
+                input: vec![plain_input(), txin],
+                output: vec![],
+            };
+            let txenv = TxEnv::from_tx(tx, 1, vec![utxo(None), utxo(None)]).unwrap();
+            let mut mac = BitMachine::for_program(&prog);
+            mac.exec(&prog, &txenv).expect("executing program")
+        };
+
+        // Disabled
+        assert_eq!(run(2, 0xffff_ffff), Value::sum_l(Value::Unit));
+        assert_eq!(run(2, 0x8000_0010), Value::sum_l(Value::Unit));
+        assert_eq!(run(1, 0x0000_0010), Value::sum_l(Value::Unit));
+        // Height-based; bits outside of the type flag and value are ignored
+        assert_eq!(
+            run(2, 0x0000_0010),
+            Value::sum_r(Value::sum_l(Value::u32(0x10)))
+        );
+        assert_eq!(
+            run(2, 0x0001_ffff),
+            Value::sum_r(Value::sum_l(Value::u32(0xffff)))
+        );
+        // Time-based
+        assert_eq!(
+            run(2, 0x0040_0010),
+            Value::sum_r(Value::sum_r(Value::u32(0x10)))
+        );
+    }
+
+    #[test]
+    fn sighash_all_msg() {
+        let prog = sighash_all_program();
+        // The node computing the transaction digest, and the node computing
+        // the message from it, which the rest of the program checks the
+        // signature against
+        let nodes: Vec<_> = prog.iter().map(|node| node.node.clone()).collect();
+        assert_eq!(
+            prog.iter().nth(457).unwrap().cmr[..],
+            SIGHASH_ALL_DIGEST_CMR
+        );
+        let msg_prog = Program::<Node>::from_nodes(nodes[..477].to_vec()).unwrap();
+
+        let asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([3; 32]));
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0x0102_0304,
+            input: vec![plain_input(), plain_input()],
+            output: vec![txout(asset, confidential::Value::Explicit(5), &[0x51])],
+        };
+        let mut explicit = utxo(None);
+        explicit.asset = asset;
+        explicit.value = confidential::Value::Explicit(0x1234_5678);
+        // Long enough that the digest needs a second block
+        let mut blinded = explicit.clone();
+        blinded.asset = confidential::Asset::Confidential(0x0b, [4; 32]);
+        blinded.value = confidential::Value::Confidential(0x08, [5; 32]);
+
+        let utxos = vec![explicit, blinded];
+        let mut msgs = vec![];
+        for ix in 0..2 {
+            let txenv = TxEnv::from_tx(tx.clone(), ix, utxos.clone()).unwrap();
+            let msg = super::sighash_all_msg(&txenv).unwrap();
+
+            let mut mac = BitMachine::with_input(&msg_prog, &Value::Unit).unwrap();
+            mac.exec(&msg_prog, &txenv).unwrap();
+            assert_eq!(mac.output_hex(), msg.to_string());
+            msgs.push(msg);
+        }
+        assert_ne!(msgs[0], msgs[1]);
+
+        // A null asset or amount fails, as it does for the program
+        for &(asset, value) in &[
+            (confidential::Asset::Null, confidential::Value::Explicit(1)),
+            (asset, confidential::Value::Null),
+        ] {
+            let mut null = utxos[0].clone();
+            null.asset = asset;
+            null.value = value;
+            let txenv = TxEnv::from_tx(tx.clone(), 0, vec![null, utxos[1].clone()]).unwrap();
+            let mut mac = BitMachine::with_input(&msg_prog, &Value::Unit).unwrap();
+            assert_eq!(
+                mac.exec(&msg_prog, &txenv).err(),
+                Some(Error::NullConfidentialField),
+            );
+            assert_eq!(
+                super::sighash_all_msg(&txenv),
+                Err(Error::NullConfidentialField),
+            );
+        }
     }
 }
@@ -18,37 +18,84 @@
 //! blockchain
 //!
 
+use bitcoin_hashes::{sha256, Hash, HashEngine};
 use std::{fmt, io};
 
 use super::TypeName;
 use bititer::BitIter;
-use cmr::Cmr;
-use encode;
+use cmr::{self, Cmr};
 use Error;
+use {encode, exec};
+
+/// Maximum length of the path taken by the `MerkleVerify` jet. Shorter
+/// paths are padded with empty entries.
+pub const MERKLE_MAX_DEPTH: usize = 8;
+
+/// Tag of the hash of a leaf checked by the `MerkleVerify` jet
+const MERKLE_LEAF_TAG: &[u8] = b"Simplicity\x1fMerkleLeaf";
+
+/// Tag of the hash of an internal node of a tree checked by the
+/// `MerkleVerify` jet. This differs from the leaf tag, so that an internal
+/// node cannot be passed off as a leaf with a shorter path.
+const MERKLE_BRANCH_TAG: &[u8] = b"Simplicity\x1fMerkleBranch";
+
+/// Maximum number of blocks compressed by the `Sha256Blocks` jet. Fewer
+/// blocks are padded with empty entries.
+pub const SHA256_MAX_BLOCKS: usize = 4;
 
 /// Set of new Simplicity nodes enabled by the Bitcoin extension
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum Node {
+    /// Sum of two 32-bit words. The output is the carry bit followed by
+    /// the low 32 bits of the sum, most significant bit first
     Adder32,
+    /// As `Adder32`, with an extra carry-in bit after the two words
     FullAdder32,
+    /// Difference of two 32-bit words. The output is the borrow bit
+    /// followed by the difference modulo 2^32, most significant bit first
     Subtractor32,
+    /// As `Subtractor32`, with an extra borrow-in bit after the two words
     FullSubtractor32,
+    /// Product of two 32-bit words, as a 64-bit word with the high 32
+    /// bits first
     Multiplier32,
+    /// As `Multiplier32`, with two further 32-bit words added to the
+    /// product; this can never overflow
     FullMultiplier32,
     Sha256HashBlock,
+    Sha256Pair,
+    Adder8,
+    Adder16,
+    Subtractor8,
+    Subtractor16,
+    Sha256Tagged,
+    Eq32Verify,
+    Lt32Verify,
+    Le32Verify,
+    MerkleVerify,
+    Sha256Blocks,
+    Sha256Iv,
+    ByteSwap32,
+    ByteSwap64,
+    /// Whether one 32-bit word is less than another, as a bit
+    Lt32,
+    /// Fails unless its input bit is set. Composing a boolean-producing
+    /// jet with this asserts its output, e.g. `comp lt32 verify` behaves
+    /// as `lt32verify`
+    Verify,
+    /// A jet with an extended code this library does not recognize, as
+    /// produced by `Program::decode_with_opaque_jets`, and its CMR if the
+    /// caller supplied it. It can be re-encoded, but fails to execute, and
+    /// its types are unknown.
+    Opaque(usize, Option<Cmr>),
 }
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(match *self {
-            Node::Adder32 => "adder32",
-            Node::FullAdder32 => "fulladder32",
-            Node::Subtractor32 => "subtractor32",
-            Node::FullSubtractor32 => "fullsubtractor32",
-            Node::Multiplier32 => "multiplier32",
-            Node::FullMultiplier32 => "fullmultiplier32",
-            Node::Sha256HashBlock => "sha256hashblock",
-        })
+        match *self {
+            Node::Opaque(code, _) => write!(f, "opaque({})", code),
+            _ => f.write_str(self.name()),
+        }
     }
 }
 
@@ -56,6 +103,27 @@ impl fmt::Display for Node {
 /// of the Simplicity whitepaper. Assumes that a 11 has
 /// already been read from the stream
 pub fn decode_node<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<Node, Error> {
+    match decode_node_with(iter, false)? {
+        Decoded::Jet(jet) => Ok(jet),
+        Decoded::Extended(_) => Err(Error::ParseError("extended primitive, not a jet")),
+    }
+}
+
+/// Result of decoding a codeword with the jet prefix `11`
+pub(crate) enum Decoded {
+    /// A jet
+    Jet(Node),
+    /// An extended primitive of the extension, which is indexed by the
+    /// given natural number
+    Extended(usize),
+}
+
+/// Decode a jet, as `decode_node`, but if `opaque_jets` is set, decode
+/// unknown extended jets as `Node::Opaque` rather than failing
+pub(crate) fn decode_node_with<I: Iterator<Item = u8>>(
+    iter: &mut BitIter<I>,
+    opaque_jets: bool,
+) -> Result<Decoded, Error> {
     match iter.next() {
         Some(false) => {
             let code = match iter.read_bits_be(2) {
@@ -64,23 +132,40 @@ pub fn decode_node<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<Node
             };
             match code {
                 0 => match iter.next() {
-                    Some(false) => Ok(Node::Adder32),
-                    Some(true) => Ok(Node::Subtractor32),
+                    Some(false) => Ok(Decoded::Jet(Node::Adder32)),
+                    Some(true) => Ok(Decoded::Jet(Node::Subtractor32)),
                     None => Err(Error::EndOfStream),
                 },
-                1 => Ok(Node::Multiplier32),
+                1 => Ok(Decoded::Jet(Node::Multiplier32)),
                 2 => match iter.next() {
-                    Some(false) => Ok(Node::FullAdder32),
-                    Some(true) => Ok(Node::FullSubtractor32),
+                    Some(false) => Ok(Decoded::Jet(Node::FullAdder32)),
+                    Some(true) => Ok(Decoded::Jet(Node::FullSubtractor32)),
                     None => Err(Error::EndOfStream),
                 },
-                3 => Ok(Node::FullMultiplier32),
+                3 => Ok(Decoded::Jet(Node::FullMultiplier32)),
                 _ => unreachable!(),
             }
         }
         Some(true) => match iter.next() {
-            Some(false) => Ok(Node::Sha256HashBlock),
-            Some(true) => Err(Error::ParseError("invalid parse 1111")),
+            Some(false) => Ok(Decoded::Jet(Node::Sha256HashBlock)),
+            // Jets and extension primitives added later are each indexed
+            // by a natural number
+            Some(true) => match iter.next() {
+                Some(false) => {
+                    let code = encode::decode_natural(&mut *iter)?;
+                    match Node::all()
+                        .iter()
+                        .cloned()
+                        .find(|jet| jet.extended_code() == Some(code))
+                    {
+                        Some(jet) => Ok(Decoded::Jet(jet)),
+                        None if opaque_jets => Ok(Decoded::Jet(Node::Opaque(code, None))),
+                        None => Err(Error::ParseError("unknown extended jet")),
+                    }
+                }
+                Some(true) => Ok(Decoded::Extended(encode::decode_natural(&mut *iter)?)),
+                None => Err(Error::EndOfStream),
+            },
             None => Err(Error::EndOfStream),
         },
         None => Err(Error::EndOfStream),
@@ -88,6 +173,80 @@ pub fn decode_node<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<Node
 }
 
 impl Node {
+    /// All jets, in the order they are declared
+    pub fn all() -> &'static [Node] {
+        &[
+            Node::Adder32,
+            Node::FullAdder32,
+            Node::Subtractor32,
+            Node::FullSubtractor32,
+            Node::Multiplier32,
+            Node::FullMultiplier32,
+            Node::Sha256HashBlock,
+            Node::Sha256Pair,
+            Node::Adder8,
+            Node::Adder16,
+            Node::Subtractor8,
+            Node::Subtractor16,
+            Node::Sha256Tagged,
+            Node::Eq32Verify,
+            Node::Lt32Verify,
+            Node::Le32Verify,
+            Node::MerkleVerify,
+            Node::Sha256Blocks,
+            Node::Sha256Iv,
+            Node::ByteSwap32,
+            Node::ByteSwap64,
+            Node::Lt32,
+            Node::Verify,
+        ]
+    }
+
+    /// Name of the jet, as used by its `Display` implementation
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Node::Adder32 => "adder32",
+            Node::FullAdder32 => "fulladder32",
+            Node::Subtractor32 => "subtractor32",
+            Node::FullSubtractor32 => "fullsubtractor32",
+            Node::Multiplier32 => "multiplier32",
+            Node::FullMultiplier32 => "fullmultiplier32",
+            Node::Sha256HashBlock => "sha256hashblock",
+            Node::Sha256Pair => "sha256pair",
+            Node::Adder8 => "adder8",
+            Node::Adder16 => "adder16",
+            Node::Subtractor8 => "subtractor8",
+            Node::Subtractor16 => "subtractor16",
+            Node::Sha256Tagged => "sha256tagged",
+            Node::Eq32Verify => "eq32verify",
+            Node::Lt32Verify => "lt32verify",
+            Node::Le32Verify => "le32verify",
+            Node::MerkleVerify => "merkleverify",
+            Node::Sha256Blocks => "sha256blocks",
+            Node::Sha256Iv => "sha256iv",
+            Node::ByteSwap32 => "byteswap32",
+            Node::ByteSwap64 => "byteswap64",
+            Node::Lt32 => "lt32",
+            Node::Verify => "verify",
+            Node::Opaque(..) => "opaque",
+        }
+    }
+
+    /// Look up a jet by its name
+    pub fn from_name(name: &str) -> Option<Node> {
+        Node::all().iter().cloned().find(|node| node.name() == name)
+    }
+
+    /// The name, source type, target type and CMR of the jet
+    pub fn signature(&self) -> (&'static str, TypeName, TypeName, Cmr) {
+        (
+            self.name(),
+            self.source_type(),
+            self.target_type(),
+            self.cmr(),
+        )
+    }
+
     /// Name of the source type for this node
     pub fn source_type(&self) -> TypeName {
         match *self {
@@ -98,6 +257,26 @@ impl Node {
             Node::Multiplier32 => TypeName(b"l"),
             Node::FullMultiplier32 => TypeName(b"*ll"),
             Node::Sha256HashBlock => TypeName(b"*h*hh"),
+            Node::Sha256Pair => TypeName(b"*hh"),
+            Node::Adder8 => TypeName(b"*cc"),
+            Node::Adder16 => TypeName(b"*ss"),
+            Node::Subtractor8 => TypeName(b"*cc"),
+            Node::Subtractor16 => TypeName(b"*ss"),
+            Node::Sha256Tagged => TypeName(b"*hh"),
+            Node::Eq32Verify => TypeName(b"l"),
+            Node::Lt32Verify => TypeName(b"l"),
+            Node::Le32Verify => TypeName(b"l"),
+            // leaf, root and a path of `MERKLE_MAX_DEPTH` optional siblings
+            Node::MerkleVerify => TypeName(b"*h*h*+1+hh*+1+hh*+1+hh*+1+hh*+1+hh*+1+hh*+1+hh+1+hh"),
+            // midstate and `SHA256_MAX_BLOCKS` optional blocks
+            Node::Sha256Blocks => TypeName(b"*h*+1*hh*+1*hh*+1*hh+1*hh"),
+            Node::Sha256Iv => TypeName(b"1"),
+            Node::ByteSwap32 => TypeName(b"i"),
+            Node::ByteSwap64 => TypeName(b"l"),
+            Node::Lt32 => TypeName(b"l"),
+            Node::Verify => TypeName(b"2"),
+            // Unknown; the type checker leaves opaque jets unconstrained
+            Node::Opaque(..) => TypeName(b"1"),
         }
     }
 
@@ -111,6 +290,23 @@ impl Node {
             Node::Multiplier32 => TypeName(b"l"),
             Node::FullMultiplier32 => TypeName(b"l"),
             Node::Sha256HashBlock => TypeName(b"h"),
+            Node::Sha256Pair => TypeName(b"h"),
+            Node::Adder8 => TypeName(b"*2c"),
+            Node::Adder16 => TypeName(b"*2s"),
+            Node::Subtractor8 => TypeName(b"*2c"),
+            Node::Subtractor16 => TypeName(b"*2s"),
+            Node::Sha256Tagged => TypeName(b"h"),
+            Node::Eq32Verify => TypeName(b"1"),
+            Node::Lt32Verify => TypeName(b"1"),
+            Node::Le32Verify => TypeName(b"1"),
+            Node::MerkleVerify => TypeName(b"1"),
+            Node::Sha256Blocks => TypeName(b"h"),
+            Node::Sha256Iv => TypeName(b"h"),
+            Node::ByteSwap32 => TypeName(b"i"),
+            Node::ByteSwap64 => TypeName(b"l"),
+            Node::Lt32 => TypeName(b"2"),
+            Node::Verify => TypeName(b"1"),
+            Node::Opaque(..) => TypeName(b"1"),
         }
     }
 
@@ -153,6 +349,70 @@ impl Node {
                 0xb0, 0x89, 0xfd, 0xea, 0xdf, 0x1b, 0x9b, 0xb3, 0x82, 0xec, 0x6e, 0x69, 0x71, 0x9d,
                 0x31, 0xba, 0xec, 0x9a,
             ])),
+            // Extended jets have no reference specification program to
+            // commit to, so commit to their name instead
+            Node::Sha256Pair => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsha256Pair")),
+            Node::Adder8 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fadder8")),
+            Node::Adder16 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fadder16")),
+            Node::Subtractor8 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsubtractor8")),
+            Node::Subtractor16 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsubtractor16")),
+            Node::Sha256Tagged => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsha256Tagged")),
+            Node::Eq32Verify => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1feq32Verify")),
+            Node::Lt32Verify => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1flt32Verify")),
+            Node::Le32Verify => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fle32Verify")),
+            Node::MerkleVerify => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fmerkleVerify")),
+            Node::Sha256Blocks => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsha256Blocks")),
+            Node::Sha256Iv => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fsha256Iv")),
+            Node::ByteSwap32 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fbyteSwap32")),
+            Node::ByteSwap64 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fbyteSwap64")),
+            Node::Lt32 => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1flt32")),
+            Node::Verify => cmr.update_1(Cmr::new(b"Simplicity\x1fJet\x1fverify")),
+            Node::Opaque(_, Some(supplied)) => supplied,
+            // The real CMR of an unknown jet cannot be derived from its
+            // code, so this is only a placeholder which commits to the code;
+            // `Program::cmr` is `None` for programs using it
+            Node::Opaque(code, None) => {
+                let mut code_bytes = [0; 32];
+                for (i, byte) in code_bytes[24..].iter_mut().enumerate() {
+                    *byte = (code as u64 >> (56 - 8 * i)) as u8;
+                }
+                cmr.update_1(
+                    Cmr::new(b"Simplicity\x1fJet\x1fopaque").update_1(Cmr::from(code_bytes)),
+                )
+            }
+        }
+    }
+
+    /// For jets added after the original set, the natural number which
+    /// follows their `11110` prefix in the encoding; this is the only
+    /// place these numbers are assigned, so encoding and decoding
+    /// cannot disagree
+    fn extended_code(&self) -> Option<usize> {
+        match *self {
+            Node::Adder32
+            | Node::FullAdder32
+            | Node::Subtractor32
+            | Node::FullSubtractor32
+            | Node::Multiplier32
+            | Node::FullMultiplier32
+            | Node::Sha256HashBlock => None,
+            Node::Sha256Pair => Some(1),
+            Node::Adder8 => Some(2),
+            Node::Adder16 => Some(3),
+            Node::Subtractor8 => Some(4),
+            Node::Subtractor16 => Some(5),
+            Node::Sha256Tagged => Some(6),
+            Node::Eq32Verify => Some(7),
+            Node::Lt32Verify => Some(8),
+            Node::Le32Verify => Some(9),
+            Node::MerkleVerify => Some(10),
+            Node::Sha256Blocks => Some(11),
+            Node::Sha256Iv => Some(12),
+            Node::ByteSwap32 => Some(13),
+            Node::ByteSwap64 => Some(14),
+            Node::Lt32 => Some(15),
+            Node::Verify => Some(16),
+            Node::Opaque(code, _) => Some(code),
         }
     }
 
@@ -162,10 +422,734 @@ impl Node {
             Node::Adder32 => w.write_u8(48 + 0, 6),
             Node::Subtractor32 => w.write_u8(48 + 1, 6),
             Node::Multiplier32 => w.write_u8(24 + 1, 5),
-            Node::FullAdder32 => w.write_u8(48 + 2, 6),
-            Node::FullSubtractor32 => w.write_u8(48 + 3, 6),
+            Node::FullAdder32 => w.write_u8(48 + 4, 6),
+            Node::FullSubtractor32 => w.write_u8(48 + 5, 6),
             Node::FullMultiplier32 => w.write_u8(24 + 3, 5),
             Node::Sha256HashBlock => w.write_u8(14, 4),
+            _ => {
+                let code = self.extended_code().expect("extended jet");
+                Ok(w.write_u8(30, 5)? + encode::encode_natural(code, &mut *w)?)
+            }
+        }
+    }
+
+    /// Execute the jet in a Bit Machine
+    ///
+    /// Assuming the surrounding program has typechecked, this can only fail
+    /// if the Bit Machine is in checked-arithmetic mode and an arithmetic
+    /// jet overflows, if an assertion jet fails, or if the jet is opaque. In
+    /// the latter cases the error contains `index`, which should be the
+    /// index of the jet's node in the program.
+    ///
+    /// The machine's current frames must fit the jet's source and target
+    /// types, as they do during program execution or in a
+    /// `BitMachine::test_harness` for the jet.
+    pub fn exec(&self, mac: &mut exec::BitMachine, index: usize) -> Result<(), Error> {
+        match *self {
+            Node::Adder32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let (res, overflow) = a.overflowing_add(b);
+                mac.write_carry(overflow)?;
+                mac.write_u32(res);
+            }
+            Node::FullAdder32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let carry = mac.read_bit();
+                let (res, overflow_1) = a.overflowing_add(b);
+                let (res, overflow_2) = res.overflowing_add(carry as u32);
+                mac.write_carry(overflow_1 || overflow_2)?;
+                mac.write_u32(res);
+            }
+            Node::Subtractor32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let (res, underflow) = a.overflowing_sub(b);
+                mac.write_carry(underflow)?;
+                mac.write_u32(res);
+            }
+            Node::FullSubtractor32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let borrow = mac.read_bit();
+                let (res, underflow_1) = a.overflowing_sub(b);
+                let (res, underflow_2) = res.overflowing_sub(borrow as u32);
+                mac.write_carry(underflow_1 || underflow_2)?;
+                mac.write_u32(res);
+            }
+            Node::Multiplier32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let res = u64::from(a) * u64::from(b);
+                debug_assert!(mac.write_frame_remaining() >= 64);
+                mac.write_u64(res);
+            }
+            Node::FullMultiplier32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let c = mac.read_u32();
+                let d = mac.read_u32();
+                // (2^32 - 1)^2 + 2 * (2^32 - 1) = 2^64 - 1, so this cannot overflow
+                let res = u64::from(a) * u64::from(b) + u64::from(c) + u64::from(d);
+                debug_assert!(mac.write_frame_remaining() >= 64);
+                mac.write_u64(res);
+            }
+            Node::Sha256HashBlock => {
+                let midstate = sha256::Midstate::from_inner(mac.read_32bytes()?);
+                let block = mac.read_bytes(64)?;
+                let mut engine = sha256::HashEngine::from_midstate(midstate, 0);
+                engine.input(&block);
+                mac.write_bytes(&engine.midstate()[..]);
+            }
+            Node::Sha256Pair => {
+                let data = mac.read_bytes(64)?;
+                mac.write_bytes(&sha256::Hash::hash(&data)[..]);
+            }
+            Node::Adder8 => {
+                let a = mac.read_u8();
+                let b = mac.read_u8();
+                let (res, overflow) = a.overflowing_add(b);
+                mac.write_carry(overflow)?;
+                mac.write_u8(res);
+            }
+            Node::Adder16 => {
+                let a = mac.read_u16();
+                let b = mac.read_u16();
+                let (res, overflow) = a.overflowing_add(b);
+                mac.write_carry(overflow)?;
+                mac.write_u16(res);
+            }
+            Node::Subtractor8 => {
+                let a = mac.read_u8();
+                let b = mac.read_u8();
+                let (res, underflow) = a.overflowing_sub(b);
+                mac.write_carry(underflow)?;
+                mac.write_u8(res);
+            }
+            Node::Subtractor16 => {
+                let a = mac.read_u16();
+                let b = mac.read_u16();
+                let (res, underflow) = a.overflowing_sub(b);
+                mac.write_carry(underflow)?;
+                mac.write_u16(res);
+            }
+            Node::Sha256Tagged => {
+                // BIP-340 style tagged hash, SHA256(SHA256(tag) || SHA256(tag) || msg),
+                // where the caller has already hashed the tag
+                let tag_hash = mac.read_32bytes()?;
+                let msg = mac.read_32bytes()?;
+                mac.write_bytes(&cmr::tagged_hash_prehashed(&tag_hash, &msg)[..]);
+            }
+            Node::Eq32Verify | Node::Lt32Verify | Node::Le32Verify => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                let ok = match *self {
+                    Node::Eq32Verify => a == b,
+                    Node::Lt32Verify => a < b,
+                    Node::Le32Verify => a <= b,
+                    _ => unreachable!(),
+                };
+                if !ok {
+                    return Err(Error::AssertionFailed(index, self.name()));
+                }
+            }
+            Node::Lt32 => {
+                let a = mac.read_u32();
+                let b = mac.read_u32();
+                mac.write(a < b);
+            }
+            Node::Verify => {
+                if !mac.read_bit() {
+                    return Err(Error::AssertionFailed(index, self.name()));
+                }
+            }
+            Node::MerkleVerify => {
+                let mut node = cmr::tagged_hash(MERKLE_LEAF_TAG, &mac.read_32bytes()?).into_inner();
+                let root = mac.read_32bytes()?;
+                for _ in 0..MERKLE_MAX_DEPTH {
+                    // Each path entry has type `+1+hh`: either nothing, which
+                    // is followed by 257 bits of padding, or a sibling hash
+                    // tagged with whether it is on the left or the right
+                    if !mac.read_bit() {
+                        mac.fwd(257);
+                        continue;
+                    }
+                    let sibling_on_right = mac.read_bit();
+                    let sibling = mac.read_32bytes()?;
+                    let mut children = [0; 64];
+                    if sibling_on_right {
+                        children[..32].copy_from_slice(&node);
+                        children[32..].copy_from_slice(&sibling);
+                    } else {
+                        children[..32].copy_from_slice(&sibling);
+                        children[32..].copy_from_slice(&node);
+                    }
+                    node = cmr::tagged_hash(MERKLE_BRANCH_TAG, &children).into_inner();
+                }
+                if node != root {
+                    return Err(Error::AssertionFailed(index, self.name()));
+                }
+            }
+            Node::Sha256Blocks => {
+                let midstate = sha256::Midstate::from_inner(mac.read_32bytes()?);
+                let mut engine = sha256::HashEngine::from_midstate(midstate, 0);
+                for _ in 0..SHA256_MAX_BLOCKS {
+                    // Each block has type `+1*hh`; an absent block is
+                    // followed by 512 bits of padding
+                    if mac.read_bit() {
+                        engine.input(&mac.read_bytes(64)?);
+                    } else {
+                        mac.fwd(512);
+                    }
+                }
+                mac.write_bytes(&engine.midstate()[..]);
+            }
+            // The midstate of an engine which has hashed nothing
+            Node::Sha256Iv => mac.write_bytes(&sha256::HashEngine::default().midstate()[..]),
+            // Convert between the little-endian serialization of some
+            // transaction fields and the big-endian words of the Bit Machine
+            Node::ByteSwap32 => {
+                let a = mac.read_u32();
+                mac.write_u32(a.swap_bytes());
+            }
+            Node::ByteSwap64 => {
+                let a = mac.read_u64();
+                mac.write_u64(a.swap_bytes());
+            }
+            Node::Opaque(..) => return Err(Error::OpaqueJet(index)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use encode::{BitWrite, BitWriter};
+    use exec::BitMachine;
+    use extension::dummy::{Node as DummyNode, TxEnv};
+    use program::Program;
+    use std::collections::BTreeMap;
+    use test_progs::decode_prog;
+
+    /// Parse a test vector: whitespace-separated chunks of hex, or of
+    /// binary if prefixed with `b`
+    fn parse_bits(s: &str) -> Vec<bool> {
+        let mut ret = vec![];
+        for chunk in s.split_whitespace() {
+            let mut chars = chunk.chars();
+            if chunk.starts_with('b') {
+                chars.next();
+                ret.extend(chars.map(|c| c == '1'));
+            } else {
+                for c in chars {
+                    let nibble = c.to_digit(16).expect("hex digit");
+                    ret.extend((0..4).map(|i| nibble & (8 >> i) != 0));
+                }
+            }
+        }
+        ret
+    }
+
+    /// Construct a program consisting of the single jet
+    fn jet_program(jet: Node) -> Program<DummyNode> {
+        let mut w = BitWriter::new(vec![]);
+        encode::encode_natural(1, &mut w).expect("encoding to a Vec");
+        jet.encode_node(&mut w).expect("encoding to a Vec");
+        encode::BitWrite::write_bit(&mut w, false).expect("encoding to a Vec");
+        encode::BitWrite::flush_all(&mut w).expect("flushing");
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(w.into_inner().into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Jet(jet));
+        prog
+    }
+
+    /// Run a jet on the given input, returning its output as hex
+    fn jet_output_hex(jet: Node, input: &str) -> String {
+        let prog = jet_program(jet);
+        let mut mac = BitMachine::with_input_bits(&prog, &parse_bits(input)).expect("input");
+        mac.exec(&prog, &TxEnv).expect("executing jet");
+        mac.output_hex()
+    }
+
+    /// Run a jet on the given input, panicking with the jet's name and the
+    /// differing bit positions if its output is not as expected
+    fn run_jet_vector(jet: Node, input: &str, expected: &str) {
+        let prog = jet_program(jet);
+
+        let mut mac = BitMachine::with_input_bits(&prog, &parse_bits(input))
+            .unwrap_or_else(|e| panic!("jet {}: bad input: {:?}", jet, e));
+        let output = mac
+            .exec(&prog, &TxEnv)
+            .unwrap_or_else(|e| panic!("jet {}: execution failed: {:?}", jet, e));
+        let output = output.to_bits(&prog.root_node().target_ty);
+        let expected = parse_bits(expected);
+        assert_eq!(
+            output.len(),
+            expected.len(),
+            "jet {}: output has wrong length",
+            jet,
+        );
+        let diff: Vec<usize> = (0..output.len())
+            .filter(|&i| output[i] != expected[i])
+            .collect();
+        assert!(
+            diff.is_empty(),
+            "jet {}: output differs at bits {:?}",
+            jet,
+            diff
+        );
+    }
+
+    /// Root of the tree in `merkle_input`
+    const MERKLE_ROOT: &str = "afd23d91897bee555a78c4510cd7095e8a49859ea7e017043f97d0d70a7babfc";
+
+    /// Input to `MerkleVerify` proving that `0101..01` is in a tree of
+    /// depth 2, with `0202..02` to its right and then `0303..03` to the left
+    fn merkle_input(root: &str) -> String {
+        let mut ret = format!("{} {}", "01".repeat(32), root);
+        ret.push_str(&format!(" b11 {}", "02".repeat(32)));
+        ret.push_str(&format!(" b10 {}", "03".repeat(32)));
+        for _ in 2..MERKLE_MAX_DEPTH {
+            ret.push_str(&format!(" b0 {} b0", "00".repeat(32)));
+        }
+        ret
+    }
+
+    #[test]
+    fn merkle_verify_tampered() {
+        let prog = jet_program(Node::MerkleVerify);
+
+        let mut bad_root = MERKLE_ROOT.to_owned();
+        bad_root.replace_range(0..2, "61");
+        let mut mac = BitMachine::with_input_bits(&prog, &parse_bits(&merkle_input(&bad_root)))
+            .expect("input");
+        assert_eq!(
+            mac.exec(&prog, &TxEnv),
+            Err(Error::AssertionFailed(0, "merkleverify")),
+        );
+
+        // Swapping the sides of the first sibling also changes the root
+        let input = merkle_input(MERKLE_ROOT).replacen("b11", "b10", 1);
+        let mut mac = BitMachine::with_input_bits(&prog, &parse_bits(&input)).expect("input");
+        assert_eq!(
+            mac.exec(&prog, &TxEnv),
+            Err(Error::AssertionFailed(0, "merkleverify")),
+        );
+
+        // The parent of the leaf is not itself a leaf, even with the rest
+        // of the path
+        let parent = "d26b03cc6f3389266a2a05f81b024b5f515590464f3a99adf701fc9d4c7a4516";
+        let mut input = format!("{} {} b10 {}", parent, MERKLE_ROOT, "03".repeat(32));
+        for _ in 1..MERKLE_MAX_DEPTH {
+            input.push_str(&format!(" b0 {} b0", "00".repeat(32)));
+        }
+        let mut mac = BitMachine::with_input_bits(&prog, &parse_bits(&input)).expect("input");
+        assert_eq!(
+            mac.exec(&prog, &TxEnv),
+            Err(Error::AssertionFailed(0, "merkleverify")),
+        );
+    }
+
+    #[test]
+    fn sha256_blocks() {
+        let iv = "6a09e667bb67ae853c6ef372a54ff53a510e527f9b05688c1f83d9ab5be0cd19";
+        let block_1 = "61".repeat(64);
+        let block_2 = "62".repeat(64);
+
+        let mid_1 = jet_output_hex(Node::Sha256HashBlock, &format!("{} {}", iv, block_1));
+        let mid_2 = jet_output_hex(Node::Sha256HashBlock, &format!("{} {}", mid_1, block_2));
+
+        let absent = format!("b0 {}", "00".repeat(64));
+        let input = format!("{} b1 {} b1 {} {} {}", iv, block_1, block_2, absent, absent);
+        assert_eq!(jet_output_hex(Node::Sha256Blocks, &input), mid_2);
+        // Absent blocks may appear anywhere
+        let input = format!("{} {} b1 {} {} b1 {}", iv, absent, block_1, absent, block_2);
+        assert_eq!(jet_output_hex(Node::Sha256Blocks, &input), mid_2);
+    }
+
+    #[test]
+    fn sha256_iv() {
+        let iv = "6a09e667bb67ae853c6ef372a54ff53a510e527f9b05688c1f83d9ab5be0cd19";
+        assert_eq!(jet_output_hex(Node::Sha256Iv, ""), iv);
+
+        // Compressing the padded empty message from the IV gives the hash
+        // of the empty string
+        let empty_block = format!("80{}", "00".repeat(63));
+        assert_eq!(
+            jet_output_hex(Node::Sha256HashBlock, &format!("{} {}", iv, empty_block)),
+            sha256::Hash::hash(&[]).to_string(),
+        );
+    }
+
+    #[test]
+    fn byte_swap() {
+        assert_eq!(jet_output_hex(Node::ByteSwap32, "01020304"), "04030201");
+        assert_eq!(jet_output_hex(Node::ByteSwap32, "ffffff00"), "00ffffff");
+        assert_eq!(
+            jet_output_hex(Node::ByteSwap64, "0102030405060708"),
+            "0807060504030201",
+        );
+        // Swapping twice is the identity
+        let swapped = jet_output_hex(Node::ByteSwap64, "00112233aabbccdd");
+        assert_eq!(
+            jet_output_hex(Node::ByteSwap64, &swapped),
+            "00112233aabbccdd"
+        );
+    }
+
+    #[test]
+    fn test_harness() {
+        // Frames are sized from the jet's own types
+        let mac = BitMachine::test_harness(Node::Adder32);
+        assert_eq!(mac.read_frame_remaining(), 64);
+        assert_eq!(mac.write_frame_remaining(), 33);
+        let mac = BitMachine::test_harness(Node::Sha256HashBlock);
+        assert_eq!(mac.read_frame_remaining(), 768);
+        assert_eq!(mac.write_frame_remaining(), 256);
+
+        let mut mac = BitMachine::test_harness(Node::Adder32);
+        mac.set_input_u32(0, 0xffff_ffff);
+        mac.set_input_u32(32, 2);
+        Node::Adder32.exec(&mut mac, 0).expect("executing jet");
+        // Carry bit, then the wrapped sum
+        assert_eq!(mac.output_hex(), "8000000080");
+
+        // Inputs may be filled in any order, and bit by bit
+        let mut mac = BitMachine::test_harness(Node::Adder32);
+        mac.set_input_bytes(32, &[0x00, 0x00, 0x01, 0x00]);
+        mac.set_input_bit(31, true);
+        Node::Adder32.exec(&mut mac, 0).expect("executing jet");
+        assert_eq!(mac.output_bits(), parse_bits("b0 00000101"));
+    }
+
+    #[test]
+    fn arithmetic_layout() {
+        let run = |jet: Node, input: &[u8], extra: Option<bool>| {
+            let mut mac = BitMachine::test_harness(jet);
+            mac.set_input_bytes(0, input);
+            if let Some(bit) = extra {
+                mac.set_input_bit(8 * input.len(), bit);
+            }
+            jet.exec(&mut mac, 0).expect("executing jet");
+            mac.output_bits()
+        };
+
+        // Carry bit set, word zero
+        assert_eq!(
+            run(Node::Adder32, &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 1], None),
+            parse_bits("b1 00000000"),
+        );
+        assert_eq!(
+            run(
+                Node::FullAdder32,
+                &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0],
+                Some(true)
+            ),
+            parse_bits("b1 00000000"),
+        );
+        // The result word is big-endian: the low byte is last
+        assert_eq!(
+            run(Node::Adder32, &[0, 0, 0, 0xfe, 0, 0, 0, 1], None),
+            parse_bits("b0 000000ff"),
+        );
+        // Borrow bit set, word wrapped modulo 2^32
+        assert_eq!(
+            run(Node::Subtractor32, &[0, 0, 0, 0, 0, 0, 0, 1], None),
+            parse_bits("b1 ffffffff"),
+        );
+        assert_eq!(
+            run(
+                Node::FullSubtractor32,
+                &[0, 0, 0, 1, 0, 0, 0, 1],
+                Some(true)
+            ),
+            parse_bits("b1 ffffffff"),
+        );
+        // No overflow bit; the high word of the product comes first
+        assert_eq!(
+            run(
+                Node::Multiplier32,
+                &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 2],
+                None
+            ),
+            parse_bits("00000001 fffffffe"),
+        );
+        assert_eq!(
+            run(Node::FullMultiplier32, &[0xff; 16], None,),
+            parse_bits("ffffffff ffffffff"),
+        );
+    }
+
+    #[test]
+    fn assert_wrapping() {
+        // comp lt32 verify
+        let nodes: [::Node<(), DummyNode>; 3] = [
+            ::Node::Jet(Node::Lt32),
+            ::Node::Jet(Node::Verify),
+            ::Node::Comp(0, 1),
+        ];
+        let wrapped: Program<DummyNode> = decode_prog(&nodes);
+        let dedicated = jet_program(Node::Lt32Verify);
+        assert_eq!(
+            wrapped.root_node().source_ty,
+            dedicated.root_node().source_ty
+        );
+        assert_eq!(
+            wrapped.root_node().target_ty,
+            dedicated.root_node().target_ty
+        );
+
+        let run = |prog: &Program<DummyNode>, input: &str| {
+            let mut mac = BitMachine::with_input_bits(prog, &parse_bits(input)).expect("input");
+            mac.exec(prog, &TxEnv).map(|_| ())
+        };
+        for input in &[
+            "00000002 00000003",
+            "00000003 00000003",
+            "00000004 00000003",
+        ] {
+            let expected = run(&dedicated, input).is_ok();
+            assert_eq!(run(&wrapped, input).is_ok(), expected, "input {}", input);
+        }
+        // The failure is reported at the `verify` node
+        assert_eq!(
+            run(&wrapped, "00000003 00000003"),
+            Err(Error::AssertionFailed(1, "verify")),
+        );
+    }
+
+    #[test]
+    fn frame_underflow() {
+        // A 64-bit input frame is too small even for the midstate
+        let mut mac = BitMachine::with_frames(64, 256);
+        assert_eq!(
+            Node::Sha256HashBlock.exec(&mut mac, 0),
+            Err(Error::FrameUnderflow(256, 64)),
+        );
+
+        // A frame holding only the midstate is too small for the block
+        let mut mac = BitMachine::with_frames(256, 256);
+        assert_eq!(
+            Node::Sha256HashBlock.exec(&mut mac, 0),
+            Err(Error::FrameUnderflow(512, 0)),
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        let mut encodings = vec![];
+        for jet in Node::all() {
+            let mut bits = vec![];
+            jet.encode_node(&mut bits).expect("encoding to a Vec");
+            encodings.push(bits);
+
+            let mut w = BitWriter::new(vec![]);
+            jet.encode_node(&mut w).expect("encoding to a Vec");
+            encode::BitWrite::flush_all(&mut w).expect("flushing");
+            let mut iter = BitIter::from(w.into_inner().into_iter());
+            assert_eq!(iter.next(), Some(true));
+            assert_eq!(iter.next(), Some(true));
+            assert_eq!(
+                decode_node(&mut iter),
+                Ok(*jet),
+                "jet {}: bad round-trip",
+                jet
+            );
+        }
+
+        // No encoding may be a prefix of (or equal to) any other
+        for (i, a) in encodings.iter().enumerate() {
+            for (j, b) in encodings.iter().enumerate() {
+                if i != j {
+                    assert!(
+                        !b.starts_with(a),
+                        "jet {} is a prefix of jet {}",
+                        Node::all()[i],
+                        Node::all()[j],
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn opaque() {
+        // `iden` followed by a jet with an unassigned extended code
+        let mut w = BitWriter::new(vec![]);
+        encode::encode_natural(2, &mut w).unwrap();
+        w.write_u8(8, 5).unwrap();
+        w.write_u8(30, 5).unwrap();
+        encode::encode_natural(1000, &mut w).unwrap();
+        w.write_bit(false).unwrap();
+        w.flush_all().unwrap();
+        let bytes = w.into_inner();
+
+        assert_eq!(
+            Program::<DummyNode>::decode(&mut BitIter::from(bytes.iter().cloned())).err(),
+            Some(Error::ParseErrorAt(8, "unknown extended jet")),
+        );
+        let no_cmrs = BTreeMap::new();
+        let prog = Program::<DummyNode>::decode_with_opaque_jets(
+            &mut BitIter::from(bytes.iter().cloned()),
+            &no_cmrs,
+        )
+        .expect("decoding program");
+        assert_eq!(prog.root_node().node, ::Node::Jet(Node::Opaque(1000, None)));
+        assert_eq!(prog.root_node().cmr, Node::Opaque(1000, None).cmr());
+        assert_ne!(
+            Node::Opaque(1000, None).cmr(),
+            Node::Opaque(1001, None).cmr(),
+        );
+
+        // Its placeholder CMR is never reported as the program's CMR
+        assert_eq!(prog.cmr(), None);
+        assert_eq!(prog.stats().cmr, None);
+        assert!(!prog.is_cmr(&prog.root_node().cmr));
+        assert!(!prog.cmr_eq(&prog));
+        assert_eq!(prog.equivalent(&prog), ::program::Equivalence::Structural);
+        assert_eq!(Node::Opaque(1000, None).to_string(), "opaque(1000)");
+
+        // It round-trips, but execution fails before running any node
+        assert_eq!(prog.to_bytes(), bytes);
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&::Value::Unit);
+        let mut events = 0;
+        assert_eq!(
+            mac.exec_with_trace(&prog, &TxEnv, &mut |_| events += 1)
+                .err(),
+            Some(Error::OpaqueJet(1)),
+        );
+        assert_eq!(events, 0);
+
+        // Given the jet's CMR, the program's CMR can be checked
+        let jet_cmr = Cmr::new(b"Simplicity\x1fJet\x1ffuture");
+        let mut jet_cmrs = BTreeMap::new();
+        jet_cmrs.insert(1000, jet_cmr);
+        let prog = Program::<DummyNode>::decode_with_opaque_jets(
+            &mut BitIter::from(bytes.iter().cloned()),
+            &jet_cmrs,
+        )
+        .expect("decoding program");
+        assert_eq!(prog.cmr(), Some(jet_cmr));
+        assert_eq!(prog.to_bytes(), bytes);
+
+        // comp(witness, opaque(1000)) with a 32-bit witness, which opaque
+        // jets leave untyped, so it is kept as decoded
+        let bytes = [
+            0xaf, 0xee, 0x9f, 0x40, 0x23, 0xc4, 0x15, 0x55, 0x55, 0x55, 0x40,
+        ];
+        let prog = Program::<DummyNode>::decode_with_opaque_jets(
+            &mut BitIter::from(bytes.iter().cloned()),
+            &no_cmrs,
+        )
+        .expect("decoding program");
+        assert_eq!(prog.nodes[1].node, ::Node::Jet(Node::Opaque(1000, None)));
+        assert_eq!(prog.raw_witness_bits().len(), 32);
+        assert_eq!(prog.to_bytes(), &bytes[..]);
+    }
+
+    #[test]
+    fn all_jets() {
+        assert_eq!(Node::all().len(), 23);
+        for node in Node::all() {
+            assert_eq!(Node::from_name(node.name()), Some(*node));
+            assert_eq!(node.signature().0, &node.to_string()[..]);
+        }
+        assert_eq!(Node::from_name("adder64"), None);
+    }
+
+    #[test]
+    fn jet_vectors() {
+        let sha256_iv = "6a09e667bb67ae853c6ef372a54ff53a510e527f9b05688c1f83d9ab5be0cd19";
+        let empty_block = format!("80{}", "00".repeat(63));
+        let vectors = [
+            (Node::Adder32, "ffffffff 00000002".to_owned(), "b1 00000001"),
+            (
+                Node::FullAdder32,
+                "ffffffff 00000000 b1".to_owned(),
+                "b1 00000000",
+            ),
+            (
+                Node::Subtractor32,
+                "00000000 00000001".to_owned(),
+                "b1 ffffffff",
+            ),
+            (
+                Node::FullSubtractor32,
+                "00000005 00000003 b1".to_owned(),
+                "b0 00000001",
+            ),
+            (
+                Node::Multiplier32,
+                "ffffffff ffffffff".to_owned(),
+                "fffffffe00000001",
+            ),
+            (
+                Node::FullMultiplier32,
+                "ffffffff ffffffff ffffffff ffffffff".to_owned(),
+                "ffffffffffffffff",
+            ),
+            (
+                Node::Sha256HashBlock,
+                format!("{} {}", sha256_iv, empty_block),
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                Node::Sha256Pair,
+                "00".repeat(64),
+                "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b",
+            ),
+            (Node::Adder8, "ff 01".to_owned(), "b1 00"),
+            (Node::Adder16, "ffff 0001".to_owned(), "b1 0000"),
+            (Node::Subtractor8, "00 01".to_owned(), "b1 ff"),
+            (Node::Subtractor16, "0001 0002".to_owned(), "b1 ffff"),
+            (
+                Node::Sha256Tagged,
+                format!(
+                    "{} {}",
+                    // SHA256("BIP0340/aux")
+                    "f1ef4e5ec063cada6d94cafa9d987ea069265839ecc11f972d77a52ed8c1cc90",
+                    "00".repeat(32),
+                ),
+                "54f169cfc9e2e5727480441f90ba25c488f461c70b5ea5dcaaf7af69270aa514",
+            ),
+            (Node::Eq32Verify, "00000003 00000003".to_owned(), ""),
+            (Node::Lt32Verify, "00000002 00000003".to_owned(), ""),
+            (Node::Le32Verify, "00000003 00000003".to_owned(), ""),
+            (Node::MerkleVerify, merkle_input(MERKLE_ROOT), ""),
+            (
+                Node::Sha256Blocks,
+                format!(
+                    "{} b1 {}{}",
+                    sha256_iv,
+                    empty_block,
+                    format!(" b0 {}", "00".repeat(64)).repeat(SHA256_MAX_BLOCKS - 1),
+                ),
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (Node::Sha256Iv, String::new(), sha256_iv),
+            (Node::ByteSwap32, "01020304".to_owned(), "04030201"),
+            (
+                Node::ByteSwap64,
+                "0102030405060708".to_owned(),
+                "0807060504030201",
+            ),
+            (Node::Lt32, "00000002 00000003".to_owned(), "b1"),
+            (Node::Verify, "b1".to_owned(), ""),
+        ];
+
+        for node in Node::all() {
+            assert!(
+                vectors.iter().any(|v| v.0 == *node),
+                "jet {}: no test vector",
+                node,
+            );
+        }
+        for &(jet, ref input, expected) in &vectors {
+            run_jet_vector(jet, input, expected);
         }
     }
 }
@@ -23,8 +23,10 @@ pub mod dummy;
 #[cfg(feature = "elements")]
 pub mod elements;
 pub mod jets;
+#[cfg(all(feature = "elements", any(test, feature = "test-vectors")))]
+pub mod test_vector;
 
-use std::{fmt, io};
+use std::{cmp, fmt, io};
 
 use bititer::BitIter;
 use cmr::Cmr;
@@ -49,6 +51,8 @@ pub use self::dummy as bitcoin;
 /// |------|--------------|
 /// | `1`  | unit         |
 /// | `2`  | single bit   |
+/// | `c`  | 8-bit word   |
+/// | `s`  | 16-bit word  |
 /// | `i`  | 32-bit word  |
 /// | `l`  | 64-bit word  |
 /// | `h`  | 256-bit word |
@@ -68,6 +72,34 @@ impl Iterator for TypeName {
     }
 }
 
+impl TypeName {
+    /// Width, in bits, of the named type, as laid out in a Bit Machine frame
+    pub fn bit_width(mut self) -> usize {
+        fn width(name: &mut TypeName) -> usize {
+            match name.next() {
+                Some(b'1') => 0,
+                Some(b'2') => 1,
+                Some(b'c') => 8,
+                Some(b's') => 16,
+                Some(b'i') => 32,
+                Some(b'l') => 64,
+                Some(b'h') => 256,
+                Some(b'+') => {
+                    let left = width(name);
+                    1 + cmp::max(left, width(name))
+                }
+                Some(b'*') => {
+                    let left = width(name);
+                    left + width(name)
+                }
+                Some(x) => panic!("Do not understand byte {} in type name", x as char),
+                None => panic!("unexpected end of string parsing type"),
+            }
+        }
+        width(&mut self)
+    }
+}
+
 /// Trait representing an extension (Bitcoin or Elements) to Simplicity
 pub trait Node: Sized + fmt::Display {
     /// Transaction environment
@@ -76,12 +108,17 @@ pub trait Node: Sized + fmt::Display {
     /// Decode a node from a bit iterator
     fn decode<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<Self, Error>;
 
+    /// Decode a primitive added after the original set, given the natural
+    /// number which follows its `11111` prefix in the encoding
+    fn decode_extended(_code: usize) -> Result<Self, Error> {
+        Err(Error::ParseError("unknown extended primitive"))
+    }
+
     /// Encode a node into a bit writer
     fn encode<W: encode::BitWrite>(&self, w: &mut W) -> io::Result<usize>;
 
-    /// Execute the node in a Bit Machine; assuming the surrounding
-    /// program has typechecked, this cannot fail
-    fn exec(&self, &mut exec::BitMachine, txenv: &Self::TxEnv);
+    /// Execute the node in a Bit Machine
+    fn exec(&self, mac: &mut exec::BitMachine, txenv: &Self::TxEnv) -> Result<(), Error>;
 
     /// Return the CMR of the node
     fn cmr(&self) -> Cmr;
@@ -0,0 +1,526 @@
+// Rust Simplicity Library
+// Written in 2020 by
+//   Andrew Poelstra <apoelstra@blockstream.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Elements Test Vectors
+//!
+//! Programs together with the transaction context needed to run them, in
+//! a JSON format which can be shared between implementations. A vector is
+//! a single object:
+//!
+//! ```json
+//! {
+//!     "program": "<hex: program and witness encoding>",
+//!     "tx": "<hex: consensus-encoded transaction>",
+//!     "index": 0,
+//!     "utxos": [
+//!         {
+//!             "script_pubkey": "<hex>",
+//!             "asset": "<hex: consensus-encoded confidential asset>",
+//!             "value": "<hex: consensus-encoded confidential value>",
+//!             "pegin": "<hex: parent genesis hash, or null>",
+//!             "pegin_height": <parent chain height, or null; may be omitted>
+//!         }
+//!     ],
+//!     "expected_output": "<hex, as by BitMachine::output_hex, or null>"
+//! }
+//! ```
+//!
+//! There is one utxo per transaction input. An `expected_output` of `null`
+//! means the program is expected to fail. The program is run with its own
+//! CMR as the script CMR, which is what a Simplicity output commits to.
+//!
+//! This module is only built with the `test-vectors` feature. Its JSON
+//! parser handles just what this format needs.
+//!
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use elements;
+use std::fmt::Write;
+
+use exec::BitMachine;
+use extension::elements::{ElementsUtxo, Node, TxEnv};
+use {Error, Program, Value};
+
+/// A program, the transaction context to run it in, and its expected result
+pub struct TestVector {
+    /// The program to run, on unit input
+    pub program: Program<Node>,
+    /// The transaction being validated
+    pub tx: elements::Transaction,
+    /// Index of the input being validated
+    pub index: u32,
+    /// The outputs spent by each input of the transaction
+    pub utxos: Vec<ElementsUtxo>,
+    /// Hex of the program's output, or `None` if it should fail
+    pub expected_output: Option<String>,
+}
+
+impl TestVector {
+    /// Parse a test vector from JSON
+    pub fn from_json(s: &str) -> Result<TestVector, Error> {
+        let mut parser = json::Parser::new(s);
+        let obj = parser.parse()?;
+        parser.finish()?;
+
+        let program = obj.field("program")?.as_str()?.parse()?;
+        let tx = elements::encode::deserialize(&obj.field("tx")?.as_hex()?)
+            .map_err(|_| Error::ParseError("invalid test vector transaction"))?;
+        let index = obj.field("index")?.as_u32()?;
+        let utxos = obj
+            .field("utxos")?
+            .as_array()?
+            .iter()
+            .map(|utxo| {
+                let pegin = match *utxo.field("pegin")? {
+                    json::Json::Null => None,
+                    ref hash => Some(
+                        elements::bitcoin::BlockHash::from_hex(hash.as_str()?)
+                            .map_err(|_| Error::ParseError("invalid test vector hex"))?,
+                    ),
+                };
+                // Optional, since it was added to the format later
+                let pegin_height = match utxo.optional_field("pegin_height")? {
+                    None | Some(&json::Json::Null) => None,
+                    Some(height) => Some(height.as_u32()?),
+                };
+                Ok(ElementsUtxo {
+                    script_pubkey: utxo.field("script_pubkey")?.as_hex()?.into(),
+                    asset: elements::encode::deserialize(&utxo.field("asset")?.as_hex()?)
+                        .map_err(|_| Error::ParseError("invalid test vector asset"))?,
+                    value: elements::encode::deserialize(&utxo.field("value")?.as_hex()?)
+                        .map_err(|_| Error::ParseError("invalid test vector value"))?,
+                    pegin: pegin,
+                    pegin_height: pegin_height,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let expected_output = match *obj.field("expected_output")? {
+            json::Json::Null => None,
+            ref output => Some(output.as_str()?.to_owned()),
+        };
+
+        let vector = TestVector {
+            program: program,
+            tx: tx,
+            index: index,
+            utxos: utxos,
+            expected_output: expected_output,
+        };
+        // Check that the vector describes a valid environment
+        TxEnv::from_tx(vector.tx.clone(), vector.index, vector.utxos.clone())?;
+        Ok(vector)
+    }
+
+    /// Serialize the test vector as JSON
+    pub fn to_json(&self) -> String {
+        fn serialize_hex<T: elements::encode::Encodable>(data: &T) -> String {
+            elements::encode::serialize(data).to_hex()
+        }
+
+        let mut ret = String::new();
+        ret.push_str("{\n");
+        writeln!(ret, "    \"program\": \"{}\",", self.program).unwrap();
+        writeln!(ret, "    \"tx\": \"{}\",", serialize_hex(&self.tx)).unwrap();
+        writeln!(ret, "    \"index\": {},", self.index).unwrap();
+        ret.push_str("    \"utxos\": [");
+        for (n, utxo) in self.utxos.iter().enumerate() {
+            ret.push_str(if n == 0 { "\n" } else { ",\n" });
+            ret.push_str("        {\n");
+            let script_pubkey = utxo.script_pubkey[..].to_hex();
+            writeln!(ret, "            \"script_pubkey\": \"{}\",", script_pubkey).unwrap();
+            writeln!(
+                ret,
+                "            \"asset\": \"{}\",",
+                serialize_hex(&utxo.asset)
+            )
+            .unwrap();
+            writeln!(
+                ret,
+                "            \"value\": \"{}\",",
+                serialize_hex(&utxo.value)
+            )
+            .unwrap();
+            match utxo.pegin {
+                Some(hash) => writeln!(ret, "            \"pegin\": \"{}\",", hash).unwrap(),
+                None => ret.push_str("            \"pegin\": null,\n"),
+            }
+            match utxo.pegin_height {
+                Some(height) => writeln!(ret, "            \"pegin_height\": {}", height).unwrap(),
+                None => ret.push_str("            \"pegin_height\": null\n"),
+            }
+            ret.push_str("        }");
+        }
+        ret.push_str("\n    ],\n");
+        match self.expected_output {
+            Some(ref output) => writeln!(ret, "    \"expected_output\": \"{}\"", output).unwrap(),
+            None => ret.push_str("    \"expected_output\": null\n"),
+        }
+        ret.push('}');
+        ret
+    }
+
+    /// Execute the program, returning the hex of its output
+    pub fn run(&self) -> Result<String, Error> {
+        let mut txenv = TxEnv::from_tx(self.tx.clone(), self.index, self.utxos.clone())?;
+        if let Some(cmr) = self.program.cmr() {
+            txenv = txenv.with_script_cmr(cmr);
+        }
+        let mut mac = BitMachine::with_input(&self.program, &Value::Unit)?;
+        mac.exec(&self.program, &txenv)?;
+        Ok(mac.output_hex())
+    }
+
+    /// Whether running the program gives the expected result
+    pub fn passes(&self) -> bool {
+        match (self.run(), self.expected_output.as_ref()) {
+            (Ok(output), Some(expected)) => output == *expected,
+            (Err(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Just enough JSON to read test vectors: no booleans, floats, negative
+/// numbers or string escapes, which are rejected
+mod json {
+    use std::iter::Peekable;
+    use std::str::Chars;
+
+    use bitcoin_hashes::hex::FromHex;
+
+    use Error;
+
+    /// Maximum nesting of arrays and objects, which bounds the recursion
+    /// of the parser
+    const MAX_DEPTH: usize = 32;
+
+    pub enum Json {
+        Null,
+        Number(u64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        pub fn field(&self, name: &str) -> Result<&Json, Error> {
+            self.optional_field(name)?
+                .ok_or(Error::ParseError("missing test vector field"))
+        }
+
+        pub fn optional_field(&self, name: &str) -> Result<Option<&Json>, Error> {
+            match *self {
+                Json::Object(ref fields) => Ok(fields
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value)),
+                _ => Err(Error::ParseError("expected JSON object")),
+            }
+        }
+
+        pub fn as_str(&self) -> Result<&str, Error> {
+            match *self {
+                Json::String(ref s) => Ok(s),
+                _ => Err(Error::ParseError("expected JSON string")),
+            }
+        }
+
+        pub fn as_hex(&self) -> Result<Vec<u8>, Error> {
+            Vec::from_hex(self.as_str()?).map_err(|_| Error::ParseError("invalid test vector hex"))
+        }
+
+        pub fn as_u32(&self) -> Result<u32, Error> {
+            match *self {
+                Json::Number(n) if n <= 0xffff_ffff => Ok(n as u32),
+                _ => Err(Error::ParseError("expected 32-bit JSON number")),
+            }
+        }
+
+        pub fn as_array(&self) -> Result<&[Json], Error> {
+            match *self {
+                Json::Array(ref elems) => Ok(elems),
+                _ => Err(Error::ParseError("expected JSON array")),
+            }
+        }
+    }
+
+    pub struct Parser<'a> {
+        chars: Peekable<Chars<'a>>,
+        /// Number of arrays and objects currently open
+        depth: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        pub fn new(s: &'a str) -> Parser<'a> {
+            Parser {
+                chars: s.chars().peekable(),
+                depth: 0,
+            }
+        }
+
+        /// Check that only whitespace remains
+        pub fn finish(&mut self) -> Result<(), Error> {
+            match self.peek() {
+                None => Ok(()),
+                Some(_) => Err(Error::ParseError("trailing data after JSON")),
+            }
+        }
+
+        /// Skip whitespace and return the next character, if any
+        fn peek(&mut self) -> Option<char> {
+            while let Some(&c) = self.chars.peek() {
+                if !c.is_whitespace() {
+                    return Some(c);
+                }
+                self.chars.next();
+            }
+            None
+        }
+
+        fn expect(&mut self, c: char) -> Result<(), Error> {
+            match self.peek() {
+                Some(next) if next == c => {
+                    self.chars.next();
+                    Ok(())
+                }
+                Some(_) => Err(Error::ParseError("malformed JSON")),
+                None => Err(Error::EndOfStream),
+            }
+        }
+
+        fn keyword(&mut self, word: &str, value: Json) -> Result<Json, Error> {
+            for c in word.chars() {
+                if self.chars.next() != Some(c) {
+                    return Err(Error::ParseError("malformed JSON"));
+                }
+            }
+            Ok(value)
+        }
+
+        fn string(&mut self) -> Result<String, Error> {
+            self.expect('"')?;
+            let mut ret = String::new();
+            loop {
+                match self.chars.next() {
+                    Some('"') => return Ok(ret),
+                    Some('\\') => return Err(Error::ParseError("unsupported JSON escape")),
+                    Some(c) => ret.push(c),
+                    None => return Err(Error::EndOfStream),
+                }
+            }
+        }
+
+        fn number(&mut self) -> Result<Json, Error> {
+            let mut ret = 0u64;
+            while let Some(digit) = self.chars.peek().and_then(|c| c.to_digit(10)) {
+                self.chars.next();
+                ret = ret
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(u64::from(digit)))
+                    .ok_or(Error::NaturalOverflow)?;
+            }
+            match self.chars.peek() {
+                Some('.') | Some('e') | Some('E') => {
+                    Err(Error::ParseError("unsupported JSON float"))
+                }
+                _ => Ok(Json::Number(ret)),
+            }
+        }
+
+        /// Parse a comma-separated list of items up to the `close` character
+        fn list<T, F>(&mut self, close: char, mut item: F) -> Result<Vec<T>, Error>
+        where
+            F: FnMut(&mut Parser<'a>) -> Result<T, Error>,
+        {
+            if self.depth == MAX_DEPTH {
+                return Err(Error::ParseError("JSON nested too deeply"));
+            }
+            self.depth += 1;
+            let mut ret = vec![];
+            if self.peek() == Some(close) {
+                self.chars.next();
+                self.depth -= 1;
+                return Ok(ret);
+            }
+            loop {
+                ret.push(item(self)?);
+                match self.peek() {
+                    Some(',') => {
+                        self.chars.next();
+                    }
+                    Some(c) if c == close => {
+                        self.chars.next();
+                        self.depth -= 1;
+                        return Ok(ret);
+                    }
+                    Some(_) => return Err(Error::ParseError("malformed JSON")),
+                    None => return Err(Error::EndOfStream),
+                }
+            }
+        }
+
+        pub fn parse(&mut self) -> Result<Json, Error> {
+            match self.peek() {
+                None => Err(Error::EndOfStream),
+                Some('n') => self.keyword("null", Json::Null),
+                Some('"') => Ok(Json::String(self.string()?)),
+                Some('0'..='9') => self.number(),
+                Some('-') => Err(Error::ParseError("unsupported JSON negative number")),
+                Some('t') | Some('f') => Err(Error::ParseError("unsupported JSON boolean")),
+                Some('[') => {
+                    self.chars.next();
+                    Ok(Json::Array(self.list(']', |p| p.parse())?))
+                }
+                Some('{') => {
+                    self.chars.next();
+                    Ok(Json::Object(self.list('}', |p| {
+                        let key = p.string()?;
+                        p.expect(':')?;
+                        Ok((key, p.parse()?))
+                    })?))
+                }
+                Some(_) => Err(Error::ParseError("malformed JSON")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin_hashes::{sha256d, Hash};
+    use elements::{confidential, OutPoint, TxIn, TxInWitness, TxOut};
+    use test_progs::single_node_prog;
+
+    #[test]
+    fn round_trip() {
+        // Outputs the transaction version
+        let program = single_node_prog(Node::Version);
+
+        let input = TxIn {
+            previous_output: OutPoint::default(),
+            is_pegin: false,
+            has_issuance: false,
+            script_sig: Default::default(),
+            sequence: 0xffff_ffff,
+            asset_issuance: Default::default(),
+            witness: TxInWitness::default(),
+        };
+        let asset = confidential::Asset::Explicit(sha256d::Hash::from_inner([7; 32]));
+        let value = confidential::Value::Explicit(1000);
+        let tx = elements::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input.clone(), input],
+            output: vec![TxOut {
+                asset: asset,
+                value: value,
+                nonce: confidential::Nonce::Null,
+                script_pubkey: vec![0x51].into(),
+                witness: Default::default(),
+            }],
+        };
+        let pegin = ElementsUtxo::new_pegin(
+            vec![0x51].into(),
+            asset,
+            value,
+            elements::bitcoin::BlockHash::from_inner([9; 32]),
+            Some(700_000),
+        );
+
+        let vector = TestVector {
+            program: program,
+            tx: tx,
+            index: 1,
+            utxos: vec![ElementsUtxo::new(vec![].into(), asset, value), pegin],
+            expected_output: Some("00000002".to_owned()),
+        };
+        assert!(vector.passes());
+
+        let json = vector.to_json();
+        let parsed = TestVector::from_json(&json).expect("parsing test vector");
+        assert_eq!(parsed.program.to_bytes(), vector.program.to_bytes());
+        assert_eq!(parsed.tx, vector.tx);
+        assert_eq!(parsed.index, 1);
+        assert_eq!(parsed.utxos, vector.utxos);
+        assert_eq!(parsed.to_json(), json);
+        assert!(parsed.passes());
+
+        // A vector expecting failure does not pass
+        let failing = json.replace("\"00000002\"", "null");
+        assert!(!TestVector::from_json(&failing).unwrap().passes());
+
+        let bad_index = json.replace("\"index\": 1", "\"index\": 2");
+        assert_eq!(
+            TestVector::from_json(&bad_index).err(),
+            Some(Error::BadInputIndex(2, 2)),
+        );
+        // Vectors from before `pegin_height` was added still parse
+        let old = json.replace(",\n            \"pegin_height\": null", "");
+        assert_ne!(old, json);
+        let parsed = TestVector::from_json(&old).expect("parsing test vector");
+        assert_eq!(parsed.utxos[0].pegin_height, None);
+
+        assert_eq!(
+            TestVector::from_json(&json[..json.len() - 1]).err(),
+            Some(Error::EndOfStream),
+        );
+
+        // Programs see their own CMR as the script CMR
+        let program = single_node_prog(Node::ScriptCmr);
+        let cmr = program.cmr().expect("program has a CMR");
+        let vector = TestVector {
+            program: program,
+            expected_output: Some(cmr.to_string()),
+            ..parsed
+        };
+        assert!(vector.passes());
+        assert!(TestVector::from_json(&vector.to_json()).unwrap().passes());
+    }
+
+    #[test]
+    fn unsupported_json() {
+        let parse = |s: &str| {
+            let mut parser = json::Parser::new(s);
+            parser.parse().and_then(|_| parser.finish())
+        };
+        assert_eq!(parse("[[1], {\"a\": null}]"), Ok(()));
+        assert_eq!(
+            parse("true"),
+            Err(Error::ParseError("unsupported JSON boolean"))
+        );
+        assert_eq!(
+            parse("[-1]"),
+            Err(Error::ParseError("unsupported JSON negative number"))
+        );
+        assert_eq!(
+            parse("1.5"),
+            Err(Error::ParseError("unsupported JSON float"))
+        );
+        assert_eq!(
+            parse("\"a\\nb\""),
+            Err(Error::ParseError("unsupported JSON escape"))
+        );
+
+        // Deep nesting fails rather than overflowing the stack
+        let deep = "[".repeat(100_000) + &"]".repeat(100_000);
+        assert_eq!(
+            parse(&deep),
+            Err(Error::ParseError("JSON nested too deeply"))
+        );
+        let shallow = "[".repeat(32) + &"]".repeat(32);
+        assert_eq!(parse(&shallow), Ok(()));
+    }
+}
@@ -16,6 +16,10 @@
 
 extern crate bitcoin_hashes;
 extern crate byteorder;
+#[cfg(feature = "elements")]
+extern crate elements;
+#[cfg(all(test, feature = "proptests"))]
+extern crate quickcheck;
 
 pub mod bititer;
 pub mod cmr;
@@ -25,20 +29,27 @@ pub mod extension;
 pub mod program;
 pub mod types;
 
-use std::fmt;
+#[cfg(test)]
+mod test_progs;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::{fmt, io};
 
 pub use program::Program;
 
 /// De/serialization error
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Error {
-    /// Unable to unify types in a DAG
-    TypeCheck,
+    /// Unable to unify types in a DAG; contains the index of the node
+    /// whose constraints failed, and the kinds of the two conflicting types
+    TypeCheck(usize, &'static str, &'static str),
     /// A recursive type was inferred, violating the "occurs check" of the
-    /// type inference engine
-    OccursCheck,
-    /// Node made a back-reference past the beginning of the program
-    BadIndex,
+    /// type inference engine; contains the index of the offending node
+    OccursCheck(usize),
+    /// Node made a back-reference past the beginning of the program;
+    /// contains the index of the node
+    BadIndex(usize),
     /// Number exceeded 32 bits
     NaturalOverflow,
     /// Non-'case' nodes may not have hidden children
@@ -51,8 +62,172 @@ pub enum Error {
     TooManyNodes(usize),
     /// Unrecognized node
     ParseError(&'static str),
+    /// Unrecognized node in a program; contains the bit position in the
+    /// encoding at which the node starts
+    ParseErrorAt(usize, &'static str),
+    /// An arithmetic jet overflowed (or, for subtraction, underflowed)
+    /// while the Bit Machine was in checked-arithmetic mode
+    ArithmeticOverflow,
+    /// A confidential asset, value or nonce which a primitive needed to
+    /// output was null
+    NullConfidentialField,
+    /// Input given to a program did not match the width of its source
+    /// type (expected width, actual width)
+    BadInputWidth(usize, usize),
+    /// An assertion jet failed; contains the index of its node in the
+    /// program and the name of the jet
+    AssertionFailed(usize, &'static str),
+    /// A confidential asset, value or nonce had a commitment prefix byte
+    /// not allowed for its type
+    BadConfidentialPrefix(u8),
+    /// A confidential asset, value or nonce had a payload of the wrong
+    /// length (expected length in bytes, actual length)
+    BadConfidentialLength(usize, usize),
+    /// A `fail` node was executed; contains the index of the node
+    ExecutionFailed(usize),
+    /// An opaque jet, which this library does not recognize, was executed;
+    /// contains the index of its node
+    OpaqueJet(usize),
+    /// Input given to a program did not match its source type; contains
+    /// the kinds of the first mismatched parts of the type and the value
+    InputTypeMismatch(&'static str, &'static str),
+    /// A transaction environment was given an input index past the end of
+    /// the transaction's inputs (index, number of inputs)
+    BadInputIndex(u32, usize),
+    /// Execution did not finish before its deadline
+    Timeout,
+    /// A transaction had too many inputs or outputs to count in 32 bits
+    CountOverflow(usize),
+    /// A program had more nodes than the caller's limit (number of nodes,
+    /// limit)
+    ProgramTooLarge(usize, usize),
+    /// A program was not a correct pruning of another; contains the index
+    /// of a node of the pruned program which does not match the original
+    BadPruning(usize),
+    /// A jet or primitive tried to read past the end of its input frame
+    /// (number of bits to read, number of bits left)
+    FrameUnderflow(usize, usize),
+    /// The scriptpubkey of the utxo spent by an input is not the Simplicity
+    /// output for the program's CMR; contains the index of the input
+    ScriptCmrMismatch(u32),
+    /// A transaction environment was given a different number of spent
+    /// outputs than the transaction has inputs (number of inputs, number
+    /// of spent outputs)
+    UtxoCountMismatch(usize, usize),
+    /// A sum of transaction amounts did not fit in 64 bits
+    AmountOverflow,
+    /// Reading a program failed with an I/O error of the given kind
+    Io(io::ErrorKind),
+    /// A program was built from no nodes
+    EmptyProgram,
+    /// A primitive which this library cannot yet execute was executed;
+    /// contains the name of the primitive
+    UnimplementedPrimitive(&'static str),
+    /// The `scriptcmr` primitive was executed in a transaction environment
+    /// which was not given the program's CMR
+    NoScriptCmr,
+    /// Pruning a program would change the type of a node which is kept,
+    /// since its type was only fixed by a pruned branch; contains the index
+    /// of the node in the original program
+    PruningChangesType(usize),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TypeCheck(idx, a, b) => write!(
+                f,
+                "type check failed at node {}: cannot unify {} with {}",
+                idx, a, b
+            ),
+            Error::OccursCheck(idx) => write!(f, "node {} has an infinitely sized type", idx),
+            Error::BadIndex(idx) => write!(
+                f,
+                "node {} refers to a node before the start of the program",
+                idx
+            ),
+            Error::NaturalOverflow => f.write_str("encoded number exceeded 32 bits"),
+            Error::NonCaseHiddenChild => f.write_str("non-case node has a hidden child"),
+            Error::CaseMultipleHiddenChildren => f.write_str("case node has two hidden children"),
+            Error::EndOfStream => f.write_str("bitstream ended early"),
+            Error::TooManyNodes(n) => write!(f, "program has too many nodes ({})", n),
+            Error::ParseError(s) => write!(f, "unrecognized node: {}", s),
+            Error::ParseErrorAt(pos, s) => write!(f, "unrecognized node at bit {}: {}", pos, s),
+            Error::ArithmeticOverflow => f.write_str("arithmetic jet overflowed"),
+            Error::NullConfidentialField => f.write_str("confidential field was null"),
+            Error::BadInputWidth(expected, actual) => write!(
+                f,
+                "program input has {} bits, but its source type has {}",
+                actual, expected
+            ),
+            Error::AssertionFailed(idx, jet) => {
+                write!(f, "assertion failed in jet {} at node {}", jet, idx)
+            }
+            Error::BadConfidentialPrefix(prefix) => {
+                write!(f, "bad confidential commitment prefix 0x{:02x}", prefix)
+            }
+            Error::BadConfidentialLength(expected, actual) => write!(
+                f,
+                "confidential payload has {} bytes, expected {}",
+                actual, expected
+            ),
+            Error::ExecutionFailed(idx) => write!(f, "executed fail node {}", idx),
+            Error::OpaqueJet(idx) => write!(f, "executed unknown jet at node {}", idx),
+            Error::BadInputIndex(ix, n) => write!(
+                f,
+                "input index {} out of range for transaction with {} inputs",
+                ix, n
+            ),
+            Error::InputTypeMismatch(expected, found) => write!(
+                f,
+                "program input does not match its source type: expected {}, found {}",
+                expected, found
+            ),
+            Error::Timeout => f.write_str("execution exceeded its deadline"),
+            Error::CountOverflow(n) => write!(
+                f,
+                "transaction has {} inputs or outputs, more than fit in 32 bits",
+                n
+            ),
+            Error::ProgramTooLarge(n, max) => {
+                write!(f, "program has {} nodes, more than the limit of {}", n, max)
+            }
+            Error::BadPruning(idx) => write!(
+                f,
+                "pruned program does not match the original at node {}",
+                idx
+            ),
+            Error::FrameUnderflow(n, left) => write!(
+                f,
+                "tried to read {} bits from a frame with {} bits left",
+                n, left
+            ),
+            Error::ScriptCmrMismatch(ix) => write!(
+                f,
+                "script CMR does not match the utxo spent by input {}",
+                ix
+            ),
+            Error::UtxoCountMismatch(inputs, utxos) => write!(
+                f,
+                "transaction has {} inputs, but {} spent outputs were given",
+                inputs, utxos
+            ),
+            Error::AmountOverflow => f.write_str("sum of transaction amounts overflowed"),
+            Error::Io(kind) => write!(f, "I/O error: {:?}", kind),
+            Error::EmptyProgram => f.write_str("program has no nodes"),
+            Error::UnimplementedPrimitive(name) => {
+                write!(f, "primitive {} is not implemented", name)
+            }
+            Error::NoScriptCmr => f.write_str("transaction environment has no script CMR"),
+            Error::PruningChangesType(idx) => {
+                write!(f, "pruning would change the type of node {}", idx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 /// Simplicity expression node, including Bitcoin/Elements extensions
 ///
 /// If Bitcoin/Elements support is not compiled (see `bitcoin` and
@@ -83,6 +258,30 @@ pub enum Node<Witness, Extension> {
     Jet(extension::jets::Node),
 }
 
+impl<Witness, Extension> Node<Witness, Extension> {
+    /// Name of the combinator, e.g. `"comp"`; extension nodes and jets
+    /// are named `"ext"` and `"jet"` respectively
+    pub fn combinator_name(&self) -> &'static str {
+        match *self {
+            Node::Iden => "iden",
+            Node::Unit => "unit",
+            Node::InjL(..) => "injl",
+            Node::InjR(..) => "injr",
+            Node::Take(..) => "take",
+            Node::Drop(..) => "drop",
+            Node::Comp(..) => "comp",
+            Node::Case(..) => "case",
+            Node::Pair(..) => "pair",
+            Node::Disconnect(..) => "disconnect",
+            Node::Witness(..) => "witness",
+            Node::Fail(..) => "fail",
+            Node::Hidden(..) => "hidden",
+            Node::Ext(..) => "ext",
+            Node::Jet(..) => "jet",
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum Value {
     Unit,
@@ -103,6 +302,11 @@ impl Value {
         }
     }
 
+    /// The unit value
+    pub fn unit() -> Value {
+        Value::Unit
+    }
+
     /// Encode a single bit as a value. Will panic if the input is out of range
     pub fn u1(n: u8) -> Value {
         match n {
@@ -126,8 +330,8 @@ impl Value {
     pub fn u4(n: u8) -> Value {
         let w0 = (n & 12) / 4;
         let w1 = n & 3;
-        if n > 7 {
-            panic!("{} out of range for Value::u2", n);
+        if n > 15 {
+            panic!("{} out of range for Value::u4", n);
         }
         Value::Prod(Box::new(Value::u2(w0)), Box::new(Value::u2(w1)))
     }
@@ -160,6 +364,12 @@ impl Value {
         Value::Prod(Box::new(Value::u32(w0)), Box::new(Value::u32(w1)))
     }
 
+    /// Encode 32 bytes, e.g. a hash, as a 256-bit word
+    pub fn u256(bytes: &[u8; 32]) -> Value {
+        let word = |i: usize| Value::u64(BigEndian::read_u64(&bytes[8 * i..8 * i + 8]));
+        Value::prod(Value::prod(word(0), word(1)), Value::prod(word(2), word(3)))
+    }
+
     /// Convenience constructor for a left sum of a value
     pub fn sum_l(a: Value) -> Value {
         Value::SumL(Box::new(a))
@@ -174,31 +384,89 @@ impl Value {
     pub fn prod(a: Value, b: Value) -> Value {
         Value::Prod(Box::new(a), Box::new(b))
     }
-}
 
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    /// The value as a number, if it is a 32-bit word as built by
+    /// `Value::u32`
+    pub fn as_u32(&self) -> Option<u32> {
+        self.word_bits(32)
+            .map(|bits| bits.iter().fold(0, |acc, &b| 2 * acc + b as u32))
+    }
+
+    /// The value as a number, if it is a 64-bit word as built by
+    /// `Value::u64`
+    pub fn as_u64(&self) -> Option<u64> {
+        self.word_bits(64)
+            .map(|bits| bits.iter().fold(0, |acc, &b| 2 * acc + b as u64))
+    }
+
+    /// The value as bytes, if it is a 256-bit word (such as a hash output by
+    /// a program) as built by `Value::u256`
+    pub fn as_bytes32(&self) -> Option<[u8; 32]> {
+        self.word_bits(256).map(|bits| {
+            let mut ret = [0; 32];
+            for (byte, chunk) in ret.iter_mut().zip(bits.chunks(8)) {
+                *byte = chunk.iter().fold(0, |acc, &b| 2 * acc + b as u8);
+            }
+            ret
+        })
+    }
+
+    /// The bits of the value, if it is a word of exactly `width` bits
+    fn word_bits(&self, width: usize) -> Option<Vec<bool>> {
+        let mut bits = Vec::with_capacity(width);
+        match self.word_width(&mut bits) {
+            Some(w) if w == width => Some(bits),
+            _ => None,
+        }
+    }
+
+    /// If the value is a word of `2^n` bits, as built by `Value::u8` and
+    /// friends, append its bits to `bits` and return its width
+    fn word_width(&self, bits: &mut Vec<bool>) -> Option<usize> {
         match *self {
-            Value::Unit => f.write_str("ε"),
-            Value::SumL(ref sub) => {
-                f.write_str("0")?;
-                if **sub != Value::Unit {
-                    write!(f, "{}", sub)
-                } else {
-                    Ok(())
-                }
+            Value::SumL(ref sub) if **sub == Value::Unit => {
+                bits.push(false);
+                Some(1)
             }
-            Value::SumR(ref sub) => {
-                f.write_str("1")?;
-                if **sub != Value::Unit {
-                    write!(f, "{}", sub)
+            Value::SumR(ref sub) if **sub == Value::Unit => {
+                bits.push(true);
+                Some(1)
+            }
+            Value::Prod(ref l, ref r) => {
+                let l_width = l.word_width(bits)?;
+                let r_width = r.word_width(bits)?;
+                if l_width == r_width {
+                    Some(l_width + r_width)
                 } else {
-                    Ok(())
+                    None
                 }
             }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Renders sums as `L x`/`R x`, products as `(x, y)` and the unit as
+    /// `()`. Words of eight bits or more are written in hex.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Unit => f.write_str("()"),
+            Value::SumL(ref sub) => write!(f, "L {}", sub),
+            Value::SumR(ref sub) => write!(f, "R {}", sub),
             Value::Prod(ref l, ref r) => {
-                write!(f, "({},", l)?;
-                write!(f, "{})", r)
+                let mut bits = vec![];
+                match self.word_width(&mut bits) {
+                    Some(width) if width >= 8 => {
+                        f.write_str("0x")?;
+                        for nibble in bits.chunks(4) {
+                            let n = nibble.iter().fold(0, |acc, &b| 2 * acc + b as u32);
+                            write!(f, "{:x}", n)?;
+                        }
+                        Ok(())
+                    }
+                    _ => write!(f, "({}, {})", l, r),
+                }
             }
         }
     }
@@ -212,14 +480,357 @@ impl Value {
         match ty.ty {
             types::FinalTypeInner::Unit => Ok(Value::Unit),
             types::FinalTypeInner::Sum(ref l, ref r) => match bits.next() {
-                Some(false) => Ok(Value::SumL(Box::new(Value::from_witness(bits, &*l)?))),
-                Some(true) => Ok(Value::SumR(Box::new(Value::from_witness(bits, &*r)?))),
+                Some(false) => Ok(Value::SumL(Box::new(Value::from_witness(bits, l)?))),
+                Some(true) => Ok(Value::SumR(Box::new(Value::from_witness(bits, r)?))),
                 None => Err(Error::EndOfStream),
             },
             types::FinalTypeInner::Product(ref l, ref r) => Ok(Value::Prod(
-                Box::new(Value::from_witness(&mut *bits, &*l)?),
-                Box::new(Value::from_witness(bits, &*r)?),
+                Box::new(Value::from_witness(&mut *bits, l)?),
+                Box::new(Value::from_witness(bits, r)?),
+            )),
+        }
+    }
+
+    /// Nodes of a program which outputs this value, whatever its input;
+    /// the last node is the root. Equal subvalues share nodes, so e.g.
+    /// `Value::u32(0)` takes only 7 nodes.
+    ///
+    /// This is the `scribe` construction from the Simplicity tech report:
+    /// `unit` for the unit value, `injl`/`injr` for sums and `pair` for
+    /// products.
+    pub fn scribe<Witness, Ext>(&self) -> Vec<Node<Witness, Ext>> {
+        let mut nodes = vec![];
+        self.scribe_into(&mut nodes, &mut HashMap::new());
+        nodes
+    }
+
+    /// Append the nodes of `self.scribe()` to a program under
+    /// construction, returning the index of the root
+    pub fn scribe_onto<Witness, Ext>(&self, nodes: &mut Vec<Node<Witness, Ext>>) -> usize {
+        self.scribe_into(nodes, &mut HashMap::new())
+    }
+
+    fn scribe_into<Witness, Ext>(
+        &self,
+        nodes: &mut Vec<Node<Witness, Ext>>,
+        memo: &mut HashMap<Value, usize>,
+    ) -> usize {
+        if let Some(&idx) = memo.get(self) {
+            return idx;
+        }
+        let node = match *self {
+            Value::Unit => Node::Unit,
+            Value::SumL(ref a) => Node::InjL(a.scribe_into(nodes, memo)),
+            Value::SumR(ref a) => Node::InjR(a.scribe_into(nodes, memo)),
+            Value::Prod(ref a, ref b) => {
+                let i = a.scribe_into(nodes, memo);
+                let j = b.scribe_into(nodes, memo);
+                Node::Pair(i, j)
+            }
+        };
+        nodes.push(node);
+        memo.insert(self.clone(), nodes.len() - 1);
+        nodes.len() - 1
+    }
+
+    /// Encode the value as witness data, i.e. without the padding that
+    /// follows the tag of a sum in a Bit Machine frame
+    pub fn to_witness_bits(&self) -> Vec<bool> {
+        let mut ret = Vec::with_capacity(self.len());
+        self.append_witness_bits(&mut ret);
+        ret
+    }
+
+    fn append_witness_bits(&self, bits: &mut Vec<bool>) {
+        match *self {
+            Value::Unit => {}
+            Value::SumL(ref a) => {
+                bits.push(false);
+                a.append_witness_bits(bits);
+            }
+            Value::SumR(ref a) => {
+                bits.push(true);
+                a.append_witness_bits(bits);
+            }
+            Value::Prod(ref a, ref b) => {
+                a.append_witness_bits(bits);
+                b.append_witness_bits(bits);
+            }
+        }
+    }
+
+    /// Decode a value of the given type from the contents of a Bit Machine
+    /// frame. Unlike witness data, frames contain padding after the tag of
+    /// a sum, up to the width of its wider branch; this is skipped.
+    pub fn from_frame<Bits: Iterator<Item = bool>>(
+        bits: &mut Bits,
+        ty: &types::FinalType,
+    ) -> Result<Value, Error> {
+        match ty.ty {
+            types::FinalTypeInner::Unit => Ok(Value::Unit),
+            types::FinalTypeInner::Sum(ref l, ref r) => {
+                let (right, sub) = match bits.next() {
+                    Some(false) => (false, l),
+                    Some(true) => (true, r),
+                    None => return Err(Error::EndOfStream),
+                };
+                for _ in sub.bit_width()..ty.bit_width() - 1 {
+                    if bits.next().is_none() {
+                        return Err(Error::EndOfStream);
+                    }
+                }
+                let val = Box::new(Value::from_frame(bits, sub)?);
+                if right {
+                    Ok(Value::SumR(val))
+                } else {
+                    Ok(Value::SumL(val))
+                }
+            }
+            types::FinalTypeInner::Product(ref l, ref r) => Ok(Value::Prod(
+                Box::new(Value::from_frame(&mut *bits, l)?),
+                Box::new(Value::from_frame(bits, r)?),
             )),
         }
     }
+
+    /// Encode the value as the contents of a Bit Machine frame of the given
+    /// type, i.e. the inverse of `from_frame`. Padding bits are set to 0.
+    ///
+    /// Will panic if the value does not have the given type.
+    pub fn to_bits(&self, ty: &types::FinalType) -> Vec<bool> {
+        let mut ret = Vec::with_capacity(ty.bit_width());
+        self.append_bits(ty, &mut ret);
+        ret
+    }
+
+    /// Check that the value has the given type
+    pub fn check_type(&self, ty: &types::FinalType) -> Result<(), Error> {
+        match (self, &ty.ty) {
+            (Value::Unit, types::FinalTypeInner::Unit) => Ok(()),
+            (Value::SumL(val), types::FinalTypeInner::Sum(l, _)) => val.check_type(l),
+            (Value::SumR(val), types::FinalTypeInner::Sum(_, r)) => val.check_type(r),
+            (Value::Prod(a, b), types::FinalTypeInner::Product(l, r)) => {
+                a.check_type(l)?;
+                b.check_type(r)
+            }
+            _ => {
+                let expected = match ty.ty {
+                    types::FinalTypeInner::Unit => "unit",
+                    types::FinalTypeInner::Sum(..) => "sum",
+                    types::FinalTypeInner::Product(..) => "product",
+                };
+                let found = match *self {
+                    Value::Unit => "unit",
+                    Value::SumL(..) | Value::SumR(..) => "sum",
+                    Value::Prod(..) => "product",
+                };
+                Err(Error::InputTypeMismatch(expected, found))
+            }
+        }
+    }
+
+    fn append_bits(&self, ty: &types::FinalType, bits: &mut Vec<bool>) {
+        match (self, &ty.ty) {
+            (Value::Unit, types::FinalTypeInner::Unit) => {}
+            (Value::SumL(val), types::FinalTypeInner::Sum(l, _)) => {
+                bits.push(false);
+                bits.extend((l.bit_width()..ty.bit_width() - 1).map(|_| false));
+                val.append_bits(l, bits);
+            }
+            (Value::SumR(val), types::FinalTypeInner::Sum(_, r)) => {
+                bits.push(true);
+                bits.extend((r.bit_width()..ty.bit_width() - 1).map(|_| false));
+                val.append_bits(r, bits);
+            }
+            (Value::Prod(a, b), types::FinalTypeInner::Product(l, r)) => {
+                a.append_bits(l, bits);
+                b.append_bits(r, bits);
+            }
+            _ => panic!("value {} does not have type {}", self, ty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use types::{FinalType, FinalTypeInner};
+
+    fn unit() -> Arc<FinalType> {
+        Arc::new(FinalType {
+            ty: FinalTypeInner::Unit,
+            bit_width: 0,
+        })
+    }
+
+    fn sum(a: Arc<FinalType>, b: Arc<FinalType>) -> Arc<FinalType> {
+        Arc::new(FinalType {
+            bit_width: 1 + std::cmp::max(a.bit_width, b.bit_width),
+            ty: FinalTypeInner::Sum(a, b),
+        })
+    }
+
+    fn prod(a: Arc<FinalType>, b: Arc<FinalType>) -> Arc<FinalType> {
+        Arc::new(FinalType {
+            bit_width: a.bit_width + b.bit_width,
+            ty: FinalTypeInner::Product(a, b),
+        })
+    }
+
+    /// The type 2^(2^n)
+    fn word(n: usize) -> Arc<FinalType> {
+        let mut ret = sum(unit(), unit());
+        for _ in 0..n {
+            ret = prod(ret.clone(), ret);
+        }
+        ret
+    }
+
+    #[test]
+    fn small_words() {
+        // Every 4-bit number is in range, and bytes are built from them
+        assert_eq!(Value::u4(15), Value::prod(Value::u2(3), Value::u2(3)));
+        assert_eq!(Value::u8(0xaf), Value::prod(Value::u4(10), Value::u4(15)));
+    }
+
+    #[test]
+    fn frame_bits_round_trip() {
+        let ty = prod(sum(unit(), unit()), word(5));
+        let val = Value::prod(Value::sum_l(Value::Unit), Value::u32(42));
+
+        let bits = val.to_bits(&ty);
+        assert_eq!(bits.len(), 33);
+        assert!(!bits[0]);
+        assert_eq!(bits[1..].iter().filter(|b| **b).count(), 3);
+        assert_eq!(
+            Value::from_frame(&mut bits.into_iter(), &ty).expect("decoding frame"),
+            val,
+        );
+    }
+
+    #[test]
+    fn frame_bits_padding() {
+        // 1 + 2^32: the left branch is padded out to 32 bits
+        let ty = sum(unit(), word(5));
+        let left = Value::sum_l(Value::Unit);
+        let right = Value::sum_r(Value::u32(0xffff_ffff));
+
+        let bits = left.to_bits(&ty);
+        assert_eq!(bits, vec![false; 33]);
+        assert_eq!(Value::from_frame(&mut bits.into_iter(), &ty), Ok(left));
+
+        let bits = right.to_bits(&ty);
+        assert_eq!(bits, vec![true; 33]);
+        assert_eq!(Value::from_frame(&mut bits.into_iter(), &ty), Ok(right));
+
+        // Both branches of 2 are the same type
+        let bit = sum(unit(), unit());
+        let one = Value::u1(1);
+        assert_eq!(one.to_bits(&bit), vec![true]);
+        assert_eq!(
+            Value::from_frame(&mut vec![true].into_iter(), &bit),
+            Ok(one)
+        );
+    }
+
+    #[test]
+    fn value_display() {
+        assert_eq!(Value::unit().to_string(), "()");
+        assert_eq!(Value::u1(1).to_string(), "R ()");
+        assert_eq!(Value::u4(5).to_string(), "((L (), R ()), (L (), R ()))");
+        assert_eq!(Value::u8(0x2a).to_string(), "0x2a");
+        assert_eq!(Value::u64(1).to_string(), "0x0000000000000001");
+
+        let value = Value::prod(Value::sum_l(Value::unit()), Value::u8(0x2a));
+        assert_eq!(value.to_string(), "(L (), 0x2a)");
+        let value = Value::sum_r(Value::prod(Value::u32(0xdeadbeef), Value::u16(0x0102)));
+        assert_eq!(value.to_string(), "R (0xdeadbeef, 0x0102)");
+    }
+
+    #[test]
+    fn error_display() {
+        use extension::dummy::Node as DummyNode;
+
+        // `unit` has target type 1, which cannot be the source of `take`
+        let prog: Vec<Node<(), DummyNode>> = vec![Node::Unit, Node::Take(0), Node::Comp(0, 1)];
+        let err = types::type_check(prog).unwrap_err();
+        assert_eq!(err, Error::TypeCheck(2, "unit", "product"));
+        assert_eq!(
+            err.to_string(),
+            "type check failed at node 2: cannot unify unit with product",
+        );
+
+        assert_eq!(
+            Error::BadIndex(3).to_string(),
+            "node 3 refers to a node before the start of the program",
+        );
+        assert_eq!(
+            Error::AssertionFailed(5, "eq32verify").to_string(),
+            "assertion failed in jet eq32verify at node 5",
+        );
+        assert_eq!(
+            Error::BadInputWidth(64, 32).to_string(),
+            "program input has 32 bits, but its source type has 64",
+        );
+        assert_eq!(
+            Error::InputTypeMismatch("product", "unit").to_string(),
+            "program input does not match its source type: expected product, found unit",
+        );
+        assert_eq!(
+            Error::Timeout.to_string(),
+            "execution exceeded its deadline"
+        );
+        assert_eq!(
+            Error::ParseErrorAt(8, "unknown extended jet").to_string(),
+            "unrecognized node at bit 8: unknown extended jet",
+        );
+
+        // Usable as a boxed error with `?`
+        fn decode() -> Result<(), Box<dyn std::error::Error>> {
+            encode::decode_natural(vec![true, true].into_iter())?;
+            Ok(())
+        }
+        assert_eq!(decode().unwrap_err().to_string(), "bitstream ended early");
+    }
+
+    #[test]
+    fn word_conversions() {
+        assert_eq!(Value::u32(0xdead_beef).as_u32(), Some(0xdead_beef));
+        assert_eq!(
+            Value::u64(0x0123_4567_89ab_cdef).as_u64(),
+            Some(0x0123_4567_89ab_cdef)
+        );
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(Value::u256(&bytes).as_bytes32(), Some(bytes));
+
+        // Words of the wrong width, and values which are not words
+        assert_eq!(Value::u64(1).as_u32(), None);
+        assert_eq!(Value::u32(1).as_u64(), None);
+        assert_eq!(Value::u64(1).as_bytes32(), None);
+        assert_eq!(Value::prod(Value::u16(1), Value::u8(1)).as_u32(), None);
+        assert_eq!(Value::sum_l(Value::u32(1)).as_u32(), None);
+        assert_eq!(Value::unit().as_u32(), None);
+    }
+
+    #[test]
+    fn scribe() {
+        use extension::dummy::{Node as DummyNode, TxEnv};
+        use test_progs::decode_prog;
+
+        assert_eq!(Value::u32(0).scribe::<(), DummyNode>().len(), 7);
+
+        let nodes = Value::u32(42).scribe::<(), DummyNode>();
+        let prog: program::Program<DummyNode> = decode_prog(&nodes);
+        assert_eq!(prog.root_node().target_ty.to_string(), "2^32");
+
+        let mut mac = exec::BitMachine::for_program(&prog);
+        mac.input(&Value::Unit);
+        assert_eq!(mac.exec(&prog, &TxEnv), Ok(Value::u32(42)));
+        assert_eq!(mac.output_hex(), "0000002a");
+    }
 }
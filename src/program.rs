@@ -19,11 +19,18 @@
 //! data.
 //!
 
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::slice;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::{cmp, fmt};
+use std::{cmp, fmt, io, iter};
 
 use bititer::BitIter;
 use cmr::{self, Cmr};
+use exec::{BitMachine, TraceKind};
+use extension::jets::Node as JetNode;
 use {encode, extension, types};
 use {Error, Node, Value};
 
@@ -76,6 +83,113 @@ impl<Ext: fmt::Display> fmt::Display for ProgramNode<Ext> {
 pub struct Program<Ext> {
     /// The list of nodes in the program
     pub nodes: Vec<ProgramNode<Ext>>,
+    /// For a program with opaque jets, its witness section as it was
+    /// decoded, since the types inferred for its `witness` nodes may be
+    /// too small to hold all of it
+    opaque_witness: Option<Vec<bool>>,
+}
+
+/// Summary of a program, as returned by `Program::stats`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProgramStats {
+    /// Number of nodes in the program
+    pub node_count: usize,
+    /// CMR of the program, or `None` if it uses opaque jets
+    pub cmr: Option<Cmr>,
+    /// Source type of the program
+    pub source_ty: Arc<types::FinalType>,
+    /// Target type of the program
+    pub target_ty: Arc<types::FinalType>,
+    /// Upper bound on the number of cells required in the Bit Machine
+    /// to execute the program
+    pub extra_cells_bound: usize,
+}
+
+/// Witness data of a program, as returned by `Program::decode_with_witness`
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Witness {
+    /// Values of the program's `witness` nodes, keyed by node index
+    pub values: BTreeMap<usize, Value>,
+}
+
+impl Witness {
+    /// Value of the `witness` node with the given index, if any
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(&index)
+    }
+}
+
+/// How closely two programs match, as returned by `Program::equivalent`
+///
+/// The Simplicity specification also defines equivalence by IMR, which
+/// would fall between `Structural` and `Cmr`; this library does not yet
+/// compute IMRs, so there is no such level.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Equivalence {
+    /// The programs consist of the same nodes, with the same witnesses
+    Structural,
+    /// The programs have the same CMR, but differ in their witness data,
+    /// hidden branches or in how their nodes are shared
+    Cmr,
+    /// The programs have different CMRs, or either uses opaque jets, so
+    /// its CMR is unknown
+    Different,
+}
+
+impl<Ext: extension::Node> fmt::Debug for Program<Ext> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.stats();
+        write!(f, "Program {{ nodes: {}, cmr: ", stats.node_count)?;
+        match stats.cmr {
+            Some(cmr) => write!(f, "{}", cmr)?,
+            None => f.write_str("unknown")?,
+        }
+        write!(
+            f,
+            ", type: {} → {}, extra_cells_bound: {} }}",
+            stats.source_ty, stats.target_ty, stats.extra_cells_bound,
+        )
+    }
+}
+
+/// Canonical encoding of the program, including witness data, as hex
+impl<Ext: extension::Node> fmt::Display for Program<Ext> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.to_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a program from hex, rejecting any encoding which is not exactly
+/// what `Display` would output for the resulting program
+impl<Ext: extension::Node> FromStr for Program<Ext> {
+    type Err = Error;
+
+    #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` is too new for our MSRV
+    fn from_str(s: &str) -> Result<Program<Ext>, Error> {
+        if s.len() % 2 != 0 {
+            return Err(Error::ParseError("odd-length hex"));
+        }
+        // `from_str_radix` would also accept a sign
+        if !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::ParseError("invalid hex"));
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or(Error::ParseError("invalid hex"))
+            })
+            .collect::<Result<Vec<u8>, Error>>()?;
+        let program = Program::decode(&mut BitIter::from(bytes.iter().cloned()))?;
+        if program.to_bytes() != bytes {
+            return Err(Error::ParseError("non-canonical program encoding"));
+        }
+        Ok(program)
+    }
 }
 
 impl<Ext: extension::Node> Program<Ext> {
@@ -84,22 +198,194 @@ impl<Ext: extension::Node> Program<Ext> {
         &self.nodes[self.nodes.len() - 1]
     }
 
+    /// Iterator over the nodes of the program, in order, so that every node
+    /// comes after its children
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, ProgramNode<Ext>> {
+        self.nodes.iter()
+    }
+
+    /// The inferred source and target types of the node at `index`, or
+    /// `None` if there is no such node
+    pub fn node_type(
+        &self,
+        index: usize,
+    ) -> Option<(Arc<types::FinalType>, Arc<types::FinalType>)> {
+        self.nodes
+            .get(index)
+            .map(|node| (node.source_ty.clone(), node.target_ty.clone()))
+    }
+
+    /// The CMR of the program, i.e. of its root node
+    ///
+    /// This is `None` if the program uses opaque jets whose CMRs were not
+    /// supplied, so that the placeholder CMR of its root node cannot be
+    /// mistaken for its real CMR.
+    #[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+    pub fn cmr(&self) -> Option<Cmr> {
+        let has_unknown_jets = self.reachable_nodes().any(|node| match node.node {
+            Node::Jet(JetNode::Opaque(_, None)) => true,
+            _ => false,
+        });
+        if has_unknown_jets {
+            None
+        } else {
+            Some(self.root_node().cmr)
+        }
+    }
+
+    /// Number of nodes in the program, including unreachable ones
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Length, in edges, of the longest path from the root of the program
+    /// to a leaf (a node without children). Both children of a `disconnect`
+    /// node count.
+    pub fn max_depth(&self) -> usize {
+        // Children always precede their parents, so one forward pass
+        // suffices
+        let mut depth = Vec::<usize>::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let node_depth = match node.node {
+                Node::InjL(i) | Node::InjR(i) | Node::Take(i) | Node::Drop(i) => 1 + depth[i],
+                Node::Comp(i, j) | Node::Case(i, j) | Node::Pair(i, j) | Node::Disconnect(i, j) => {
+                    1 + cmp::max(depth[i], depth[j])
+                }
+                _ => 0,
+            };
+            depth.push(node_depth);
+        }
+        depth[self.nodes.len() - 1]
+    }
+
+    /// Summary of the program: its size, CMR, type and cell bound
+    pub fn stats(&self) -> ProgramStats {
+        let root = self.root_node();
+        ProgramStats {
+            node_count: self.node_count(),
+            cmr: self.cmr(),
+            source_ty: root.source_ty.clone(),
+            target_ty: root.target_ty.clone(),
+            extra_cells_bound: root.extra_cells_bound,
+        }
+    }
+
+    /// Whether the program has the given CMR; false if it uses opaque
+    /// jets
+    pub fn is_cmr(&self, cmr: &Cmr) -> bool {
+        self.cmr() == Some(*cmr)
+    }
+
+    /// Whether two programs have the same CMR, i.e. are the same program
+    /// up to witness data and pruning of unexecuted branches; false if
+    /// either uses opaque jets
+    pub fn cmr_eq(&self, other: &Program<Ext>) -> bool {
+        match (self.cmr(), other.cmr()) {
+            (Some(cmr), Some(other_cmr)) => cmr == other_cmr,
+            _ => false,
+        }
+    }
+
+    /// Determine how closely two programs match
+    pub fn equivalent(&self, other: &Program<Ext>) -> Equivalence {
+        if self.structural_hash() == other.structural_hash() {
+            Equivalence::Structural
+        } else if self.cmr_eq(other) {
+            Equivalence::Cmr
+        } else {
+            Equivalence::Different
+        }
+    }
+
     /// Decode a program from a stream of bits
     pub fn decode<I: Iterator<Item = u8>>(iter: &mut BitIter<I>) -> Result<Program<Ext>, Error> {
+        Program::decode_inner(iter, None, None)
+    }
+
+    /// Decode a program from a stream of bits, as `decode`, but failing
+    /// with `Error::ProgramTooLarge` if it has more than `max_nodes` nodes
+    ///
+    /// The limit is checked against the node count at the start of the
+    /// encoding, before any nodes are allocated, so this should be used to
+    /// decode untrusted programs.
+    pub fn decode_with_limit<I: Iterator<Item = u8>>(
+        iter: &mut BitIter<I>,
+        max_nodes: usize,
+    ) -> Result<Program<Ext>, Error> {
+        Program::decode_inner(iter, None, Some(max_nodes))
+    }
+
+    /// Decode a program from a stream of bits, decoding any jets which
+    /// this library does not recognize as `jets::Node::Opaque`
+    ///
+    /// This allows programs using jets added by a later soft fork to be
+    /// parsed and re-encoded unchanged. `jet_cmrs` gives the CMRs of such
+    /// jets by their extended codes, which are stored in their nodes so
+    /// that the program's CMR can be checked; if any used jet is missing,
+    /// it gets a placeholder CMR and `cmr` returns `None`.
+    ///
+    /// Since the types of opaque jets are unknown, they are left untyped,
+    /// so other nodes may be mistyped. Such a program fails to execute,
+    /// and its witness section is kept as it was decoded, to be re-encoded
+    /// as it is, though the values of its `witness` nodes may be cut short.
+    pub fn decode_with_opaque_jets<I: Iterator<Item = u8>>(
+        iter: &mut BitIter<I>,
+        jet_cmrs: &BTreeMap<usize, Cmr>,
+    ) -> Result<Program<Ext>, Error> {
+        Program::decode_inner(iter, Some(jet_cmrs), None)
+    }
+
+    /// Decode a program, decoding unknown jets as opaque ones with the
+    /// CMRs in `opaque_jets` if it is given
+    fn decode_inner<I: Iterator<Item = u8>>(
+        iter: &mut BitIter<I>,
+        opaque_jets: Option<&BTreeMap<usize, Cmr>>,
+        max_nodes: Option<usize>,
+    ) -> Result<Program<Ext>, Error> {
         // Decode a bunch of untyped, witness-less nodes
-        let nodes = encode::decode_program_no_witness(&mut *iter)?;
+        let mut nodes =
+            encode::decode_program_no_witness_with(&mut *iter, opaque_jets.is_some(), max_nodes)?;
+        let mut has_opaque_jets = false;
+        for node in &mut nodes {
+            if let Node::Jet(JetNode::Opaque(code, ref mut cmr)) = *node {
+                *cmr = opaque_jets.and_then(|jet_cmrs| jet_cmrs.get(&code).cloned());
+                has_opaque_jets = true;
+            }
+        }
 
         // Do type-checking
         let typed_nodes = types::type_check(nodes)?;
 
         // Parse witnesses, if available
-        // FIXME actually only read as much as wit_len
         let wit_len = match iter.next() {
             Some(false) => 0,
             Some(true) => encode::decode_natural(&mut *iter)?,
             None => return Err(Error::EndOfStream),
         };
 
+        if has_opaque_jets {
+            let witness: Vec<bool> = iter.by_ref().take(wit_len).collect();
+            if witness.len() != wit_len {
+                return Err(Error::EndOfStream);
+            }
+            let mut program = Program::attach_witness(typed_nodes, &mut witness.iter().cloned())?;
+            program.opaque_witness = Some(witness);
+            return Ok(program);
+        }
+        let mut wit_iter = iter.by_ref().take(wit_len);
+        let program = Program::attach_witness(typed_nodes, &mut wit_iter)?;
+        if wit_iter.next().is_some() {
+            return Err(Error::ParseError("trailing data in witness section"));
+        }
+        Ok(program)
+    }
+
+    /// Fill in the values of type-checked `witness` nodes, reading them in
+    /// order from a witness section without its length prefix
+    fn attach_witness<W: Iterator<Item = bool>>(
+        typed_nodes: Vec<types::TypedNode<(), Ext>>,
+        wit_iter: &mut W,
+    ) -> Result<Program<Ext>, Error> {
         let typed_nodes = typed_nodes
             .into_iter()
             .map(|node| {
@@ -116,10 +402,9 @@ impl<Ext: extension::Node> Program<Ext> {
                         Node::Case(i, j) => Node::Case(i, j),
                         Node::Pair(i, j) => Node::Pair(i, j),
                         Node::Disconnect(i, j) => Node::Disconnect(i, j),
-                        Node::Witness(()) => Node::Witness(Value::from_witness(
-                            &mut iter.by_ref().take(wit_len),
-                            &node.target_ty,
-                        )?),
+                        Node::Witness(()) => {
+                            Node::Witness(Value::from_witness(&mut *wit_iter, &node.target_ty)?)
+                        }
                         Node::Fail(x, y) => Node::Fail(x, y),
                         Node::Hidden(x) => Node::Hidden(x),
                         Node::Ext(e) => Node::Ext(e),
@@ -131,7 +416,44 @@ impl<Ext: extension::Node> Program<Ext> {
             })
             .collect::<Result<Vec<_>, _>>()?;
 
-        // Compute cached data and return
+        Ok(Program::from_typed_nodes(typed_nodes))
+    }
+
+    /// Build a program directly from its nodes, e.g. as output by
+    /// `Value::scribe`, inferring types and computing CMRs as `decode` does
+    ///
+    /// Children are referred to by absolute index and must precede their
+    /// parents; the last node is the root. `witness` nodes carry their
+    /// values, which must match the inferred types. Fails with
+    /// `Error::EmptyProgram` if `nodes` is empty.
+    pub fn from_nodes(nodes: Vec<Node<Value, Ext>>) -> Result<Program<Ext>, Error> {
+        if nodes.is_empty() {
+            return Err(Error::EmptyProgram);
+        }
+        for (idx, node) in nodes.iter().enumerate() {
+            let max_child = match *node {
+                Node::InjL(i) | Node::InjR(i) | Node::Take(i) | Node::Drop(i) => i,
+                Node::Comp(i, j) | Node::Case(i, j) | Node::Pair(i, j) | Node::Disconnect(i, j) => {
+                    cmp::max(i, j)
+                }
+                _ => continue,
+            };
+            if max_child >= idx {
+                return Err(Error::BadIndex(idx));
+            }
+        }
+
+        let typed_nodes = types::type_check(nodes)?;
+        for node in &typed_nodes {
+            if let Node::Witness(ref value) = node.node {
+                value.check_type(&node.target_ty)?;
+            }
+        }
+        Ok(Program::from_typed_nodes(typed_nodes))
+    }
+
+    /// Compute cached data for type-checked nodes
+    fn from_typed_nodes(typed_nodes: Vec<types::TypedNode<Value, Ext>>) -> Program<Ext> {
         let mut ret = Vec::<ProgramNode<Ext>>::with_capacity(typed_nodes.len());
         for (index, node) in typed_nodes.into_iter().enumerate() {
             let final_node = ProgramNode {
@@ -150,7 +472,580 @@ impl<Ext: extension::Node> Program<Ext> {
             ret.push(final_node);
         }
 
-        Ok(Program { nodes: ret })
+        Program {
+            nodes: ret,
+            opaque_witness: None,
+        }
+    }
+
+    /// Decode a program from a stream of bits, returning its witness data
+    /// separately as well as attached to the program's `witness` nodes
+    ///
+    /// The program section is followed by a witness section, consisting
+    /// of a length prefix and then the values of each `witness` node in
+    /// order, which are parsed according to their inferred types.
+    pub fn decode_with_witness<I: Iterator<Item = u8>>(
+        iter: &mut BitIter<I>,
+    ) -> Result<(Program<Ext>, Witness), Error> {
+        let program = Program::decode(iter)?;
+        let witness = program.witness();
+        Ok((program, witness))
+    }
+
+    /// Decode a program from bytes which may or may not include its
+    /// witness section, returning the witness data only if it is present
+    ///
+    /// The witness section is taken to be absent if nothing but the zero
+    /// padding of the final byte follows the program. Since a `Program`
+    /// needs values for its `witness` nodes, this then fails with
+    /// `Error::ParseError` unless the program has no witness data at all.
+    /// Such a program, whose empty witness section fits in the padding, is
+    /// returned with no witness, since the two encodings are identical.
+    /// Unlike `decode`, this also fails with `Error::ParseError` if
+    /// anything other than padding follows the witness section.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Program<Ext>, Option<Witness>), Error> {
+        // Whether the bits of `bytes` from `pos` onward are only padding
+        let is_padding = |pos: usize| {
+            let left = 8 * bytes.len() - pos;
+            left == 0 || (left < 8 && bytes[bytes.len() - 1] & ((1 << left) - 1) == 0)
+        };
+
+        let mut bits = BitIter::from(bytes.iter().cloned());
+        let nodes = encode::decode_program_no_witness_with(&mut bits, false, None)?;
+        let typed_nodes = types::type_check(nodes)?;
+
+        if is_padding(bits.n_total_read()) {
+            let program = match Program::attach_witness(typed_nodes, &mut iter::empty()) {
+                Err(Error::EndOfStream) => Err(Error::ParseError("missing witness section")),
+                res => res,
+            }?;
+            return Ok((program, None));
+        }
+
+        let wit_len = match bits.next() {
+            Some(false) => 0,
+            Some(true) => encode::decode_natural(&mut bits)?,
+            None => return Err(Error::EndOfStream),
+        };
+        let mut wit_iter = bits.by_ref().take(wit_len);
+        let program = Program::attach_witness(typed_nodes, &mut wit_iter)?;
+        if wit_iter.next().is_some() || !is_padding(bits.n_total_read()) {
+            return Err(Error::ParseError("trailing data after witness"));
+        }
+        let witness = program.witness();
+        Ok((program, Some(witness)))
+    }
+
+    /// Values of the program's `witness` nodes, keyed by node index
+    fn witness(&self) -> Witness {
+        let values = self
+            .nodes
+            .iter()
+            .filter_map(|node| match node.node {
+                Node::Witness(ref value) => Some((node.index, value.clone())),
+                _ => None,
+            })
+            .collect();
+        Witness { values: values }
+    }
+
+    /// Encode the program, followed by its witness data, into a bitstream.
+    /// Returns the number of bits written.
+    ///
+    /// In debug builds, this checks that the returned length is exact and
+    /// that the encoding decodes to a program with the same CMR, failing
+    /// with an `InvalidData` error if not.
+    pub fn encode<W: encode::BitWrite>(&self, w: &mut W) -> io::Result<usize> {
+        let start = w.n_written();
+        let len = self.encode_inner(&mut *w)?;
+        debug_assert_eq!(w.n_written() - start, len);
+        if cfg!(debug_assertions) {
+            self.check_encoding(len)?;
+        }
+        Ok(len)
+    }
+
+    /// Re-encode the program and check that it decodes to the same CMR
+    /// using exactly `len` bits
+    fn check_encoding(&self, len: usize) -> io::Result<()> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut bits = vec![];
+        self.encode_inner(&mut bits)?;
+        if bits.len() != len {
+            return Err(bad("encoder returned the wrong length"));
+        }
+
+        let mut w = encode::BitWriter::new(vec![]);
+        for bit in bits {
+            encode::BitWrite::write_bit(&mut w, bit)?;
+        }
+        encode::BitWrite::flush_all(&mut w)?;
+        let mut iter = BitIter::with_bit_len(w.into_inner().into_iter(), len);
+        let jet_cmrs = self
+            .nodes
+            .iter()
+            .filter_map(|node| match node.node {
+                Node::Jet(JetNode::Opaque(code, Some(cmr))) => Some((code, cmr)),
+                _ => None,
+            })
+            .collect();
+        let decoded = Program::<Ext>::decode_inner(&mut iter, Some(&jet_cmrs), None)
+            .map_err(|_| bad("encoding does not decode"))?;
+        if iter.n_total_read() != len {
+            return Err(bad("encoding has trailing bits"));
+        }
+        if decoded.cmr() != self.cmr() {
+            return Err(bad("encoding changed the CMR"));
+        }
+        Ok(())
+    }
+
+    fn encode_inner<W: encode::BitWrite>(&self, w: &mut W) -> io::Result<usize> {
+        let mut len = encode::encode_natural(self.nodes.len(), &mut *w)?;
+        for node in &self.nodes {
+            len += encode::encode_node_no_witness(&node.node, node.index, &mut *w)?;
+        }
+
+        len += Program::<Ext>::encode_witness(&self.raw_witness_bits(), w)?;
+        Ok(len)
+    }
+
+    /// Encode a witness section, or the single bit marking its absence if
+    /// `witness` is empty
+    fn encode_witness<W: encode::BitWrite>(witness: &[bool], w: &mut W) -> io::Result<usize> {
+        if witness.is_empty() {
+            w.write_bit(false)?;
+            Ok(1)
+        } else {
+            w.write_bit(true)?;
+            let len = encode::encode_natural(witness.len(), &mut *w)?;
+            for &bit in witness {
+                w.write_bit(bit)?;
+            }
+            Ok(1 + len + witness.len())
+        }
+    }
+
+    /// The witness section of the program, without its length prefix: the
+    /// values of all `witness` nodes, in node order, concatenated
+    ///
+    /// This does not depend on the types of the witness values, so can be
+    /// used to compare or re-serialize witnesses on their own. For a
+    /// program with opaque jets, it is the witness section as decoded.
+    pub fn raw_witness_bits(&self) -> Vec<bool> {
+        if let Some(ref witness) = self.opaque_witness {
+            return witness.clone();
+        }
+        Program::witness_bits(self.nodes.iter().map(|node| &node.node))
+    }
+
+    /// The values of the `witness` nodes among `nodes`, in order,
+    /// concatenated
+    fn witness_bits<'a, I>(nodes: I) -> Vec<bool>
+    where
+        I: Iterator<Item = &'a Node<Value, Ext>>,
+        Ext: 'a,
+    {
+        let mut witness = vec![];
+        for node in nodes {
+            if let Node::Witness(ref value) = *node {
+                witness.extend(value.to_witness_bits());
+            }
+        }
+        witness
+    }
+
+    /// Encode the program, followed by its witness data, as bytes; the
+    /// final byte is padded with zeroes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = encode::BitWriter::new(vec![]);
+        self.encode(&mut w).expect("encoding to a Vec");
+        encode::BitWrite::flush_all(&mut w).expect("flushing");
+        w.into_inner()
+    }
+
+    /// Decode a program from a byte-oriented reader, pulling bytes from it
+    /// only as they are needed by the decoder
+    ///
+    /// An I/O error from the reader stops decoding, and is returned as
+    /// `Error::Io` in place of whatever error the decoder then reports.
+    pub fn decode_from_reader<R: io::Read>(r: R) -> Result<Program<Ext>, Error> {
+        let mut io_error = None;
+        let result = {
+            let bytes = io::Read::bytes(io::BufReader::new(r)).scan((), |_, byte| match byte {
+                Ok(byte) => Some(byte),
+                Err(e) => {
+                    io_error = Some(e.kind());
+                    None
+                }
+            });
+            Program::decode(&mut BitIter::from(bytes))
+        };
+        match io_error {
+            Some(kind) => Err(Error::Io(kind)),
+            None => result,
+        }
+    }
+
+    /// Prune the branches of `case` nodes which are not taken when the
+    /// program is run on `input`, replacing each with a `hidden` node
+    /// carrying the branch's CMR, i.e. turning the `case` into an `assertl`
+    /// or `assertr`
+    ///
+    /// The pruned program has the same CMR, and will execute identically
+    /// on `input`, but may fail on other inputs. Its types are inferred
+    /// from the remaining nodes, as a decoder of the pruned program would.
+    /// If that changes the type of any node which is kept, e.g. because a
+    /// `witness` node's type was only fixed by a pruned branch, this fails
+    /// with `Error::PruningChangesType`.
+    pub fn prune(&self, input: &Value, txenv: &Ext::TxEnv) -> Result<Program<Ext>, Error> {
+        // Each entry records the index of the original node it comes from
+        enum Pruned {
+            Kept(usize),
+            Case(usize, usize, usize),
+            Hidden(usize),
+        }
+
+        // A `case` node is always followed immediately by the branch it takes
+        let mut taken = vec![(false, false); self.nodes.len()];
+        let mut last_case: Option<(usize, usize, usize)> = None;
+        let mut mac = BitMachine::for_program(self);
+        mac.input(input);
+        mac.exec_with_trace(self, txenv, &mut |event| {
+            if event.kind != TraceKind::Enter {
+                return;
+            }
+            if let Some((idx, s, t)) = last_case.take() {
+                taken[idx].0 |= event.index == s;
+                taken[idx].1 |= event.index == t;
+            }
+            if let Node::Case(s, t) = self.nodes[event.index].node {
+                last_case = Some((event.index, s, t));
+            }
+        })?;
+
+        // Keep everything reachable from the root through taken branches
+        let mut keep = vec![false; self.nodes.len()];
+        if let Some(last) = keep.last_mut() {
+            *last = true;
+        }
+        for node in self.nodes.iter().rev() {
+            if !keep[node.index] {
+                continue;
+            }
+            match node.node {
+                Node::InjL(i) | Node::InjR(i) | Node::Take(i) | Node::Drop(i) => keep[i] = true,
+                Node::Case(i, j) => {
+                    keep[i] |= taken[node.index].0;
+                    keep[j] |= taken[node.index].1;
+                }
+                Node::Comp(i, j) | Node::Pair(i, j) | Node::Disconnect(i, j) => {
+                    keep[i] = true;
+                    keep[j] = true;
+                }
+                _ => {}
+            }
+        }
+
+        // Lay out the kept nodes, adding a hidden node for each pruned branch
+        let mut new_index = vec![0; self.nodes.len()];
+        let mut hidden_index = BTreeMap::new();
+        let mut pruned = vec![];
+        for node in self.nodes.iter().filter(|node| keep[node.index]) {
+            if let Node::Case(s, t) = node.node {
+                let mut child = |i: usize| {
+                    if keep[i] {
+                        return new_index[i];
+                    }
+                    *hidden_index.entry(i).or_insert_with(|| {
+                        pruned.push(Pruned::Hidden(i));
+                        pruned.len() - 1
+                    })
+                };
+                let (s, t) = (child(s), child(t));
+                pruned.push(Pruned::Case(node.index, s, t));
+            } else {
+                pruned.push(Pruned::Kept(node.index));
+            }
+            new_index[node.index] = pruned.len() - 1;
+        }
+
+        let mut w = encode::BitWriter::new(vec![]);
+        encode::encode_natural(pruned.len(), &mut w).expect("encoding to a Vec");
+        for (idx, entry) in pruned.iter().enumerate() {
+            let node = match *entry {
+                Pruned::Hidden(old) => Node::Hidden(self.nodes[old].cmr),
+                Pruned::Case(_, s, t) => Node::Case(s, t),
+                Pruned::Kept(old) => match self.nodes[old].node {
+                    Node::InjL(i) => Node::InjL(new_index[i]),
+                    Node::InjR(i) => Node::InjR(new_index[i]),
+                    Node::Take(i) => Node::Take(new_index[i]),
+                    Node::Drop(i) => Node::Drop(new_index[i]),
+                    Node::Comp(i, j) => Node::Comp(new_index[i], new_index[j]),
+                    Node::Pair(i, j) => Node::Pair(new_index[i], new_index[j]),
+                    Node::Disconnect(i, j) => Node::Disconnect(new_index[i], new_index[j]),
+                    ref node => {
+                        encode::encode_node_no_witness(node, idx, &mut w)
+                            .expect("encoding to a Vec");
+                        continue;
+                    }
+                },
+            };
+            encode::encode_node_no_witness::<(), _, Ext>(&node, idx, &mut w)
+                .expect("encoding to a Vec");
+        }
+        encode::BitWrite::flush_all(&mut w).expect("flushing");
+
+        // Extension nodes cannot be cloned, so are recovered by decoding.
+        // Without the pruned branches, inference may generalize types and
+        // so change how frames are laid out and witnesses read; refuse to
+        // prune rather than produce a program which behaves differently.
+        let mut iter = BitIter::from(w.into_inner().into_iter());
+        let nodes = encode::decode_program_no_witness_with(&mut iter, true, None)?;
+        let typed_nodes = types::type_check(nodes)?;
+        for (node, entry) in typed_nodes.iter().zip(&pruned) {
+            let old = match *entry {
+                Pruned::Kept(old) | Pruned::Case(old, _, _) => &self.nodes[old],
+                Pruned::Hidden(..) => continue,
+            };
+            if node.source_ty != old.source_ty || node.target_ty != old.target_ty {
+                return Err(Error::PruningChangesType(old.index));
+            }
+        }
+        let witness = Program::witness_bits(pruned.iter().filter_map(|entry| match *entry {
+            Pruned::Kept(old) => Some(&self.nodes[old].node),
+            _ => None,
+        }));
+        let program = Program::attach_witness(typed_nodes, &mut witness.into_iter())?;
+        debug_assert_eq!(program.cmr(), self.cmr());
+        Ok(program)
+    }
+
+    /// Check that the program is a correct pruning of `original`, such as
+    /// `prune` produces: that it has the same structure, witness values and
+    /// types, except that branches of `case` nodes may be replaced by
+    /// `hidden` nodes, each of which must carry the CMR of the branch it
+    /// replaces
+    ///
+    /// This implies that the two programs have the same CMR, and execute
+    /// identically on inputs which take no pruned branch. On failure,
+    /// returns `Error::BadPruning` with the index of a node of this program
+    /// which does not match the corresponding node of `original`.
+    #[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+    pub fn verify_pruning(&self, original: &Program<Ext>) -> Result<(), Error> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![(self.root_node().index, original.root_node().index)];
+        while let Some((idx, orig_idx)) = stack.pop() {
+            if !visited.insert((idx, orig_idx)) {
+                continue;
+            }
+            let (node, orig) = (&self.nodes[idx], &original.nodes[orig_idx]);
+            if node.source_ty != orig.source_ty || node.target_ty != orig.target_ty {
+                return Err(Error::BadPruning(idx));
+            }
+            match (&node.node, &orig.node) {
+                // Only a branch of a `case` may be hidden, and not both
+                (&Node::Case(i, j), &Node::Case(k, l)) => {
+                    let is_hidden = |n: usize| match self.nodes[n].node {
+                        Node::Hidden(..) => true,
+                        _ => false,
+                    };
+                    if is_hidden(i) && is_hidden(j) {
+                        return Err(Error::BadPruning(idx));
+                    }
+                    for &(child, orig_child) in &[(i, k), (j, l)] {
+                        match self.nodes[child].node {
+                            Node::Hidden(cmr) if cmr == original.nodes[orig_child].cmr => {}
+                            Node::Hidden(..) => return Err(Error::BadPruning(child)),
+                            _ => stack.push((child, orig_child)),
+                        }
+                    }
+                }
+                (&Node::InjL(i), &Node::InjL(j))
+                | (&Node::InjR(i), &Node::InjR(j))
+                | (&Node::Take(i), &Node::Take(j))
+                | (&Node::Drop(i), &Node::Drop(j)) => stack.push((i, j)),
+                (&Node::Comp(i, j), &Node::Comp(k, l))
+                | (&Node::Pair(i, j), &Node::Pair(k, l))
+                | (&Node::Disconnect(i, j), &Node::Disconnect(k, l)) => {
+                    stack.push((i, k));
+                    stack.push((j, l));
+                }
+                // Witness CMRs ignore their values, so compare those too
+                (Node::Witness(a), Node::Witness(b)) if a == b => {}
+                // Other leaves match if their CMRs do
+                (&Node::Unit, &Node::Unit)
+                | (&Node::Iden, &Node::Iden)
+                | (&Node::Fail(..), &Node::Fail(..))
+                | (&Node::Jet(..), &Node::Jet(..))
+                | (&Node::Ext(..), &Node::Ext(..))
+                    if node.cmr == orig.cmr => {}
+                _ => return Err(Error::BadPruning(idx)),
+            }
+        }
+        debug_assert_eq!(self.cmr(), original.cmr());
+        Ok(())
+    }
+
+    /// Which nodes are reachable from the root, indexed like `nodes`
+    fn reachable(&self) -> Vec<bool> {
+        let mut ret = vec![false; self.nodes.len()];
+        if let Some(last) = ret.last_mut() {
+            *last = true;
+        }
+        // Children always precede their parents, so one backward pass
+        // suffices
+        for node in self.nodes.iter().rev() {
+            if !ret[node.index] {
+                continue;
+            }
+            match node.node {
+                Node::InjL(i) | Node::InjR(i) | Node::Take(i) | Node::Drop(i) => ret[i] = true,
+                Node::Comp(i, j) | Node::Case(i, j) | Node::Pair(i, j) | Node::Disconnect(i, j) => {
+                    ret[i] = true;
+                    ret[j] = true;
+                }
+                _ => {}
+            }
+        }
+        ret
+    }
+
+    /// Iterator over the nodes which are reachable from the root
+    fn reachable_nodes<'a>(&'a self) -> impl Iterator<Item = &'a ProgramNode<Ext>> + 'a {
+        self.nodes
+            .iter()
+            .zip(self.reachable())
+            .filter(|&(_, reachable)| reachable)
+            .map(|(node, _)| node)
+    }
+
+    /// Whether the program contains a reachable `disconnect` node
+    #[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+    pub fn uses_disconnect(&self) -> bool {
+        self.reachable_nodes().any(|node| match node.node {
+            Node::Disconnect(..) => true,
+            _ => false,
+        })
+    }
+
+    /// Whether the program contains a reachable `witness` node
+    #[allow(clippy::match_like_matches_macro)] // `matches!` is too new for our MSRV
+    pub fn uses_witness(&self) -> bool {
+        self.reachable_nodes().any(|node| match node.node {
+            Node::Witness(..) => true,
+            _ => false,
+        })
+    }
+
+    /// Index of the first reachable opaque jet of the program, if any
+    pub(crate) fn first_opaque_jet(&self) -> Option<usize> {
+        self.reachable_nodes().find_map(|node| match node.node {
+            Node::Jet(JetNode::Opaque(..)) => Some(node.index),
+            _ => None,
+        })
+    }
+
+    /// The set of jets used by reachable nodes of the program
+    pub fn jets_used(&self) -> BTreeSet<JetNode> {
+        self.reachable_nodes()
+            .filter_map(|node| match node.node {
+                Node::Jet(jet) => Some(jet),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The 256-bit constants, such as asset ids or hashes, written by
+    /// reachable `scribe` expressions in the program, in program order and
+    /// without duplicates
+    ///
+    /// A constant is a node whose output is 256 bits wide and which is built
+    /// only from `unit`, `pair`, and `injl` or `injr` nodes outputting single
+    /// bits, whatever its source type.
+    pub fn constant_hashes(&self) -> Vec<[u8; 32]> {
+        // The bits output by each node, if it is a constant no wider than
+        // 256 bits. Wider constants are not tracked, since shared nodes
+        // let their width grow exponentially in the size of the program
+        let mut bits: Vec<Option<Vec<bool>>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let const_bit = |i: usize, bit: bool| match bits[i] {
+                Some(ref child) if child.is_empty() && node.target_ty.bit_width() == 1 => {
+                    Some(vec![bit])
+                }
+                _ => None,
+            };
+            let node_bits = match node.node {
+                Node::Unit => Some(vec![]),
+                Node::InjL(i) => const_bit(i, false),
+                Node::InjR(i) => const_bit(i, true),
+                Node::Pair(..) if node.target_ty.bit_width() > 256 => None,
+                Node::Pair(i, j) => match (&bits[i], &bits[j]) {
+                    (Some(left), Some(right)) => {
+                        Some(left.iter().chain(right.iter()).cloned().collect())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            bits.push(node_bits);
+        }
+
+        let mut ret = vec![];
+        for (node, reachable) in self.nodes.iter().zip(self.reachable()) {
+            match bits[node.index] {
+                Some(ref bits) if reachable && bits.len() == 256 => {
+                    let mut hash = [0; 32];
+                    for (i, bit) in bits.iter().enumerate() {
+                        if *bit {
+                            hash[i / 8] |= 1 << (7 - i % 8);
+                        }
+                    }
+                    if !ret.contains(&hash) {
+                        ret.push(hash);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ret
+    }
+
+    /// Hash of the program's structure: its nodes (including witness data)
+    /// and their inferred types
+    ///
+    /// Two programs have the same structural hash iff they decoded to the
+    /// same nodes, so this can be used to cache validation results. Unlike
+    /// the CMR this is not a consensus value, and may change between
+    /// versions of this library.
+    pub fn structural_hash(&self) -> [u8; 32] {
+        let mut type_hashes = HashMap::new();
+        let mut eng = sha256::Hash::engine();
+        for node in &self.nodes {
+            let mut bits = Vec::<bool>::new();
+            encode::encode_node_no_witness(&node.node, node.index, &mut bits)
+                .expect("encoding to a Vec");
+            if let Node::Witness(ref value) = node.node {
+                bits.extend(value.to_bits(&node.target_ty));
+            }
+
+            eng.write_u64::<LittleEndian>(bits.len() as u64)
+                .expect("engines don't error");
+            for chunk in bits.chunks(8) {
+                let mut byte = 0u8;
+                for (i, bit) in chunk.iter().enumerate() {
+                    if *bit {
+                        byte |= 1 << (7 - i);
+                    }
+                }
+                eng.input(&[byte]);
+            }
+
+            eng.input(&type_hash(&node.source_ty, &mut type_hashes)[..]);
+            eng.input(&type_hash(&node.target_ty, &mut type_hashes)[..]);
+        }
+        sha256::Hash::from_engine(eng).into_inner()
     }
 
     /// Print out the program in a graphviz-parseable format
@@ -200,20 +1095,58 @@ impl<Ext: extension::Node> Program<Ext> {
     }
 }
 
+/// Hash of the structure of a type, for `Program::structural_hash`. Types
+/// share subtypes heavily, so hashes are memoized by address.
+fn type_hash(
+    ty: &Arc<types::FinalType>,
+    memo: &mut HashMap<*const types::FinalType, sha256::Hash>,
+) -> sha256::Hash {
+    let ptr: *const types::FinalType = &**ty;
+    if let Some(hash) = memo.get(&ptr) {
+        return *hash;
+    }
+
+    let mut eng = sha256::Hash::engine();
+    match ty.ty {
+        types::FinalTypeInner::Unit => eng.input(&[0]),
+        types::FinalTypeInner::Sum(ref l, ref r) => {
+            eng.input(&[1]);
+            eng.input(&type_hash(l, memo)[..]);
+            eng.input(&type_hash(r, memo)[..]);
+        }
+        types::FinalTypeInner::Product(ref l, ref r) => {
+            eng.input(&[2]);
+            eng.input(&type_hash(l, memo)[..]);
+            eng.input(&type_hash(r, memo)[..]);
+        }
+    }
+    let hash = sha256::Hash::from_engine(eng);
+    memo.insert(ptr, hash);
+    hash
+}
+
 fn compute_cmr<Ext: extension::Node>(program: &[ProgramNode<Ext>], node: &Node<Value, Ext>) -> Cmr {
+    node_cmr(node, |i| program[i].cmr)
+}
+
+/// CMR of a node, given a function returning the CMRs of its children
+pub(crate) fn node_cmr<Witness, Ext: extension::Node, F: Fn(usize) -> Cmr>(
+    node: &Node<Witness, Ext>,
+    child: F,
+) -> Cmr {
     match *node {
         Node::Iden => cmr::tag::iden(),
         Node::Unit => cmr::tag::unit(),
-        Node::InjL(i) => cmr::tag::injl().update_1(program[i].cmr),
-        Node::InjR(i) => cmr::tag::injr().update_1(program[i].cmr),
-        Node::Take(i) => cmr::tag::take().update_1(program[i].cmr),
-        Node::Drop(i) => cmr::tag::drop().update_1(program[i].cmr),
-        Node::Comp(i, j) => cmr::tag::comp().update(program[i].cmr, program[j].cmr),
-        Node::Case(i, j) => cmr::tag::case().update(program[i].cmr, program[j].cmr),
-        Node::Pair(i, j) => cmr::tag::pair().update(program[i].cmr, program[j].cmr),
-        Node::Disconnect(i, _) => cmr::tag::disconnect().update_1(program[i].cmr),
+        Node::InjL(i) => cmr::tag::injl().update_1(child(i)),
+        Node::InjR(i) => cmr::tag::injr().update_1(child(i)),
+        Node::Take(i) => cmr::tag::take().update_1(child(i)),
+        Node::Drop(i) => cmr::tag::drop().update_1(child(i)),
+        Node::Comp(i, j) => cmr::tag::comp().update(child(i), child(j)),
+        Node::Case(i, j) => cmr::tag::case().update(child(i), child(j)),
+        Node::Pair(i, j) => cmr::tag::pair().update(child(i), child(j)),
+        Node::Disconnect(i, _) => cmr::tag::disconnect().update_1(child(i)),
         Node::Witness(..) => cmr::tag::witness(),
-        Node::Fail(..) => unimplemented!(),
+        Node::Fail(x, y) => cmr::tag::fail().update(Cmr::from(x), Cmr::from(y)),
         Node::Hidden(cmr) => cmr,
         Node::Ext(ref b) => b.cmr(),
         Node::Jet(ref j) => j.cmr(),
@@ -244,7 +1177,7 @@ fn compute_extra_cells_bound<Ext: extension::Node>(
                 + cmp::max(program[i].extra_cells_bound, program[j].extra_cells_bound)
         }
         Node::Witness(..) => witness_target_width,
-        Node::Fail(..) => unimplemented!(),
+        Node::Fail(..) => 0,
         Node::Hidden(..) => 0,
         Node::Ext(..) => 0, // FIXME should fallthrough
         Node::Jet(..) => 0,
@@ -271,7 +1204,7 @@ fn compute_frame_count_bound<Ext: extension::Node>(
             2 + cmp::max(program[i].frame_count_bound, program[j].frame_count_bound)
         }
         Node::Witness(..) => 0,
-        Node::Fail(..) => unimplemented!(),
+        Node::Fail(..) => 0,
         Node::Hidden(..) => 0,
         Node::Ext(..) => 0, // FIXME should fallthrough
         Node::Jet(..) => 0,
@@ -282,10 +1215,40 @@ fn compute_frame_count_bound<Ext: extension::Node>(
 mod tests {
     use super::*;
 
+    #[cfg(feature = "elements")]
+    use std::io::Cursor;
+
     use bititer::BitIter;
-    use extension::dummy::Node as DummyNode;
+    use encode::{BitWrite, BitWriter};
+    use exec::BitMachine;
+    use extension::dummy::{Node as DummyNode, TxEnv as DummyTxEnv};
+    #[cfg(feature = "elements")]
+    use extension::elements::Node as ElementsNode;
+    use extension::jets::Node as JetNode;
+    #[cfg(feature = "proptests")]
+    use quickcheck::QuickCheck;
+    #[cfg(feature = "proptests")]
+    use test_progs::arbitrary::ArbitraryProgram;
+    use test_progs::{decode_prog, encode_nodes, encode_prog_with_witness};
+    #[cfg(feature = "elements")]
+    use test_progs::{sighash_all, sighash_all_program};
     use Node;
 
+    #[test]
+    #[cfg(feature = "proptests")]
+    fn arbitrary_roundtrip() {
+        fn prop(prog: ArbitraryProgram) -> bool {
+            let prog = Program::<DummyNode>::from_nodes(prog.nodes).expect("typechecking program");
+            let bytes = prog.to_bytes();
+            let decoded = Program::<DummyNode>::decode(&mut BitIter::from(bytes.into_iter()))
+                .expect("decoding program");
+            decoded.nodes == prog.nodes
+        }
+        QuickCheck::new()
+            .tests(100)
+            .quickcheck(prop as fn(ArbitraryProgram) -> bool);
+    }
+
     #[test]
     fn unit_prog() {
         let prog = vec![0x24];
@@ -324,4 +1287,700 @@ mod tests {
             "7a4ebcbd3be89bb9dfd901fdbeff16cfa80aa36363785b14615cbdd3f0ae1f0a"
         );
     }
+
+    #[test]
+    fn decode_with_bit_len() {
+        // 100 01001 00100 0 0, followed by one bit of padding
+        let prog = [0x89, 0x20];
+
+        let mut iter = BitIter::with_bit_len(prog.iter().cloned(), 15);
+        let decoded = Program::<DummyNode>::decode(&mut iter).expect("decoding program");
+        assert_eq!(decoded.nodes.len(), 2);
+        // All bits were consumed, and the padding is not visible
+        assert_eq!(iter.n_total_read(), 15);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = BitIter::with_bit_len(prog.iter().cloned(), 14);
+        assert_eq!(
+            Program::<DummyNode>::decode(&mut iter).err(),
+            Some(Error::EndOfStream),
+        );
+    }
+
+    #[test]
+    fn shared_subexpression() {
+        // `injl unit` is defined once and referenced twice by the pair
+        let nodes: Vec<Node<(), DummyNode>> = vec![Node::Unit, Node::InjL(0), Node::Pair(1, 1)];
+        let prog: Program<DummyNode> = decode_prog(&nodes);
+        assert_eq!(prog.nodes.len(), 3);
+        assert_eq!(prog.nodes[2].node, Node::Pair(1, 1));
+        assert_eq!(
+            prog.cmr(),
+            Some(cmr::tag::pair().update(prog.nodes[1].cmr, prog.nodes[1].cmr)),
+        );
+
+        // A reference must point back to an already-decoded node
+        let mut w = BitWriter::new(vec![]);
+        encode::encode_natural(1, &mut w).unwrap();
+        BitWrite::write_u8(&mut w, 4, 5).unwrap();
+        encode::encode_natural(1, &mut w).unwrap();
+        w.flush_all().unwrap();
+        assert_eq!(
+            Program::<DummyNode>::decode(&mut BitIter::from(w.into_inner().into_iter())).err(),
+            Some(Error::BadIndex(0)),
+        );
+    }
+
+    #[test]
+    fn prune() {
+        // case(injl(unit), comp(iden, ... comp(iden, injr(unit)))), where the
+        // right branch is long enough that hiding it saves space
+        let mut nodes: Vec<Node<(), DummyNode>> =
+            vec![Node::Unit, Node::InjL(0), Node::InjR(0), Node::Iden];
+        nodes.push(Node::Comp(3, 2));
+        for i in 4..40 {
+            nodes.push(Node::Comp(3, i));
+        }
+        let right = nodes.len() - 1;
+        nodes.push(Node::Case(1, right));
+
+        let prog: Program<DummyNode> = decode_prog(&nodes);
+
+        let run = |prog: &Program<DummyNode>, bit: u8| {
+            let mut mac = BitMachine::for_program(prog);
+            mac.input(&Value::prod(Value::u1(bit), Value::Unit));
+            mac.exec(prog, &DummyTxEnv).expect("executing program")
+        };
+
+        // Only the left branch is taken, so the right one is hidden
+        let input = Value::prod(Value::u1(0), Value::Unit);
+        let pruned = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        assert_eq!(pruned.cmr(), prog.cmr());
+        assert_eq!(pruned.nodes.len(), 4);
+        assert_eq!(pruned.nodes[2].node, Node::Hidden(prog.nodes[right].cmr));
+        assert_eq!(pruned.root_node().node, Node::Case(1, 2));
+        assert!(pruned.to_bytes().len() < prog.to_bytes().len());
+        assert_eq!(pruned.root_node().source_ty, prog.root_node().source_ty);
+        assert_eq!(run(&pruned, 0), run(&prog, 0));
+
+        // Taking the right branch hides the left one instead
+        let input = Value::prod(Value::u1(1), Value::Unit);
+        let pruned = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        assert_eq!(pruned.cmr(), prog.cmr());
+        match pruned.root_node().node {
+            Node::Case(s, _) => assert_eq!(pruned.nodes[s].node, Node::Hidden(prog.nodes[1].cmr)),
+            ref node => panic!("unexpected root {:?}", node),
+        }
+        assert_eq!(run(&pruned, 1), run(&prog, 1));
+        let decoded = Program::<DummyNode>::from_str(&pruned.to_string()).expect("decoding");
+        assert_eq!(decoded.equivalent(&pruned), Equivalence::Structural);
+        assert_eq!(decoded.verify_pruning(&prog), Ok(()));
+
+        // A witness whose type is fixed by a kept node keeps its value
+        let nodes: Vec<Node<Value, DummyNode>> = vec![
+            Node::Witness(Value::u64(0x0000_0001_ffff_ffff)),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+            Node::Witness(Value::u64(0)),
+            Node::Comp(3, 1),
+            Node::Case(2, 4),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        let input = Value::prod(Value::u1(0), Value::Unit);
+        let pruned = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        assert_eq!(pruned.nodes.len(), 5);
+        assert_eq!(pruned.raw_witness_bits().len(), 64);
+        let decoded = Program::<DummyNode>::from_str(&pruned.to_string()).expect("decoding");
+        assert_eq!(decoded.equivalent(&pruned), Equivalence::Structural);
+        assert_eq!(decoded.verify_pruning(&prog), Ok(()));
+        let mut mac = BitMachine::for_program(&decoded);
+        mac.input(&input);
+        assert_eq!(
+            mac.exec(&decoded, &DummyTxEnv),
+            Ok(Value::prod(Value::u1(1), Value::u32(0))),
+        );
+
+        // A witness whose type is only fixed by the pruned branch would
+        // be read as unit by a decoder of the pruned program
+        let nodes: Vec<Node<Value, DummyNode>> = vec![
+            Node::Witness(Value::u1(1)),
+            Node::Unit,
+            Node::Unit,
+            Node::InjL(2),
+            Node::Comp(1, 3),
+            Node::Case(0, 4),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        assert_eq!(
+            prog.prune(&input, &DummyTxEnv).err(),
+            Some(Error::PruningChangesType(0)),
+        );
+    }
+
+    #[test]
+    fn verify_pruning() {
+        // case(injl(unit), comp(iden, injr(unit))) on a bit and unit
+        let nodes: Vec<Node<Value, DummyNode>> = vec![
+            Node::Unit,
+            Node::InjL(0),
+            Node::InjR(0),
+            Node::Iden,
+            Node::Comp(3, 2),
+            Node::Case(1, 4),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+
+        let input = Value::prod(Value::u1(0), Value::Unit);
+        let pruned = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        assert_eq!(pruned.verify_pruning(&prog), Ok(()));
+        assert_eq!(prog.verify_pruning(&prog), Ok(()));
+        // The original is not a pruning of the pruned program
+        assert_eq!(prog.verify_pruning(&pruned), Err(Error::BadPruning(4)));
+
+        // Hiding a branch behind the CMR of the other one
+        let bad: Vec<Node<Value, DummyNode>> = vec![
+            Node::Unit,
+            Node::InjL(0),
+            Node::Hidden(prog.nodes[1].cmr),
+            Node::Case(1, 2),
+        ];
+        let bad = Program::<DummyNode>::from_nodes(bad).expect("typechecking program");
+        assert_eq!(bad.verify_pruning(&prog), Err(Error::BadPruning(2)));
+        assert_ne!(bad.cmr(), prog.cmr());
+
+        // Hiding a branch correctly, but changing the other one
+        let bad: Vec<Node<Value, DummyNode>> = vec![
+            Node::Unit,
+            Node::InjR(0),
+            Node::Hidden(prog.nodes[4].cmr),
+            Node::Case(1, 2),
+        ];
+        let bad = Program::<DummyNode>::from_nodes(bad).expect("typechecking program");
+        assert_eq!(bad.verify_pruning(&prog), Err(Error::BadPruning(1)));
+
+        // Hiding a node which is not a branch of a `case`, even with the
+        // right CMR
+        let nodes: Vec<Node<Value, DummyNode>> =
+            vec![Node::Iden, Node::Jet(JetNode::Adder32), Node::Comp(0, 1)];
+        let orig = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        let bad: Vec<Node<Value, DummyNode>> = vec![
+            Node::Hidden(orig.nodes[0].cmr),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+        ];
+        let bad = Program::<DummyNode>::from_nodes(bad).expect("typechecking program");
+        assert_eq!(bad.cmr(), orig.cmr());
+        // The hidden node no longer fixes the source type of the root
+        assert_eq!(bad.verify_pruning(&orig), Err(Error::BadPruning(2)));
+
+        // Hiding both branches of a `case`
+        let bad: Vec<Node<Value, DummyNode>> = vec![
+            Node::Hidden(prog.nodes[1].cmr),
+            Node::Hidden(prog.nodes[4].cmr),
+            Node::Case(0, 1),
+        ];
+        let bad = Program::<DummyNode>::from_nodes(bad).expect("typechecking program");
+        assert_eq!(bad.verify_pruning(&prog), Err(Error::BadPruning(2)));
+
+        // case(comp(witness, adder32), comp(witness, adder32))
+        let nodes: Vec<Node<Value, DummyNode>> = vec![
+            Node::Witness(Value::u64(1)),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+            Node::Witness(Value::u64(2)),
+            Node::Comp(3, 1),
+            Node::Case(2, 4),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        let input = Value::prod(Value::u1(0), Value::Unit);
+        let pruned = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        assert_eq!(pruned.verify_pruning(&prog), Ok(()));
+
+        // Changing only the witness value keeps the CMR
+        let mut bad = prog.prune(&input, &DummyTxEnv).expect("pruning");
+        bad.nodes[0].node = Node::Witness(Value::u64(3));
+        assert_eq!(bad.cmr(), prog.cmr());
+        assert_eq!(bad.verify_pruning(&prog), Err(Error::BadPruning(0)));
+
+        // case(witness, comp(unit, injl(unit))), where only the right branch
+        // fixes the type of the witness. Inferring types without the pruned
+        // branch makes the witness, and so the output, unit.
+        let nodes: Vec<Node<Value, DummyNode>> = vec![
+            Node::Witness(Value::u1(1)),
+            Node::Unit,
+            Node::Unit,
+            Node::InjL(2),
+            Node::Comp(1, 3),
+            Node::Case(0, 4),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        let bad: Vec<Node<Value, DummyNode>> = vec![
+            Node::Witness(Value::Unit),
+            Node::Hidden(prog.nodes[4].cmr),
+            Node::Case(0, 1),
+        ];
+        let bad = Program::<DummyNode>::from_nodes(bad).expect("typechecking program");
+        assert_eq!(bad.cmr(), prog.cmr());
+        assert_eq!(bad.verify_pruning(&prog), Err(Error::BadPruning(2)));
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    #[allow(clippy::manual_div_ceil)] // `div_ceil` is too new for our MSRV
+    fn encoded_bit_len() {
+        let mut iter = BitIter::from(sighash_all::SIGHASH_ALL.iter().cloned());
+        let prog = Program::<ElementsNode>::decode(&mut iter).expect("decoding program");
+        let decoded_len = iter.n_total_read();
+
+        let mut w = BitWriter::new(vec![]);
+        assert_eq!(prog.encode(&mut w).expect("encoding"), decoded_len);
+        assert_eq!(w.n_written(), decoded_len);
+        assert_eq!((decoded_len + 7) / 8, sighash_all::SIGHASH_ALL.len());
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn node_count_max_depth() {
+        let prog = sighash_all_program();
+        assert_eq!(prog.node_count(), 6392);
+        assert_eq!(prog.max_depth(), 199);
+
+        // comp(iden, adder32) has a path of one edge
+        let nodes: Vec<Node<Value, DummyNode>> =
+            vec![Node::Iden, Node::Jet(JetNode::Adder32), Node::Comp(0, 1)];
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("building program");
+        assert_eq!(prog.node_count(), 3);
+        assert_eq!(prog.max_depth(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn stats() {
+        let prog = sighash_all_program();
+
+        let stats = prog.stats();
+        assert_eq!(stats.node_count, prog.nodes.len());
+        assert_eq!(stats.node_count, 6392);
+        assert_eq!(stats.cmr, Some(Cmr::from(sighash_all::SIGHASH_ALL_CMR)));
+        assert_eq!(stats.source_ty.to_string(), "1");
+        assert_eq!(stats.target_ty.to_string(), "1");
+        assert_eq!(stats.extra_cells_bound, 232120);
+        assert_eq!(
+            format!("{:?}", prog),
+            "Program { nodes: 6392, \
+             cmr: e6ce807b06794d83772d7568dc1dbcb56e0703702139396a14a160b2785b108d, \
+             type: 1 → 1, extra_cells_bound: 232120 }",
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn node_types() {
+        let prog = sighash_all_program();
+
+        let root = prog.nodes.len() - 1;
+        let (source, target) = prog.node_type(root).expect("root exists");
+        assert_eq!(source.to_string(), "1");
+        assert_eq!(target.to_string(), "1");
+        assert_eq!(prog.node_type(root + 1), None);
+
+        // The types of the root's children fit together with the root's
+        let (left, right) = match prog.root_node().node {
+            Node::Comp(i, j) => (i, j),
+            ref node => panic!("unexpected root {:?}", node),
+        };
+        let (left_source, left_target) = prog.node_type(left).unwrap();
+        let (right_source, right_target) = prog.node_type(right).unwrap();
+        assert_eq!(left_source, source);
+        assert_eq!(left_target, right_source);
+        assert_eq!(right_target, target);
+
+        assert_eq!(prog.iter().count(), prog.nodes.len());
+        for (index, node) in prog.iter().enumerate() {
+            assert_eq!(node.index, index);
+            assert_eq!(
+                prog.node_type(index),
+                Some((node.source_ty.clone(), node.target_ty.clone())),
+            );
+        }
+    }
+
+    #[test]
+    fn from_nodes() {
+        // comp(iden, adder32)
+        let prog = Program::<DummyNode>::from_nodes(vec![
+            Node::Iden,
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+        ])
+        .expect("building program");
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::prod(Value::u32(40), Value::u32(2)));
+        assert_eq!(
+            mac.exec(&prog, &DummyTxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(42))),
+        );
+
+        // Same program as by decoding
+        let decoded: Program<DummyNode> =
+            decode_prog(&[Node::Iden, Node::Jet(JetNode::Adder32), Node::Comp(0, 1)]);
+        assert_eq!(prog.cmr(), decoded.cmr());
+
+        // Witness values are checked against their types
+        let witness = vec![
+            Node::Witness(Value::u32(7)),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+        ];
+        assert_eq!(
+            Program::<DummyNode>::from_nodes(witness).err(),
+            Some(Error::InputTypeMismatch("product", "sum")),
+        );
+
+        // Children must precede their parents
+        assert_eq!(
+            Program::<DummyNode>::from_nodes(vec![Node::Unit, Node::Comp(0, 1)]).err(),
+            Some(Error::BadIndex(1)),
+        );
+        assert_eq!(
+            Program::<DummyNode>::from_nodes(vec![]).err(),
+            Some(Error::EmptyProgram),
+        );
+    }
+
+    #[test]
+    fn decode_with_witness() {
+        let nodes: [Node<(), DummyNode>; 5] = [
+            Node::Witness(()),
+            Node::Witness(()),
+            Node::Pair(0, 1),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(2, 3),
+        ];
+        // Witness section: two 32-bit words
+        let bits: Vec<bool> = [40u32, 2]
+            .iter()
+            .flat_map(|word| (0..32).map(move |i| word & (1 << (31 - i)) != 0))
+            .collect();
+        let bytes = encode_prog_with_witness(&nodes, &bits);
+
+        let (prog, witness) =
+            Program::<DummyNode>::decode_with_witness(&mut BitIter::from(bytes.into_iter()))
+                .expect("decoding program");
+        assert_eq!(witness.values.len(), 2);
+        assert_eq!(witness.get(0), Some(&Value::u32(40)));
+        assert_eq!(witness.get(1), Some(&Value::u32(2)));
+        assert_eq!(witness.get(2), None);
+        assert_eq!(prog.nodes[0].node, Node::Witness(Value::u32(40)));
+
+        let raw = prog.raw_witness_bits();
+        assert_eq!(raw.len(), 64);
+        assert_eq!(raw[..32], Value::u32(40).to_witness_bits()[..]);
+        assert_eq!(raw[32..], Value::u32(2).to_witness_bits()[..]);
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::Unit);
+        assert_eq!(
+            mac.exec(&prog, &DummyTxEnv),
+            Ok(Value::prod(Value::u1(0), Value::u32(42))),
+        );
+
+        // The witness section must hold exactly the witness values
+        let mut long = bits.clone();
+        long.extend(vec![false; 36]);
+        let bytes = encode_prog_with_witness(&nodes, &long);
+        assert_eq!(
+            Program::<DummyNode>::decode_with_witness(&mut BitIter::from(bytes.into_iter())).err(),
+            Some(Error::ParseError("trailing data in witness section")),
+        );
+    }
+
+    #[test]
+    fn from_bytes() {
+        let nodes: [Node<Value, DummyNode>; 5] = [
+            Node::Witness(Value::u32(40)),
+            Node::Witness(Value::u32(2)),
+            Node::Pair(0, 1),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(2, 3),
+        ];
+        let prog = Program::<DummyNode>::from_nodes(nodes.to_vec()).expect("typechecking");
+
+        // Program and witness
+        let (decoded, witness) =
+            Program::<DummyNode>::from_bytes(&prog.to_bytes()).expect("decoding program");
+        let witness = witness.expect("witness section");
+        assert_eq!(witness.get(0), Some(&Value::u32(40)));
+        assert_eq!(witness.get(1), Some(&Value::u32(2)));
+        assert_eq!(decoded.nodes, prog.nodes);
+
+        // Program only: its witness nodes cannot be given values
+        let mut w = BitWriter::new(vec![]);
+        encode_nodes(&nodes, &mut w);
+        w.flush_all().expect("flushing");
+        let program_only = w.into_inner();
+        assert_eq!(
+            Program::<DummyNode>::from_bytes(&program_only).map(|_| ()),
+            Err(Error::ParseError("missing witness section")),
+        );
+
+        // A program without witness nodes needs no witness section
+        let adder = Program::<DummyNode>::from_nodes(nodes[3..4].to_vec()).expect("typechecking");
+        let (decoded, witness) =
+            Program::<DummyNode>::from_bytes(&adder.to_bytes()).expect("decoding program");
+        assert_eq!(witness, None);
+        assert_eq!(decoded.nodes, adder.nodes);
+
+        // Anything after the witness section is rejected
+        let mut trailing = prog.to_bytes();
+        trailing.push(0);
+        assert_eq!(
+            Program::<DummyNode>::from_bytes(&trailing).map(|_| ()),
+            Err(Error::ParseError("trailing data after witness")),
+        );
+    }
+
+    #[test]
+    fn exec_witness() {
+        // Output a 64-bit witness alongside the sum of its halves
+        let nodes: [Node<(), DummyNode>; 5] = [
+            Node::Witness(()),
+            Node::Jet(JetNode::Adder32),
+            Node::Iden,
+            Node::Pair(1, 2),
+            Node::Comp(0, 3),
+        ];
+        let value = 0x0123_4567_89ab_cdefu64;
+        let bits: Vec<bool> = (0..64).map(|i| value & (1 << (63 - i)) != 0).collect();
+        let bytes = encode_prog_with_witness(&nodes, &bits);
+
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(bytes.into_iter()))
+            .expect("decoding program");
+        assert_eq!(prog.nodes[0].target_ty.to_string(), "2^64");
+
+        let mut mac = BitMachine::for_program(&prog);
+        mac.input(&Value::Unit);
+        assert_eq!(
+            mac.exec(&prog, &DummyTxEnv),
+            Ok(Value::prod(
+                Value::prod(Value::u1(0), Value::u32(0x0123_4567 + 0x89ab_cdef)),
+                Value::u64(value),
+            )),
+        );
+    }
+
+    #[test]
+    fn equivalent() {
+        let decode = |nodes: &[Node<(), DummyNode>]| -> Program<DummyNode> { decode_prog(nodes) };
+
+        // The same program, with and without sharing of the `unit` node
+        let shared = decode(&[Node::Unit, Node::Pair(0, 0)]);
+        let unshared = decode(&[Node::Unit, Node::Unit, Node::Pair(0, 1)]);
+        let other = decode(&[Node::Unit, Node::InjL(0), Node::Pair(0, 1)]);
+
+        assert!(shared.cmr_eq(&unshared));
+        assert!(!shared.cmr_eq(&other));
+        assert_eq!(shared.equivalent(&shared), Equivalence::Structural);
+        assert_eq!(shared.equivalent(&unshared), Equivalence::Cmr);
+        assert_eq!(unshared.equivalent(&shared), Equivalence::Cmr);
+        assert_eq!(shared.equivalent(&other), Equivalence::Different);
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn hex_round_trip() {
+        let prog = sighash_all_program();
+        let hex = prog.to_string();
+        assert_eq!(prog.to_bytes(), &sighash_all::SIGHASH_ALL[..]);
+
+        let parsed = hex.parse::<Program<ElementsNode>>().expect("parsing hex");
+        assert_eq!(parsed.cmr(), prog.cmr());
+        assert_eq!(parsed.to_string(), hex);
+
+        assert_eq!(
+            hex[1..].parse::<Program<ElementsNode>>().err(),
+            Some(Error::ParseError("odd-length hex")),
+        );
+        assert_eq!(
+            format!("{}zz", hex).parse::<Program<ElementsNode>>().err(),
+            Some(Error::ParseError("invalid hex")),
+        );
+        assert_eq!(
+            format!("{}+0", hex).parse::<Program<ElementsNode>>().err(),
+            Some(Error::ParseError("invalid hex")),
+        );
+        assert_eq!(
+            format!("{}00", hex).parse::<Program<ElementsNode>>().err(),
+            Some(Error::ParseError("non-canonical program encoding")),
+        );
+    }
+
+    #[test]
+    fn witness_round_trip() {
+        // A `witness` feeding `adder32`, with the witness section attached
+        let nodes: [Node<(), DummyNode>; 3] = [
+            Node::Witness(()),
+            Node::Jet(JetNode::Adder32),
+            Node::Comp(0, 1),
+        ];
+        let bits: Vec<bool> = (0..64).map(|i| i % 3 == 0).collect();
+        let bytes = encode_prog_with_witness(&nodes, &bits);
+
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(bytes.iter().cloned()))
+            .expect("decoding program");
+        assert_eq!(prog.to_bytes(), bytes);
+        let parsed = prog
+            .to_string()
+            .parse::<Program<DummyNode>>()
+            .expect("parsing hex");
+        assert_eq!(parsed.nodes, prog.nodes);
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn capabilities() {
+        let prog = sighash_all_program();
+        assert!(prog.uses_disconnect());
+        assert!(prog.uses_witness());
+        let jets: Vec<JetNode> = prog.jets_used().into_iter().collect();
+        assert_eq!(
+            jets,
+            vec![
+                JetNode::Adder32,
+                JetNode::FullAdder32,
+                JetNode::Subtractor32,
+                JetNode::FullSubtractor32,
+                JetNode::Multiplier32,
+                JetNode::FullMultiplier32,
+                JetNode::Sha256HashBlock,
+            ],
+        );
+
+        // Unreachable nodes are not counted
+        let nodes: [Node<(), DummyNode>; 6] = [
+            Node::Witness(()),
+            Node::Jet(JetNode::Adder32),
+            Node::Iden,
+            Node::Unit,
+            Node::Disconnect(2, 3),
+            Node::Comp(0, 1),
+        ];
+        let bytes = encode_prog_with_witness(&nodes, &[false; 64]);
+        let prog = Program::<DummyNode>::decode(&mut BitIter::from(bytes.into_iter()))
+            .expect("decoding program");
+        assert!(!prog.uses_disconnect());
+        assert!(prog.uses_witness());
+        let jets: Vec<JetNode> = prog.jets_used().into_iter().collect();
+        assert_eq!(jets, vec![JetNode::Adder32]);
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn decode_from_reader() {
+        let prog =
+            Program::<ElementsNode>::decode_from_reader(Cursor::new(&sighash_all::SIGHASH_ALL[..]))
+                .expect("decoding program from reader");
+        let slice_prog = sighash_all_program();
+
+        assert_eq!(prog.nodes, slice_prog.nodes);
+        assert_eq!(
+            prog.root_node().cmr,
+            Cmr::from(sighash_all::SIGHASH_ALL_CMR),
+        );
+
+        // I/O errors are reported, rather than being taken as the end of
+        // the stream
+        struct Broken;
+        impl io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "broken"))
+            }
+        }
+        let truncated = Cursor::new(&sighash_all::SIGHASH_ALL[..100]);
+        assert_eq!(
+            Program::<ElementsNode>::decode_from_reader(truncated.clone()).err(),
+            Some(Error::EndOfStream),
+        );
+        assert_eq!(
+            Program::<ElementsNode>::decode_from_reader(io::Read::chain(truncated, Broken)).err(),
+            Some(Error::Io(io::ErrorKind::ConnectionReset)),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn decode_with_limit() {
+        let decode = |max_nodes| {
+            Program::<ElementsNode>::decode_with_limit(
+                &mut BitIter::from(sighash_all::SIGHASH_ALL.iter().cloned()),
+                max_nodes,
+            )
+        };
+        assert_eq!(decode(10).err(), Some(Error::ProgramTooLarge(6392, 10)));
+        assert_eq!(decode(6391).err(), Some(Error::ProgramTooLarge(6392, 6391)));
+        assert_eq!(decode(6392).expect("decoding program").node_count(), 6392);
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn constant_hashes() {
+        let asset_id = sha256::Hash::hash(b"asset").into_inner();
+
+        // Compare a scribed asset id with the asset of the current input
+        let mut nodes = Value::u256(&asset_id).scribe::<Value, ElementsNode>();
+        let scribed = nodes.len() - 1;
+        nodes.push(Node::Ext(ElementsNode::CurrentAsset));
+        nodes.push(Node::Pair(scribed, scribed + 1));
+        // A 32-bit constant, which is not reported
+        let word_root = Value::u32(7).scribe_onto(&mut nodes);
+        nodes.push(Node::Pair(scribed + 2, word_root));
+        let prog = Program::<ElementsNode>::from_nodes(nodes).expect("typechecking program");
+        assert_eq!(prog.constant_hashes(), vec![asset_id]);
+
+        // A constant of 2^40 bits, built by sharing nodes, which must not
+        // be materialized
+        let mut nodes: Vec<Node<Value, DummyNode>> = vec![Node::Unit, Node::InjL(0)];
+        for i in 1..41 {
+            nodes.push(Node::Pair(i, i));
+        }
+        let prog = Program::<DummyNode>::from_nodes(nodes).expect("typechecking program");
+        assert_eq!(prog.root_node().target_ty.bit_width(), 1 << 40);
+        assert_eq!(prog.constant_hashes(), vec![[0; 32]]);
+
+        // `sighash_all` hardcodes its hash tags and padding, and a public key
+        let prog = sighash_all_program();
+        let hashes = prog.constant_hashes();
+        assert!(hashes.contains(&cmr::Cmr::new(b"Simplicity\x1fSigHash").into_inner()));
+        assert!(hashes.contains(&cmr::Cmr::new(b"Simplicity\x1fSignature").into_inner()));
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn structural_hash() {
+        let decode = |bytes: &[u8]| {
+            Program::<ElementsNode>::decode(&mut BitIter::from(bytes.iter().cloned()))
+                .expect("decoding program")
+        };
+
+        let prog1 = decode(&sighash_all::SIGHASH_ALL);
+        let prog2 = decode(&sighash_all::SIGHASH_ALL);
+        assert_eq!(prog1.structural_hash(), prog2.structural_hash());
+
+        let unit = decode(&[0x24]);
+        let injl_unit = decode(&[0x89, 0x20]);
+        assert_ne!(unit.structural_hash(), prog1.structural_hash());
+        assert_ne!(unit.structural_hash(), injl_unit.structural_hash());
+    }
+
+    #[test]
+    #[cfg(feature = "elements")]
+    fn program_cmr() {
+        let prog = sighash_all_program();
+
+        let cmr = Cmr::from(sighash_all::SIGHASH_ALL_CMR);
+        assert_eq!(prog.cmr(), Some(cmr));
+        assert!(prog.is_cmr(&cmr));
+        assert!(!prog.is_cmr(&prog.nodes[0].cmr));
+    }
 }
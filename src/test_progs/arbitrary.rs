@@ -0,0 +1,293 @@
+// Rust Simplicity Library
+// Written in 2020 by
+//   Andrew Poelstra <apoelstra@blockstream.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Random Programs
+//!
+//! `quickcheck` generator for random well-typed programs, built from the
+//! core combinators and jets, for property tests of the type checker,
+//! encoder and Bit Machine
+//!
+
+use std::rc::Rc;
+
+use quickcheck::{Arbitrary, Gen};
+
+use extension::dummy::Node as DummyNode;
+use extension::jets::Node as JetNode;
+use extension::TypeName;
+use {Node, Value};
+
+/// Largest source type, in bits, of a jet used by the generator. This
+/// rules out jets such as `sha256blocks`, whose inputs would take
+/// thousands of nodes to construct
+const MAX_JET_SOURCE_WIDTH: usize = 512;
+
+/// Simplicity type, as chosen by the generator
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Ty {
+    Unit,
+    Sum(Rc<Ty>, Rc<Ty>),
+    Prod(Rc<Ty>, Rc<Ty>),
+}
+
+impl Ty {
+    fn sum(a: Ty, b: Ty) -> Ty {
+        Ty::Sum(Rc::new(a), Rc::new(b))
+    }
+
+    fn prod(a: Ty, b: Ty) -> Ty {
+        Ty::Prod(Rc::new(a), Rc::new(b))
+    }
+
+    /// The type `2^n`, for `n` a power of two
+    fn word(n: usize) -> Ty {
+        if n == 1 {
+            Ty::sum(Ty::Unit, Ty::Unit)
+        } else {
+            Ty::prod(Ty::word(n / 2), Ty::word(n / 2))
+        }
+    }
+
+    /// Parse the type of a jet, as `types::type_from_name`
+    fn from_name(name: &mut TypeName) -> Ty {
+        match name.next() {
+            Some(b'1') => Ty::Unit,
+            Some(b'2') => Ty::word(1),
+            Some(b'c') => Ty::word(8),
+            Some(b's') => Ty::word(16),
+            Some(b'i') => Ty::word(32),
+            Some(b'l') => Ty::word(64),
+            Some(b'h') => Ty::word(256),
+            Some(b'+') => {
+                let a = Ty::from_name(name);
+                Ty::sum(a, Ty::from_name(name))
+            }
+            Some(b'*') => {
+                let a = Ty::from_name(name);
+                Ty::prod(a, Ty::from_name(name))
+            }
+            Some(x) => panic!("Do not understand byte {} in type name", x as char),
+            None => panic!("unexpected end of string parsing type"),
+        }
+    }
+
+    fn bit_width(&self) -> usize {
+        match *self {
+            Ty::Unit => 0,
+            Ty::Sum(ref a, ref b) => 1 + ::std::cmp::max(a.bit_width(), b.bit_width()),
+            Ty::Prod(ref a, ref b) => a.bit_width() + b.bit_width(),
+        }
+    }
+}
+
+/// Random number below `n`
+fn below<G: Gen>(g: &mut G, n: usize) -> usize {
+    (u32::arbitrary(g) as usize) % n
+}
+
+/// Random small type, for the intermediate type of a `comp`
+fn arbitrary_ty<G: Gen>(g: &mut G, depth: usize) -> Ty {
+    match if depth == 0 { below(g, 2) } else { below(g, 5) } {
+        0 => Ty::Unit,
+        1 => Ty::word(1),
+        2 => Ty::word(8),
+        3 => Ty::sum(arbitrary_ty(g, depth - 1), arbitrary_ty(g, depth - 1)),
+        _ => Ty::prod(arbitrary_ty(g, depth - 1), arbitrary_ty(g, depth - 1)),
+    }
+}
+
+/// Random value of the given type
+fn arbitrary_value<G: Gen>(g: &mut G, ty: &Ty) -> Value {
+    match *ty {
+        Ty::Unit => Value::Unit,
+        Ty::Sum(ref a, ref b) => {
+            if bool::arbitrary(g) {
+                Value::sum_r(arbitrary_value(g, b))
+            } else {
+                Value::sum_l(arbitrary_value(g, a))
+            }
+        }
+        Ty::Prod(ref a, ref b) => {
+            let left = arbitrary_value(g, a);
+            Value::prod(left, arbitrary_value(g, b))
+        }
+    }
+}
+
+/// Program builder; nodes are only ever appended, so that children
+/// precede their parents
+struct Builder<'g, G: 'g> {
+    g: &'g mut G,
+    nodes: Vec<Node<Value, DummyNode>>,
+}
+
+impl<'g, G: Gen> Builder<'g, G> {
+    fn push(&mut self, node: Node<Value, DummyNode>) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// Add a program which outputs the given value. Unlike
+    /// `Value::scribe`, this never shares nodes, since shared nodes would
+    /// also share types
+    fn scribe(&mut self, value: &Value) -> usize {
+        match *value {
+            Value::Unit => self.push(Node::Unit),
+            Value::SumL(ref a) => {
+                let a = self.scribe(a);
+                self.push(Node::InjL(a))
+            }
+            Value::SumR(ref a) => {
+                let a = self.scribe(a);
+                self.push(Node::InjR(a))
+            }
+            Value::Prod(ref a, ref b) => {
+                let a = self.scribe(a);
+                let b = self.scribe(b);
+                self.push(Node::Pair(a, b))
+            }
+        }
+    }
+
+    /// Add a random program of type `source -> target`, of combinator
+    /// depth roughly at most `depth`, and return the index of its root
+    fn term(&mut self, source: &Ty, target: &Ty, depth: usize) -> usize {
+        // Every target type has a choice which recurses only on smaller
+        // targets, so generation terminates even at depth zero
+        let mut choices = vec![];
+        match *target {
+            Ty::Unit => choices.push("unit"),
+            Ty::Sum(..) => choices.push("inj"),
+            Ty::Prod(..) => choices.push("pair"),
+        }
+        if source == target {
+            choices.push("iden");
+        }
+        if depth > 0 {
+            if let Ty::Prod(ref a, _) = *source {
+                choices.extend(&["take", "drop"]);
+                if let Ty::Sum(..) = **a {
+                    choices.push("case");
+                }
+            }
+            choices.extend(&["comp", "jet"]);
+        }
+
+        let depth = depth.saturating_sub(1);
+        match choices[below(self.g, choices.len())] {
+            "unit" => self.push(Node::Unit),
+            "iden" => self.push(Node::Iden),
+            "inj" => {
+                let (a, b) = match *target {
+                    Ty::Sum(ref a, ref b) => (a.clone(), b.clone()),
+                    _ => unreachable!(),
+                };
+                if bool::arbitrary(self.g) {
+                    let child = self.term(source, &a, depth);
+                    self.push(Node::InjL(child))
+                } else {
+                    let child = self.term(source, &b, depth);
+                    self.push(Node::InjR(child))
+                }
+            }
+            "pair" => {
+                let (a, b) = match *target {
+                    Ty::Prod(ref a, ref b) => (a.clone(), b.clone()),
+                    _ => unreachable!(),
+                };
+                let left = self.term(source, &a, depth);
+                let right = self.term(source, &b, depth);
+                self.push(Node::Pair(left, right))
+            }
+            "take" | "drop" => {
+                let (a, b) = match *source {
+                    Ty::Prod(ref a, ref b) => (a.clone(), b.clone()),
+                    _ => unreachable!(),
+                };
+                if bool::arbitrary(self.g) {
+                    let child = self.term(&a, target, depth);
+                    self.push(Node::Take(child))
+                } else {
+                    let child = self.term(&b, target, depth);
+                    self.push(Node::Drop(child))
+                }
+            }
+            "case" => {
+                let (a, b, c) = match *source {
+                    Ty::Prod(ref ab, ref c) => match **ab {
+                        Ty::Sum(ref a, ref b) => (a.clone(), b.clone(), c.clone()),
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                };
+                let left = self.term(&Ty::Prod(a, c.clone()), target, depth);
+                let right = self.term(&Ty::Prod(b, c), target, depth);
+                self.push(Node::Case(left, right))
+            }
+            "comp" => {
+                let middle = arbitrary_ty(self.g, 2);
+                let left = self.term(source, &middle, depth);
+                let right = self.term(&middle, target, depth);
+                self.push(Node::Comp(left, right))
+            }
+            "jet" => {
+                let jets: Vec<_> = JetNode::all()
+                    .iter()
+                    .cloned()
+                    .filter(|jet| {
+                        Ty::from_name(&mut jet.source_type()).bit_width() <= MAX_JET_SOURCE_WIDTH
+                    })
+                    .collect();
+                let jet = jets[below(self.g, jets.len())];
+                let jet_source = Ty::from_name(&mut jet.source_type());
+                let jet_target = Ty::from_name(&mut jet.target_type());
+
+                let input = self.term(source, &jet_source, depth);
+                let jet = self.push(Node::Jet(jet));
+                let left = self.push(Node::Comp(input, jet));
+                let right = self.term(&jet_target, target, depth);
+                self.push(Node::Comp(left, right))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A random well-typed program, of type `1 -> A`, which applies a random
+/// term to a random input that it scribes itself
+#[derive(Clone, Debug)]
+pub struct ArbitraryProgram {
+    /// Nodes of the program, which can be passed to `Program::from_nodes`
+    pub nodes: Vec<Node<Value, DummyNode>>,
+}
+
+impl Arbitrary for ArbitraryProgram {
+    fn arbitrary<G: Gen>(g: &mut G) -> ArbitraryProgram {
+        let source = arbitrary_ty(g, 3);
+        let target = arbitrary_ty(g, 3);
+        let input = arbitrary_value(g, &source);
+        let depth = 1 + below(g, 5);
+
+        let mut builder = Builder {
+            g: g,
+            nodes: vec![],
+        };
+        let input = builder.scribe(&input);
+        let term = builder.term(&source, &target, depth);
+        builder.push(Node::Comp(input, term));
+        ArbitraryProgram {
+            nodes: builder.nodes,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Rust Simplicity Library
+// Written in 2020 by
+//   Andrew Poelstra <apoelstra@blockstream.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Test Programs
+//!
+//! Serialized Simplicity programs used as test vectors throughout the
+//! library
+//!
+
+#[cfg(feature = "proptests")]
+pub mod arbitrary;
+#[cfg(feature = "elements")]
+pub mod sighash_all;
+
+use bititer::BitIter;
+use encode::{self, BitWrite, BitWriter};
+use extension;
+use {Node, Program};
+
+/// Encode the length and nodes of a program, leaving the writer where
+/// its witness section, if any, goes
+pub fn encode_nodes<W, Ext: extension::Node>(nodes: &[Node<W, Ext>], w: &mut BitWriter<Vec<u8>>) {
+    encode::encode_natural(nodes.len(), &mut *w).expect("encoding to a Vec");
+    for (idx, node) in nodes.iter().enumerate() {
+        encode::encode_node_no_witness(node, idx, &mut *w).expect("encoding to a Vec");
+    }
+}
+
+/// Encode a witness-less program, for decoding with `Program::decode`
+pub fn encode_prog<Ext: extension::Node>(nodes: &[Node<(), Ext>]) -> Vec<u8> {
+    let mut w = BitWriter::new(vec![]);
+    encode_nodes(nodes, &mut w);
+    w.write_bit(false).expect("encoding to a Vec");
+    w.flush_all().expect("flushing");
+    w.into_inner()
+}
+
+/// Encode a program followed by a witness section of the given bits
+pub fn encode_prog_with_witness<Ext: extension::Node>(
+    nodes: &[Node<(), Ext>],
+    witness: &[bool],
+) -> Vec<u8> {
+    let mut w = BitWriter::new(vec![]);
+    encode_nodes(nodes, &mut w);
+    w.write_bit(true).expect("encoding to a Vec");
+    encode::encode_natural(witness.len(), &mut w).expect("encoding to a Vec");
+    for &bit in witness {
+        w.write_bit(bit).expect("encoding to a Vec");
+    }
+    w.flush_all().expect("flushing");
+    w.into_inner()
+}
+
+/// Decode the program encoded by `encode_prog`
+pub fn decode_prog<Ext: extension::Node>(nodes: &[Node<(), Ext>]) -> Program<Ext> {
+    Program::decode(&mut BitIter::from(encode_prog(nodes).into_iter())).expect("decoding program")
+}
+
+/// The `sighash_all` program, decoded
+#[cfg(feature = "elements")]
+pub fn sighash_all_program() -> Program<extension::elements::Node> {
+    Program::decode(&mut BitIter::from(sighash_all::SIGHASH_ALL.iter().cloned()))
+        .expect("decoding program")
+}
+
+/// A program consisting of a single primitive of an extension. Only
+/// extensions have primitives, so this is unused without one
+#[cfg_attr(not(any(feature = "bitcoin", feature = "elements")), allow(dead_code))]
+pub fn single_node_prog<Ext: extension::Node>(node: Ext) -> Program<Ext> {
+    decode_prog(&[Node::Ext(node)])
+}
@@ -3,7 +3,7 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
-use extension;
+use extension::{self, jets::Node as JetNode};
 use Error;
 use Node;
 
@@ -15,6 +15,15 @@ enum Type {
 }
 
 impl Type {
+    /// Name of the outermost type constructor, for error messages
+    fn kind(&self) -> &'static str {
+        match *self {
+            Type::Unit => "unit",
+            Type::Sum(..) => "sum",
+            Type::Product(..) => "product",
+        }
+    }
+
     fn into_rcvar(self) -> RcVar {
         Rc::new(RefCell::new(UnificationVar::concrete(self)))
     }
@@ -72,7 +81,9 @@ impl FinalType {
         self.bit_width
     }
 
-    fn from_var(var: RcVar) -> Result<Arc<FinalType>, Error> {
+    /// Finalize a unification variable, returning `None` if its type is
+    /// recursive (i.e. it fails the occurs check)
+    fn from_var(var: RcVar) -> Option<Arc<FinalType>> {
         let var = find_root(var);
         let mut var_borr = var.borrow_mut();
 
@@ -80,13 +91,13 @@ impl FinalType {
             Variable::Free => Type::Unit,
             Variable::Bound(ref ty, ref mut occurs_check) => {
                 if *occurs_check {
-                    return Err(Error::OccursCheck);
+                    return None;
                 }
                 *occurs_check = true;
                 ty.clone()
             }
             Variable::EqualTo(..) => unreachable!(),
-            Variable::Finalized(ref done) => return Ok(done.clone()),
+            Variable::Finalized(ref done) => return Some(done.clone()),
         };
 
         let (sub1, sub2) = match existing_type {
@@ -96,38 +107,15 @@ impl FinalType {
                     bit_width: 0,
                 });
                 var_borr.var = Variable::Finalized(ret.clone());
-                return Ok(ret);
+                return Some(ret);
             }
             Type::Sum(ref sub1, ref sub2) => (sub1.clone(), sub2.clone()),
             Type::Product(ref sub1, ref sub2) => (sub1.clone(), sub2.clone()),
         };
         drop(var_borr);
 
-        let sub1 = find_root(sub1);
-        let sub2 = find_root(sub2);
-
-        let sub1_borr = sub1.borrow_mut();
-        let final1 = match sub1_borr.var {
-            Variable::Free => Arc::new(FinalType {
-                ty: FinalTypeInner::Unit,
-                bit_width: 0,
-            }),
-            Variable::Bound(..) => FinalType::from_var(sub1.clone())?,
-            Variable::EqualTo(..) => unreachable!(),
-            Variable::Finalized(ref f1) => f1.clone(),
-        };
-        drop(sub1_borr);
-        let sub2_borr = sub2.borrow_mut();
-        let final2 = match sub2_borr.var {
-            Variable::Free => Arc::new(FinalType {
-                ty: FinalTypeInner::Unit,
-                bit_width: 0,
-            }),
-            Variable::Bound(..) => FinalType::from_var(sub2.clone())?,
-            Variable::EqualTo(..) => unreachable!(),
-            Variable::Finalized(ref f2) => f2.clone(),
-        };
-        drop(sub2_borr);
+        let final1 = FinalType::from_var(sub1)?;
+        let final2 = FinalType::from_var(sub2)?;
 
         let ret = match existing_type {
             Type::Unit => unreachable!(),
@@ -141,7 +129,7 @@ impl FinalType {
             }),
         };
         var.borrow_mut().var = Variable::Finalized(ret.clone());
-        Ok(ret)
+        Some(ret)
     }
 }
 
@@ -200,7 +188,11 @@ impl UnificationVar {
     }
 }
 
-fn bind(rcvar: &RcVar, ty: Type) -> Result<(), Error> {
+/// Two types which could not be unified, described by their outermost
+/// constructors
+struct TypeMismatch(&'static str, &'static str);
+
+fn bind(rcvar: &RcVar, ty: Type) -> Result<(), TypeMismatch> {
     // Cloning a `Variable` is cheap, as the nontrivial variants merely
     // hold `Rc`s
     let self_var = rcvar.borrow().var.clone();
@@ -221,23 +213,7 @@ fn bind(rcvar: &RcVar, ty: Type) -> Result<(), Error> {
                 unify(al1, be1)?;
                 unify(al2, be2)
             }
-            // FIXME output a sane error
-            _ => {
-                //            (a, b) => {
-                /*
-                let self_s = match a {
-                    Type::Unit => "unit",
-                    Type::Sum(..) => "sum",
-                    Type::Product(..) => "prod",
-                };
-                let b_s = match b {
-                    Type::Unit => "unit",
-                    Type::Sum(..) => "sum",
-                    Type::Product(..) => "prod",
-                };
-                */
-                Err(Error::TypeCheck)
-            }
+            (a, b) => Err(TypeMismatch(a.kind(), b.kind())),
         },
     }
 }
@@ -266,7 +242,7 @@ fn find_root(mut node: RcVar) -> RcVar {
     }
 }
 
-fn unify(mut alpha: RcVar, mut beta: RcVar) -> Result<(), Error> {
+fn unify(mut alpha: RcVar, mut beta: RcVar) -> Result<(), TypeMismatch> {
     alpha = find_root(alpha);
     beta = find_root(beta);
 
@@ -320,6 +296,8 @@ fn type_from_name<I: Iterator<Item = u8>>(n: &mut I, pow2s: &[RcVar]) -> Type {
             let unit = Type::Unit.into_rcvar();
             Type::Sum(unit.clone(), unit)
         }
+        Some(b'c') => Type::Product(pow2s[2].clone(), pow2s[2].clone()),
+        Some(b's') => Type::Product(pow2s[3].clone(), pow2s[3].clone()),
         Some(b'i') => Type::Product(pow2s[4].clone(), pow2s[4].clone()),
         Some(b'l') => Type::Product(pow2s[5].clone(), pow2s[5].clone()),
         Some(b'h') => Type::Product(pow2s[7].clone(), pow2s[7].clone()),
@@ -336,6 +314,135 @@ fn type_from_name<I: Iterator<Item = u8>>(n: &mut I, pow2s: &[RcVar]) -> Type {
     }
 }
 
+/// Add the type constraints of a single node, given the unification
+/// arrows of the nodes before it
+fn unify_node<Witness, Ext: extension::Node>(
+    node: &UnificationArrow,
+    program_node: &Node<Witness, Ext>,
+    program: &[Node<Witness, Ext>],
+    rcs: &[Rc<UnificationArrow>],
+    pow2s: &[RcVar],
+) -> Result<(), TypeMismatch> {
+    match *program_node {
+        Node::Iden => unify(node.source.clone(), node.target.clone())?,
+        Node::Unit => bind(&node.target, Type::Unit)?,
+        Node::InjL(i) => {
+            unify(node.source.clone(), rcs[i].source.clone())?;
+            let target_type = Type::Sum(
+                rcs[i].target.clone(),
+                Rc::new(RefCell::new(UnificationVar::free())),
+            );
+            bind(&node.target, target_type)?;
+        }
+        Node::InjR(i) => {
+            unify(node.source.clone(), rcs[i].source.clone())?;
+            let target_type = Type::Sum(
+                Rc::new(RefCell::new(UnificationVar::free())),
+                rcs[i].target.clone(),
+            );
+            bind(&node.target, target_type)?;
+        }
+        Node::Take(i) => {
+            unify(node.target.clone(), rcs[i].target.clone())?;
+            let target_type = Type::Product(
+                rcs[i].source.clone(),
+                Rc::new(RefCell::new(UnificationVar::free())),
+            );
+            bind(&node.source, target_type)?;
+        }
+        Node::Drop(i) => {
+            unify(node.target.clone(), rcs[i].target.clone())?;
+            let target_type = Type::Product(
+                Rc::new(RefCell::new(UnificationVar::free())),
+                rcs[i].source.clone(),
+            );
+            bind(&node.source, target_type)?;
+        }
+        Node::Comp(i, j) => {
+            unify(node.source.clone(), rcs[i].source.clone())?;
+            unify(rcs[i].target.clone(), rcs[j].source.clone())?;
+            unify(node.target.clone(), rcs[j].target.clone())?;
+        }
+        Node::Case(i, j) => {
+            let var1 = Rc::new(RefCell::new(UnificationVar::free()));
+            let var2 = Rc::new(RefCell::new(UnificationVar::free()));
+            let var3 = Rc::new(RefCell::new(UnificationVar::free()));
+
+            let sum12_ty = Type::Sum(var1.clone(), var2.clone());
+            let sum12_var = Rc::new(RefCell::new(UnificationVar::free()));
+            bind(&sum12_var, sum12_ty)?;
+
+            let source_ty = Type::Product(sum12_var, var3.clone());
+            bind(&node.source, source_ty)?;
+            if let Node::Hidden(..) = program[i] {
+            } else {
+                bind(
+                    &find_root(rcs[i].source.clone()),
+                    Type::Product(var1.clone(), var3.clone()),
+                )?;
+                unify(node.target.clone(), rcs[i].target.clone())?;
+            }
+            if let Node::Hidden(..) = program[j] {
+            } else {
+                bind(
+                    &find_root(rcs[j].source.clone()),
+                    Type::Product(var2.clone(), var3.clone()),
+                )?;
+                unify(node.target.clone(), rcs[j].target.clone())?;
+            }
+        }
+        Node::Pair(i, j) => {
+            unify(node.source.clone(), rcs[i].source.clone())?;
+            unify(node.source.clone(), rcs[j].source.clone())?;
+            bind(
+                &node.target,
+                Type::Product(rcs[i].target.clone(), rcs[j].target.clone()),
+            )?;
+        }
+        Node::Disconnect(i, j) => {
+            // See chapter 6 (Delegation) of TR
+            // Be careful, this order changed! https://github.com/ElementsProject/simplicity/pull/46
+            let var_a = Rc::new(RefCell::new(UnificationVar::free()));
+            let var_b = Rc::new(RefCell::new(UnificationVar::free()));
+            let var_c = Rc::new(RefCell::new(UnificationVar::free()));
+            let var_d = Rc::new(RefCell::new(UnificationVar::free()));
+
+            let s_source = Type::Product(pow2s[8].clone(), var_a.clone()).into_rcvar();
+            let s_target = Type::Product(var_b.clone(), var_c.clone()).into_rcvar();
+            unify(rcs[i].source.clone(), s_source)?;
+            unify(rcs[i].target.clone(), s_target)?;
+
+            let node_target = Type::Product(var_b, var_d.clone()).into_rcvar();
+            unify(node.source.clone(), var_a)?;
+            unify(node.target.clone(), node_target)?;
+
+            unify(rcs[j].source.clone(), var_c)?;
+            unify(rcs[j].target.clone(), var_d)?;
+        }
+        Node::Witness(..) => {
+            // No type constraints
+        }
+        Node::Hidden(..) => {
+            // No type constraints
+        }
+        Node::Ext(ref bn) => {
+            bind(&node.source, type_from_name(&mut bn.source_type(), pow2s))?;
+            bind(&node.target, type_from_name(&mut bn.target_type(), pow2s))?;
+        }
+        Node::Jet(JetNode::Opaque(..)) => {
+            // No type constraints, since the jet's type is unknown
+        }
+        Node::Jet(ref jt) => {
+            bind(&node.source, type_from_name(&mut jt.source_type(), pow2s))?;
+            bind(&node.target, type_from_name(&mut jt.target_type(), pow2s))?;
+        }
+        Node::Fail(..) => {
+            // No type constraints
+        }
+    }
+    Ok(())
+}
+
 /// Attach types to all nodes in a program
 pub fn type_check<Witness, Ext: extension::Node>(
     program: Vec<Node<Witness, Ext>>,
@@ -369,130 +476,8 @@ pub fn type_check<Witness, Ext: extension::Node>(
             target: Rc::new(RefCell::new(UnificationVar::free())),
         };
 
-        match *program_node {
-            Node::Iden => unify(node.source.clone(), node.target.clone())?,
-            Node::Unit => bind(&node.target, Type::Unit)?,
-            Node::InjL(i) => {
-                unify(node.source.clone(), rcs[i].source.clone())?;
-                let target_type = Type::Sum(
-                    rcs[i].target.clone(),
-                    Rc::new(RefCell::new(UnificationVar::free())),
-                );
-                bind(&node.target, target_type)?;
-            }
-            Node::InjR(i) => {
-                unify(node.source.clone(), rcs[i].source.clone())?;
-                let target_type = Type::Sum(
-                    Rc::new(RefCell::new(UnificationVar::free())),
-                    rcs[i].target.clone(),
-                );
-                bind(&node.target, target_type)?;
-            }
-            Node::Take(i) => {
-                unify(node.target.clone(), rcs[i].target.clone())?;
-                let target_type = Type::Product(
-                    rcs[i].source.clone(),
-                    Rc::new(RefCell::new(UnificationVar::free())),
-                );
-                bind(&node.source, target_type)?;
-            }
-            Node::Drop(i) => {
-                unify(node.target.clone(), rcs[i].target.clone())?;
-                let target_type = Type::Product(
-                    Rc::new(RefCell::new(UnificationVar::free())),
-                    rcs[i].source.clone(),
-                );
-                bind(&node.source, target_type)?;
-            }
-            Node::Comp(i, j) => {
-                unify(node.source.clone(), rcs[i].source.clone())?;
-                unify(rcs[i].target.clone(), rcs[j].source.clone())?;
-                unify(node.target.clone(), rcs[j].target.clone())?;
-            }
-            Node::Case(i, j) => {
-                let var1 = Rc::new(RefCell::new(UnificationVar::free()));
-                let var2 = Rc::new(RefCell::new(UnificationVar::free()));
-                let var3 = Rc::new(RefCell::new(UnificationVar::free()));
-
-                let sum12_ty = Type::Sum(var1.clone(), var2.clone());
-                let sum12_var = Rc::new(RefCell::new(UnificationVar::free()));
-                bind(&sum12_var, sum12_ty)?;
-
-                let source_ty = Type::Product(sum12_var, var3.clone());
-                bind(&node.source, source_ty)?;
-                if let Node::Hidden(..) = program[i] {
-                } else {
-                    bind(
-                        &find_root(rcs[i].source.clone()),
-                        Type::Product(var1.clone(), var3.clone()),
-                    )?;
-                    unify(node.target.clone(), rcs[i].target.clone())?;
-                }
-                if let Node::Hidden(..) = program[j] {
-                } else {
-                    bind(
-                        &find_root(rcs[j].source.clone()),
-                        Type::Product(var2.clone(), var3.clone()),
-                    )?;
-                    unify(node.target.clone(), rcs[j].target.clone())?;
-                }
-            }
-            Node::Pair(i, j) => {
-                unify(node.source.clone(), rcs[i].source.clone())?;
-                unify(node.source.clone(), rcs[j].source.clone())?;
-                bind(
-                    &node.target,
-                    Type::Product(rcs[i].target.clone(), rcs[j].target.clone()),
-                )?;
-            }
-            Node::Disconnect(i, j) => {
-                // See chapter 6 (Delegation) of TR
-                // Be careful, this order changed! https://github.com/ElementsProject/simplicity/pull/46
-                let var_a = Rc::new(RefCell::new(UnificationVar::free()));
-                let var_b = Rc::new(RefCell::new(UnificationVar::free()));
-                let var_c = Rc::new(RefCell::new(UnificationVar::free()));
-                let var_d = Rc::new(RefCell::new(UnificationVar::free()));
-
-                let s_source = Type::Product(pow2s[8].clone(), var_a.clone()).into_rcvar();
-                let s_target = Type::Product(var_b.clone(), var_c.clone()).into_rcvar();
-                unify(rcs[i].source.clone(), s_source)?;
-                unify(rcs[i].target.clone(), s_target)?;
-
-                let node_target = Type::Product(var_b, var_d.clone()).into_rcvar();
-                unify(node.source.clone(), var_a)?;
-                unify(node.target.clone(), node_target)?;
-
-                unify(rcs[j].source.clone(), var_c)?;
-                unify(rcs[j].target.clone(), var_d)?;
-            }
-            Node::Witness(..) => {
-                // No type constraints
-            }
-            Node::Hidden(..) => {
-                // No type constraints
-            }
-            Node::Ext(ref bn) => {
-                bind(
-                    &node.source,
-                    type_from_name(&mut bn.source_type(), &pow2s[..]),
-                )?;
-                bind(
-                    &node.target,
-                    type_from_name(&mut bn.target_type(), &pow2s[..]),
-                )?;
-            }
-            Node::Jet(ref jt) => {
-                bind(
-                    &node.source,
-                    type_from_name(&mut jt.source_type(), &pow2s[..]),
-                )?;
-                bind(
-                    &node.target,
-                    type_from_name(&mut jt.target_type(), &pow2s[..]),
-                )?;
-            }
-            Node::Fail(..) => unimplemented!("Cannot typecheck a program with `Fail` in it"),
-        };
+        unify_node(&node, program_node, &program, &rcs, &pow2s)
+            .map_err(|TypeMismatch(a, b)| Error::TypeCheck(rcs.len(), a, b))?;
 
         rcs.push(Rc::new(node));
     }
@@ -502,8 +487,10 @@ pub fn type_check<Witness, Ext: extension::Node>(
     for (idx, node) in program.into_iter().enumerate() {
         finals.push(TypedNode {
             node: node,
-            source_ty: FinalType::from_var(rcs[idx].source.clone())?,
-            target_ty: FinalType::from_var(rcs[idx].target.clone())?,
+            source_ty: FinalType::from_var(rcs[idx].source.clone())
+                .ok_or(Error::OccursCheck(idx))?,
+            target_ty: FinalType::from_var(rcs[idx].target.clone())
+                .ok_or(Error::OccursCheck(idx))?,
         });
     }
 